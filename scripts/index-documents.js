@@ -7,22 +7,18 @@ import { index, vectorise } from './shared/embeddings.js'
 const __dirname = dirname(fileURLToPath(import.meta.url))
 const datadir = join(__dirname, '../data')
 
-async function indexParagraph(title, name, chapter, page, partext, parnum) {
-  const vector = await vectorise(partext)
-  await index(title, name, chapter, page, partext, parnum, vector)
-}
-
 async function indexFile(group, file) {
   const name = `${group}/${file.slice(0, -4)}`
   console.log(name)
   const text = await readFile(join(datadir, group, file), 'utf8')
   const { title, pars } = splitText(text)
-  // await Promise.all(text.map(({ chapter, page, partext }, parnum) =>
-  //   postParagraph(title, name, chapter, String(page), partext, String(parnum))))
+  const paragraphs = []
   for (let parnum = 0; parnum < pars.length; ++parnum) {
     const { chapter, page, partext } = pars[parnum]
-    await indexParagraph(title, name, chapter, page, partext, parnum)
+    const vector = await vectorise(partext)
+    paragraphs.push({ title, chapter, page, partext, parnum, vector })
   }
+  await index(name, paragraphs)
 }
 
 async function indexGroup(group) {
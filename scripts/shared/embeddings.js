@@ -9,16 +9,20 @@ export async function vectorise(prompt) {
   return embedding
 }
 
-export async function index(title, name, chapter, page, partext, parnum, vector) {
+export async function index(name, paragraphs) {
   const start = performance.now()
-  const id = `${name}-${parnum}`
-  page = String(page)
-  parnum = String(parnum)
-  await putJsonToJson(`${vectorDbUrl}/collections/${encodeURIComponent(collection)}/embeddings/${encodeURIComponent(id)}`, {
-    metadata: { title, name, chapter, page, partext, parnum }, vector
+  const embeddings = paragraphs.map(({ title, chapter, page, partext, parnum, vector }) => ({
+    id: `${name}-${parnum}`,
+    metadata: { title, name, chapter, page, partext, parnum },
+    vector
+  }))
+  const results = await putJsonToJson(`${vectorDbUrl}/collections/${encodeURIComponent(collection)}/embeddings`, {
+    embeddings
   })
   const duration = Math.trunc(performance.now() - start)
-  console.log(`${id} indexed (in ${duration}ms)`)
+  const failed = results.filter(({ status }) => status !== 'created')
+  for (const { id, status } of failed) console.log(`  ${id} not indexed: ${status}`)
+  console.log(`${name}: ${results.length - failed.length} paragraphs indexed (in ${duration}ms)`)
 }
 
 export async function search(query, filter) {
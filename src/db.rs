@@ -1,30 +1,52 @@
 use axum::Extension;
-use lazy_static::lazy_static;
 use rayon::prelude::*;
 use schemars::JsonSchema;
 use std::{
 	borrow::ToOwned,
-	collections::{BinaryHeap, HashMap, HashSet},
-	env,
-	fs::{self, File},
-	path::PathBuf,
-	sync::Arc,
+	cmp::Ordering,
+	collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+	env, fs, io, iter, mem,
+	ops::{Deref, DerefMut},
+	path::{Path, PathBuf},
+	sync::{Arc, LazyLock, Mutex, MutexGuard, PoisonError},
 };
 use tokio::{
-	sync::RwLock,
+	sync::{Notify, OwnedMutexGuard},
 	time::{self, Duration},
 };
 use url_escape::{decode, encode_component};
 
-use crate::similarity::{get_cache_attr, get_distance_fn, normalize, Distance, ScoreIndex};
+use crate::{
+	filter::Filter,
+	index::{Index, IndexOptions, SearchParams, Vectors},
+	inverted::InvertedIndex,
+	lru::Lru,
+	metadata::{self, Metadata},
+	migration,
+	planner::{Access, Plan, Strategy},
+	quantization::{QuantizationOptions, Quantizer},
+	segment::{self, Checkpoint, Segment, Slots},
+	shared::Shared,
+	similarity::{
+		get_cache_attr, get_distance_fn, normalize, top_k, Distance, ScoreIndex, ScoreType,
+	},
+	snapshot::{self, Snapshot},
+	store::{self, Stored, Values},
+	tombstones::Tombstones,
+	vectors::VectorStore,
+	wal::{Durability, Operation, Wal},
+};
 
-lazy_static! {
-	pub static ref STORE_PATH: PathBuf =
-		PathBuf::from(env::var("LITEVEC_STORAGE").unwrap_or_else(|_| "./storage".to_string()));
-}
+/// Deleted slots are dropped when saving the collection, if they are a bigger fraction
+/// of all slots
+const COMPACTION_RATIO: f32 = 0.25;
+
+pub static STORE_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
+	PathBuf::from(env::var("LITEVEC_STORAGE").unwrap_or_else(|_| "./storage".to_string()))
+});
 
 #[allow(clippy::module_name_repetitions)]
-pub type DbExtension = Extension<Arc<RwLock<Db>>>;
+pub type DbExtension = Extension<Arc<Db>>;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -36,40 +58,194 @@ pub enum Error {
 
 	#[error("The dimension of the vector doesn't match the dimension of the collection")]
 	DimensionMismatch,
+
+	#[error("The index options are not valid")]
+	InvalidIndex,
+
+	#[error("The quantization options are not valid")]
+	InvalidQuantization,
+
+	#[error("The distance options are not valid")]
+	InvalidDistance,
+
+	#[error("The write-ahead log couldn't be written")]
+	Log(#[from] io::Error),
+}
+
+/// Collection behind its own locks, so that collections are used independently
+pub type CollectionLock = Arc<CollectionCell>;
+
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Slot {
+	/// Latest version of the collection, which queries keep using until they end,
+	/// even if a newer one is published meanwhile
+	Loaded(Arc<Collection>),
+	/// Collection taken by its writer to modify it, queries wait until it is published
+	Writing,
+	/// Stored collection, with its summary if it was unloaded after being used
+	Unloaded(Option<Summary>),
+	/// Collection, which was deleted or renamed while it was waited for
+	Removed,
+}
+
+#[derive(Debug)]
+pub struct CollectionCell {
+	/// Published state of the collection, locked only to take or replace it
+	slot: Mutex<Slot>,
+	/// Wakes the queries waiting for the collection to be published
+	published: Notify,
+	/// Held while the next version of the collection is being made, and while the
+	/// collection is loaded, saved or removed, so that it has one writer at a time
+	writer: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// Locked collection, which is taken from its slot when it is modified first, so
+/// that it is modified in place, unless a query still uses the published version,
+/// which is copied then. Queries wait for the collection until it is published.
+#[derive(Debug)]
+pub struct CollectionWriter {
+	cell: CollectionLock,
+	next: Arc<Collection>,
+	/// If the collection was taken from its slot and hasn't been published since
+	taken: bool,
+	_writer: OwnedMutexGuard<()>,
+}
+
+/// Files written by saving or merging a collection, which are applied to it after
+/// they have been written, so that queries don't wait for the writing
+struct Saved {
+	/// Vectors mapped from the written segment and the slots, which they belong to
+	stored: Option<(Values, Vec<usize>)>,
+	segments: Vec<Segment>,
+	checkpoint: Checkpoint,
+	stored_size: usize,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug)]
 pub struct Db {
-	/// Collections in the database
-	pub collections: HashMap<String, Collection>,
+	/// Collections by their names, the map is locked only to find or replace them
+	collections: Mutex<HashMap<String, CollectionLock>>,
+	/// Order in which the loaded collections were used
+	lru: Lru,
+	/// Memory in bytes, which the loaded collections may take before the least
+	/// recently used ones are unloaded, unlimited if not set
+	memory_budget: Option<usize>,
 	/// List of deleted collection names since the last storing
-	#[serde(skip)]
-	deleted: HashSet<String>,
+	deleted: Mutex<HashSet<String>>,
+	/// Log of the mutations since the last storing, if the database is stored
+	wal: Option<Wal>,
+	/// Locked while the collections are being saved
+	saving: tokio::sync::Mutex<()>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, JsonSchema)]
 pub struct SimilarityResult {
-	/// Similarity score
+	/// Value of the distance metric
 	score: f32,
+	/// If the score is a distance or a similarity
+	score_type: ScoreType,
 	/// Matching embedding
 	embedding: Embedding,
 }
 
+/// Outcome of inserting an embedding, which may have existed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Upserted {
+	Created,
+	Updated,
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, JsonSchema)]
 pub struct Collection {
 	/// Dimension of the vectors in the collection
 	pub dimension: usize,
 	/// Distance metric used for querying
 	pub distance: Distance,
-	/// Embeddings in the collection
+	/// Identifiers and metadata of the embeddings in the collection by their slots,
+	/// deleted ones keep their slots until the collection is compacted
 	#[serde(default)]
-	pub embeddings: Vec<Embedding>,
-	/// Cache of embedding IDs to improve the speed of the embedding lookups
+	records: Shared<Vec<Record>>,
+	/// Vectors of the embeddings by their slots, stored after the deleted slots
+	#[serde(skip)]
+	vectors: Shared<VectorStore>,
+	/// Slots of the embeddings by their IDs, without the deleted ones
+	#[serde(skip)]
+	ids: Shared<HashMap<String, usize>>,
+	/// Index for searching the vectors, stored after the collection
 	#[serde(skip)]
-	ids: HashMap<String, usize>,
+	index: Shared<Index>,
+	/// Quantized vectors, stored after the index
+	#[serde(skip)]
+	quantizer: Shared<Quantizer>,
+	/// Inverted index of metadata fields, stored after the quantizer
+	#[serde(skip)]
+	metadata_index: Shared<InvertedIndex>,
+	/// Deleted slots, stored after the metadata index
+	#[serde(skip)]
+	tombstones: Tombstones,
 	/// If the collection was modified and hasn't been saved yet
 	#[serde(skip)]
 	dirty: bool,
+	/// Size of the content of the collection files, when they were loaded or saved last
+	#[serde(skip)]
+	stored_size: usize,
+	/// Slots written since the collection was saved last, which form the mutable
+	/// head segment, written as a new segment by the next saving, with `true` for
+	/// the slots with a modified vector
+	#[serde(skip)]
+	head: BTreeMap<usize, bool>,
+	/// Sealed segments of the saved collection, the oldest first
+	#[serde(skip)]
+	segments: Vec<Segment>,
+	/// Saved index and quantized vectors
+	#[serde(skip)]
+	checkpoint: Checkpoint,
+	/// If all slots have to be written to a single segment, because the slots were
+	/// moved, the index was rebuilt or the collection hasn't been saved yet
+	#[serde(skip)]
+	rewrite: bool,
+}
+
+/// Content of a collection file, which lists the segments of the collection and is
+/// followed by its deleted slots
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Manifest {
+	dimension: usize,
+	distance: Distance,
+	/// Number of slots
+	len: usize,
+	/// Number of values of each vector kept in memory, zero if they were dropped
+	/// after quantizing
+	stride: usize,
+	/// Metadata fields indexed for filtering
+	metadata_index: InvertedIndex,
+	segments: Vec<Segment>,
+	checkpoint: Checkpoint,
+}
+
+/// Description of a collection, which is kept when the collection is unloaded
+#[derive(Debug, Clone, serde::Serialize, JsonSchema)]
+pub struct Summary {
+	/// Dimension of the embeddings in the collection
+	pub dimension: usize,
+	/// Distance function used for the collection
+	pub distance: Distance,
+	/// Index used for querying
+	pub index: IndexOptions,
+	/// Compression of the vectors
+	pub quantization: Option<QuantizationOptions>,
+	/// Metadata fields indexed for filtering
+	pub metadata_index: Vec<String>,
+	/// Number of embeddings in the collection
+	pub embedding_count: usize,
+	/// Size of the vectors in bytes if they weren't compressed
+	pub raw_size: usize,
+	/// Size of the compressed vectors in bytes
+	pub compressed_size: Option<usize>,
+	/// If the index and the quantization have been trained, all embeddings are
+	/// scored until then
+	pub trained: bool,
 }
 
 impl Collection {
@@ -77,44 +253,98 @@ impl Collection {
 		self.dirty
 	}
 
-	pub fn set_dirty(&mut self) {
+	pub const fn set_dirty(&mut self) {
 		self.dirty = true;
 	}
 
-	pub fn unset_dirty(&mut self) {
+	pub const fn unset_dirty(&mut self) {
 		self.dirty = false;
 	}
 
+	/// Marks the collection for writing all its slots to a single segment by the
+	/// next saving
+	pub const fn rewrite_all(&mut self) {
+		self.rewrite = true;
+		self.dirty = true;
+	}
+
+	pub fn index_options(&self) -> IndexOptions {
+		self.index.options()
+	}
+
+	pub fn quantization_options(&self) -> Option<QuantizationOptions> {
+		self.quantizer.options()
+	}
+
+	/// Metadata fields indexed for filtering
+	pub fn indexed_fields(&self) -> &[String] {
+		self.metadata_index.fields()
+	}
+
+	/// Number of embeddings, which haven't been deleted
+	pub fn embedding_count(&self) -> usize {
+		self.records.len() - self.tombstones.len()
+	}
+
+	/// Size of the vectors in bytes if they weren't compressed
+	pub fn raw_size(&self) -> usize {
+		self.embedding_count() * self.dimension * size_of::<f32>()
+	}
+
+	/// Size of the compressed vectors in bytes if the collection is quantized
+	pub fn compressed_size(&self) -> Option<usize> {
+		self.quantizer.size()
+	}
+
+	/// Estimated memory taken by the loaded collection in bytes, counting the stored
+	/// records, indexes and quantized vectors by the size of their serialized form
+	pub fn memory_size(&self) -> usize {
+		self.stored_size + self.vectors.size()
+	}
+
+	pub fn summary(&self) -> Summary {
+		Summary {
+			dimension: self.dimension,
+			distance: self.distance,
+			index: self.index_options(),
+			quantization: self.quantization_options(),
+			metadata_index: self.indexed_fields().to_vec(),
+			embedding_count: self.embedding_count(),
+			raw_size: self.raw_size(),
+			compressed_size: self.compressed_size(),
+			trained: self.is_trained(),
+		}
+	}
+
+	/// If the index and the quantizer don't need training, or have been trained
+	pub fn is_trained(&self) -> bool {
+		self.index.is_trained()
+			&& (self.quantizer.options().is_none() || self.quantizer.is_trained())
+	}
+
 	pub fn list(&self) -> Vec<String> {
-		tracing::debug!("Listing {} embeddings", self.embeddings.len());
-		self.embeddings.iter().map(|e| e.id.clone()).collect()
+		tracing::debug!("Listing {} embeddings", self.embedding_count());
+		self.live_slots()
+			.into_iter()
+			.map(|index| self.records[index].id.clone())
+			.collect()
 	}
 
-	pub fn get(&self, id: &str) -> Option<&Embedding> {
+	pub fn get(&self, id: &str, novector: bool) -> Option<Embedding> {
 		tracing::debug!("Getting embedding {}", id);
 		self.ids
 			.get(id)
-			.and_then(|index| self.embeddings.get(*index))
+			.filter(|&&index| index < self.records.len())
+			.map(|&index| self.output(index, novector))
 	}
 
-	pub fn get_by_metadata(
-		&self,
-		filter: &[HashMap<String, String>],
-		k: usize,
-		novector: bool,
-	) -> Vec<Embedding> {
+	pub fn get_by_metadata(&self, filter: &Filter, k: usize, novector: bool) -> Vec<Embedding> {
 		let embeddings: Vec<Embedding> = self
-			.embeddings
-			.iter()
-			.filter_map(|embedding| {
-				if match_embedding(embedding, filter) {
-					if novector {
-						let mut clone = embedding.clone();
-						clone.vector.clear();
-						Some(clone)
-					} else {
-						Some(embedding.to_owned())
-					}
+			.candidates(filter)
+			.into_iter()
+			.filter_map(|index| {
+				if match_embedding(&self.records[index], filter) {
+					Some(self.output(index, novector))
 				} else {
 					None
 				}
@@ -127,384 +357,2952 @@ impl Collection {
 
 	pub fn get_by_metadata_and_similarity(
 		&self,
-		filter: &[HashMap<String, String>],
+		filter: &Filter,
 		query: &[f32],
 		k: usize,
-	) -> Vec<SimilarityResult> {
-		let memo_attr = get_cache_attr(self.distance, query);
-		let distance_fn = get_distance_fn(self.distance);
-
-		let scores = self
-			.embeddings
-			.par_iter()
-			.enumerate()
-			.filter_map(|(index, embedding)| {
-				if match_embedding(embedding, filter) {
-					let score = distance_fn(&embedding.vector, query, memo_attr);
-					Some(ScoreIndex { score, index })
-				} else {
-					None
-				}
-			})
-			.collect::<Vec<_>>();
-
-		let mut heap = BinaryHeap::new();
-		for score_index in scores {
-			if heap.len() < k || score_index < *heap.peek().unwrap() {
-				heap.push(score_index);
+		params: SearchParams,
+	) -> (Vec<SimilarityResult>, Plan) {
+		// the vectors were normalized on insertion, the query has to be too
+		let normalized;
+		let query = if self.distance == Distance::Cosine {
+			normalized = normalize(query);
+			&normalized
+		} else {
+			query
+		};
 
-				if heap.len() > k {
-					heap.pop();
-				}
-			}
-		}
+		let candidates = self.metadata_index.candidates(filter);
+		let mut plan = Plan::new(
+			self.records.len(),
+			candidates.as_deref(),
+			!filter.is_empty(),
+			|index| {
+				!self.tombstones.is_deleted(index) && match_embedding(&self.records[index], filter)
+			},
+			k,
+			Access {
+				index: self.index.is_searchable() && !params.exact,
+				// exact search can use the original vectors, if they were kept
+				quantizer: self.quantizer.is_trained()
+					&& !(params.exact && self.quantizer.keeps_vectors()),
+				vectors: !self.quantizer.is_trained() || self.quantizer.keeps_vectors(),
+			},
+			self.is_trained(),
+		);
+		tracing::debug!("Searching by {:?}", plan.strategy);
+
+		// embeddings, which the metadata index excluded, do not need to be checked
+		let mask = candidates
+			.as_deref()
+			.map(|candidates| self.mask(candidates));
+		let live = |index: usize| !self.tombstones.is_deleted(index);
+		let accept = |index: usize| {
+			live(index)
+				&& mask.as_ref().is_none_or(|mask| mask[index])
+				&& match_embedding(&self.records[index], filter)
+		};
 
-		tracing::debug!("Found {} embeddings", heap.len());
-		heap.into_sorted_vec()
-			.into_iter()
-			.map(|ScoreIndex { score, index }| SimilarityResult {
-				score,
-				embedding: self.embeddings[index].clone(),
-			})
-			.collect()
+		let results = match plan.strategy {
+			Strategy::BruteForce => None,
+			Strategy::Filtered => self.search(query, k, params, accept),
+			Strategy::PostFilter => {
+				let requested = plan.candidates.unwrap_or(k);
+				self.search(query, requested, params, live)
+					.map(|mut results| {
+						results.retain(|result| accept(result.index));
+						results.truncate(k);
+						results
+					})
+					.and_then(|results| {
+						if results.len() < k {
+							plan.fallback = true;
+							self.search(query, k, params, accept)
+						} else {
+							Some(results)
+						}
+					})
+			},
+		};
+		let results = results.unwrap_or_else(|| self.brute_force(filter, candidates, query, k));
+		tracing::debug!("Found {} embeddings", results.len());
+		(self.to_similarity_results(results), plan)
 	}
 
-	pub fn update_metadata(&mut self, id: &str, metadata: Option<HashMap<String, String>>) -> bool {
-		tracing::debug!("Updating embedding {}", id);
-		match self.ids.get(id) {
-			None => false,
-			Some(index) => {
-				let embedding = self.embeddings.get_mut(*index).unwrap();
-				embedding.metadata = metadata;
-				true
-			},
+	/// Searches the index, or the quantized vectors if there's no index
+	fn search(
+		&self,
+		query: &[f32],
+		k: usize,
+		params: SearchParams,
+		accept: impl Fn(usize) -> bool + Sync,
+	) -> Option<Vec<ScoreIndex>> {
+		if let Some(results) = self.index.search(&*self.vectors, query, k, params, &accept) {
+			return Some(results);
 		}
-	}
 
-	pub fn delete(&mut self, id: &str) -> bool {
-		match self.ids.get(id) {
-			None => false,
-			Some(index) => {
-				tracing::debug!("Deleting embedding {}", id);
-				self.embeddings.remove(*index);
-				true
-			},
+		// exact search can use the original vectors, if they were kept
+		if params.exact && self.quantizer.keeps_vectors() {
+			return None;
+		}
+		let results = self
+			.quantizer
+			.search(query, k, params.oversampling, accept)?;
+		if self.quantizer.keeps_vectors() {
+			return Some(self.rescore(results, query, k));
 		}
+		Some(results)
 	}
 
-	pub fn delete_by_metadata(&mut self, filter: &[HashMap<String, String>]) -> bool {
-		if filter.is_empty() {
-			let len = self.embeddings.len();
-			tracing::debug!("Deleting {} embeddings", len);
-			self.embeddings.clear();
-			return len > 0;
-		}
+	/// Scores the original vectors of all candidates matching the filter
+	fn brute_force(
+		&self,
+		filter: &Filter,
+		candidates: Option<Vec<usize>>,
+		query: &[f32],
+		k: usize,
+	) -> Vec<ScoreIndex> {
+		let memo_attr = get_cache_attr(self.distance, query);
+		let distance_fn = get_distance_fn(self.distance);
 
-		let indexes = self
-			.embeddings
-			.par_iter()
-			.enumerate()
-			.filter_map(|(index, embedding)| {
-				if match_embedding(embedding, filter) {
-					tracing::debug!("Deleting embedding {}", embedding.id);
-					Some(index)
-				} else {
-					None
-				}
-			})
+		let score = |index: usize| {
+			if match_embedding(&self.records[index], filter) {
+				let score = distance_fn(self.vectors.vector(index), query, memo_attr);
+				Some(ScoreIndex { score, index })
+			} else {
+				None
+			}
+		};
+		let scores = candidates
+			.unwrap_or_else(|| self.live_slots())
+			.into_par_iter()
+			.filter_map(score)
 			.collect::<Vec<_>>();
-		let len = indexes.len();
-
-		for index in indexes {
-			self.embeddings.remove(index);
-		}
 
-		tracing::debug!("Deleted {} embeddings", len);
-		len > 0
+		top_k(scores, k)
 	}
 
-	pub fn index_embeddings(&mut self) {
-		for (index, embedding) in self.embeddings.iter().enumerate() {
-			self.ids.insert(embedding.id.clone(), index);
-		}
+	/// Slots of the embeddings, which can match the filter, all of them unless
+	/// the metadata index can narrow them. Deleted embeddings aren't indexed.
+	fn candidates(&self, filter: &Filter) -> Vec<usize> {
+		self.metadata_index
+			.candidates(filter)
+			.unwrap_or_else(|| self.live_slots())
 	}
-}
 
-fn match_embedding(embedding: &Embedding, filter: &[HashMap<String, String>]) -> bool {
-	// an empty filter matches any embedding
-	if filter.is_empty() {
-		return true;
+	/// Slots of the embeddings, which haven't been deleted
+	fn live_slots(&self) -> Vec<usize> {
+		(0..self.records.len())
+			.filter(|&index| !self.tombstones.is_deleted(index))
+			.collect()
 	}
 
-	match &embedding.metadata {
-		// no metadata in an embedding cannot be matched by a not empty filter
-		None => false,
-		Some(metadata) => {
-			// enumerate criteria with OR semantics; look for the first one matching
-			for criteria in filter {
-				let mut matches = true;
-				// enumerate entries with AND semantics; look for the first one failing
-				for (key, expected) in criteria {
-					let found = metadata.get(key).map_or(false, |actual| actual == expected);
-					// a not matching entry means the whole embedding not matching
-					if !found {
-						matches = false;
-						break;
-					}
-				}
-				// all entries matching mean the whole embedding matching
-				if matches {
-					return true;
-				}
-			}
-			// no match found
-			false
-		},
+	/// Marks the candidates for checking the embeddings found by an index or a quantizer
+	fn mask(&self, candidates: &[usize]) -> Vec<bool> {
+		let mut mask = vec![false; self.records.len()];
+		for &index in candidates {
+			mask[index] = true;
+		}
+		mask
 	}
-}
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, JsonSchema)]
-pub struct Embedding {
-	/// Unique identifier
-	pub id: String,
-	/// Vector computed from a text chunk
-	pub vector: Vec<f32>,
-	/// Metadata about the source text
-	pub metadata: Option<HashMap<String, String>>,
-}
+	/// Scores the candidates found in the quantized vectors with the original vectors
+	fn rescore(&self, candidates: Vec<ScoreIndex>, query: &[f32], k: usize) -> Vec<ScoreIndex> {
+		let memo_attr = get_cache_attr(self.distance, query);
+		let distance_fn = get_distance_fn(self.distance);
 
-impl Db {
-	pub fn new() -> Self {
-		Self {
-			collections: HashMap::new(),
-			deleted: HashSet::new(),
-		}
+		top_k(
+			candidates
+				.into_iter()
+				.map(|ScoreIndex { index, .. }| ScoreIndex {
+					score: distance_fn(self.vectors.vector(index), query, memo_attr),
+					index,
+				}),
+			k,
+		)
 	}
 
-	pub fn create_collection(
-		&mut self,
-		name: String,
-		dimension: usize,
-		distance: Distance,
-	) -> Result<Collection, Error> {
-		tracing::debug!("Creating collection {name}");
+	fn to_similarity_results(&self, scores: Vec<ScoreIndex>) -> Vec<SimilarityResult> {
+		scores
+			.into_iter()
+			.map(|ScoreIndex { score, index }| SimilarityResult {
+				score: self.distance.to_metric(score),
+				score_type: self.distance.score_type(),
+				embedding: self.output(index, false),
+			})
+			.collect()
+	}
 
-		if self.collections.contains_key(&name) {
-			return Err(Error::UniqueViolation);
+	/// Clones the embedding for a response, reconstructing its vector if it was quantized
+	fn output(&self, index: usize, novector: bool) -> Embedding {
+		let record = &self.records[index];
+		let vector = if novector {
+			Vec::new()
+		} else if self.vectors.vector(index).is_empty() {
+			self.quantizer.decode(index).unwrap_or_default()
+		} else {
+			self.vectors.vector(index).to_vec()
+		};
+		Embedding {
+			id: record.id.clone(),
+			vector,
+			metadata: record.metadata.clone(),
 		}
+	}
 
-		let collection = Collection {
-			dimension,
-			distance,
-			embeddings: Vec::new(),
-			ids: HashMap::new(),
-			dirty: true,
+	/// Inserts a new embedding, or replaces the vector and the metadata of an existing one
+	/// if `upsert` is set
+	pub fn upsert(&mut self, mut embedding: Embedding, upsert: bool) -> Result<Upserted, Error> {
+		self.check_upsert(&embedding, upsert)?;
+		let existing = self.ids.get(&embedding.id).copied();
+
+		embedding.vector = self.prepare_vector(embedding.vector)?;
+		let upserted = if let Some(index) = existing {
+			self.replace(index, embedding);
+			Upserted::Updated
+		} else {
+			self.insert(embedding);
+			Upserted::Created
 		};
-
-		let name_for_remove = name.clone();
-		self.collections.insert(name, collection.clone());
-		self.deleted.remove(&name_for_remove);
-
-		Ok(collection)
+		self.set_dirty();
+		Ok(upserted)
 	}
 
-	pub fn rename_collection(&mut self, name: &str, new_name: String) -> Result<(), Error> {
-		tracing::debug!("Renaming collection {name} to {new_name}");
-
-		if self.collections.contains_key(&new_name) {
+	/// Checks that the embedding can be inserted, or replace an existing one if
+	/// `upsert` is set, without modifying the collection
+	fn check_upsert(&self, embedding: &Embedding, upsert: bool) -> Result<(), Error> {
+		if !upsert && self.ids.contains_key(&embedding.id) {
 			return Err(Error::UniqueViolation);
 		}
+		if embedding.vector.len() != self.dimension {
+			return Err(Error::DimensionMismatch);
+		}
+		Ok(())
+	}
 
-		let mut collection = self.collections.remove(name).ok_or(Error::NotFound)?;
-		self.deleted.insert(name.to_string());
-
-		collection.set_dirty();
-		let name_for_remove = new_name.clone();
-		self.collections.insert(new_name, collection);
-		self.deleted.remove(&name_for_remove);
+	/// Replaces the vector, the metadata or both of an existing embedding, keeping
+	/// the metadata if only the vector is replaced
+	#[allow(clippy::option_option)]
+	pub fn update(
+		&mut self,
+		id: &str,
+		vector: Option<Vec<f32>>,
+		metadata: Option<Option<Metadata>>,
+	) -> Result<(), Error> {
+		self.check_update(id, vector.as_deref())?;
+		let index = self.ids[id];
+		let Some(vector) = vector else {
+			if let Some(metadata) = metadata {
+				self.update_metadata(id, metadata);
+			}
+			return Ok(());
+		};
 
+		tracing::debug!("Replacing vector of embedding {}", id);
+		let vector = self.prepare_vector(vector)?;
+		let metadata = metadata.unwrap_or_else(|| self.records[index].metadata.clone());
+		self.replace(
+			index,
+			Embedding {
+				id: id.to_string(),
+				vector,
+				metadata,
+			},
+		);
 		Ok(())
 	}
 
-	pub fn delete_collection(&mut self, name: &str) -> Result<(), Error> {
-		tracing::debug!("Deleting collection {name}");
-
-		if !self.collections.contains_key(name) {
+	/// Checks that the embedding exists and that the vector replacing its one fits,
+	/// without modifying the collection
+	fn check_update(&self, id: &str, vector: Option<&[f32]>) -> Result<(), Error> {
+		if !self.ids.contains_key(id) {
 			return Err(Error::NotFound);
 		}
-
-		self.collections.remove(name);
-		self.deleted.insert(name.to_string());
-
+		if vector.is_some_and(|vector| vector.len() != self.dimension) {
+			return Err(Error::DimensionMismatch);
+		}
 		Ok(())
 	}
 
-	pub fn insert_into_collection(
-		&mut self,
-		collection_name: &str,
-		mut embedding: Embedding,
-	) -> Result<(), Error> {
-		let collection = self
-			.collections
-			.get_mut(collection_name)
-			.ok_or(Error::NotFound)?;
-
-		if collection.ids.contains_key(&embedding.id) {
-			return Err(Error::UniqueViolation);
-		}
-
-		if embedding.vector.len() != collection.dimension {
+	fn prepare_vector(&self, vector: Vec<f32>) -> Result<Vec<f32>, Error> {
+		if vector.len() != self.dimension {
 			return Err(Error::DimensionMismatch);
 		}
 
 		// Normalize the vector if the distance metric is cosine, so we can use dot product later
-		if collection.distance == Distance::Cosine {
-			embedding.vector = normalize(&embedding.vector);
+		if self.distance == Distance::Cosine {
+			return Ok(normalize(&vector));
 		}
+		Ok(vector)
+	}
 
-		tracing::debug!(
-			"Inserting embedding {} to collection {}",
-			embedding.id,
-			collection_name
-		);
-		collection
-			.ids
-			.insert(embedding.id.clone(), collection.embeddings.len() - 1);
-		collection.embeddings.push(embedding);
-		collection.set_dirty();
+	fn insert(&mut self, embedding: Embedding) {
+		if let Some(index) = self.tombstones.reuse() {
+			self.ids.insert(embedding.id.clone(), index);
+			self.replace(index, embedding);
+			return;
+		}
 
-		Ok(())
+		let Embedding {
+			id,
+			vector,
+			metadata,
+		} = embedding;
+		// the vector store drops the vectors if the quantizer doesn't need them
+		self.quantizer.insert(&vector);
+		self.vectors.push(&vector);
+
+		let index = self.records.len();
+		self.ids.insert(id.clone(), index);
+		self.metadata_index.insert(index, metadata.as_ref());
+		self.records.push(Record { id, metadata });
+		self.index.insert(index, &*self.vectors);
+		self.head.insert(index, true);
 	}
 
-	pub fn get_collection(&self, name: &str) -> Option<&Collection> {
-		tracing::debug!("Getting collection {}", name);
-		self.collections.get(name)
+	/// Replaces the embedding at `index` keeping its slot
+	fn replace(&mut self, index: usize, embedding: Embedding) {
+		let Embedding {
+			id,
+			vector,
+			metadata,
+		} = embedding;
+		self.quantizer.update(index, &vector);
+		self.vectors.set(index, vector);
+
+		let previous = self.records[index].metadata.as_ref();
+		self.metadata_index
+			.update(index, previous, metadata.as_ref());
+		self.records[index] = Record { id, metadata };
+		self.index.update(index, &*self.vectors);
+		self.head.insert(index, true);
 	}
 
-	pub fn get_collection_mut(&mut self, name: &str) -> Option<&mut Collection> {
-		tracing::debug!("Getting collection {}", name);
-		self.collections.get_mut(name)
+	pub fn update_metadata(&mut self, id: &str, metadata: Option<Metadata>) -> bool {
+		tracing::debug!("Updating embedding {}", id);
+		match self.ids.get(id) {
+			None => false,
+			Some(index) => {
+				let record = self.records.get_mut(*index).unwrap();
+				let previous = mem::replace(&mut record.metadata, metadata);
+				self.metadata_index
+					.update(*index, previous.as_ref(), record.metadata.as_ref());
+				self.head.entry(*index).or_default();
+				true
+			},
+		}
 	}
 
-	pub fn list(&self) -> Vec<String> {
-		tracing::debug!("Listing {} collections", self.collections.len());
-		self.collections.keys().map(ToOwned::to_owned).collect()
+	/// Merges the patch into the metadata of an embedding, see [`metadata::merge`]
+	pub fn merge_metadata(&mut self, id: &str, patch: &Metadata) -> bool {
+		tracing::debug!("Patching embedding {}", id);
+		let Some(&index) = self.ids.get(id) else {
+			return false;
+		};
+		self.patch(index, patch);
+		true
 	}
 
-	fn load_from_store() -> anyhow::Result<Self> {
-		let marker = STORE_PATH.join("._collections");
-		if STORE_PATH.exists() {
-			if marker.exists() {
-				let mut db = Self::new();
-				db.load_collections()?;
-				return Ok(db);
-			}
-			let db = Self::convert_old_store()?;
-			File::create(marker)?;
-			return Ok(db);
+	/// Slots of the embeddings matching the filter
+	fn matching_slots(&self, filter: &Filter) -> Vec<usize> {
+		self.candidates(filter)
+			.into_par_iter()
+			.filter(|&index| match_embedding(&self.records[index], filter))
+			.collect()
+	}
+
+	/// Identifiers of the embeddings in the slots
+	fn ids_of(&self, indexes: &[usize]) -> Vec<String> {
+		indexes
+			.iter()
+			.map(|&index| self.records[index].id.clone())
+			.collect()
+	}
+
+	/// Merges the patch into the metadata of the embeddings in the slots
+	fn patch_slots(&mut self, indexes: &[usize], patch: &Metadata) {
+		for &index in indexes {
+			self.patch(index, patch);
 		}
-		tracing::debug!("Creating database store");
-		fs::create_dir_all(STORE_PATH.as_path())?;
-		File::create(marker)?;
-		Ok(Self::new())
+		tracing::debug!("Patched {} embeddings", indexes.len());
 	}
 
-	pub fn is_dirty(&self) -> bool {
-		return !self.deleted.is_empty() || self.collections.values().any(Collection::is_dirty);
+	fn patch(&mut self, index: usize, patch: &Metadata) {
+		let record = &mut self.records[index];
+		let previous = record.metadata.clone();
+		metadata::merge(record.metadata.get_or_insert_with(Metadata::new), patch);
+		self.metadata_index
+			.update(index, previous.as_ref(), record.metadata.as_ref());
+		self.head.entry(index).or_default();
 	}
 
-	pub fn save_to_store(&mut self) -> anyhow::Result<()> {
-		self.delete_collections()?;
-		self.store_collections()
+	pub fn delete(&mut self, id: &str) -> bool {
+		let Some(index) = self.ids.remove(id) else {
+			return false;
+		};
+		tracing::debug!("Deleting embedding {}", id);
+		self.remove(index);
+		true
 	}
 
-	fn delete_collections(&mut self) -> anyhow::Result<()> {
-		for name in &self.deleted {
-			tracing::debug!("Deleting collection {} from store", name);
-			let file_name = encode_component(name).to_string();
-			fs::remove_file(STORE_PATH.join(file_name).as_path())?;
+	/// Marks the slot as deleted, the vector is kept for navigating the index
+	/// until the collection is compacted. The deleted slots are saved apart from the
+	/// segments, which keep the deleted records.
+	fn remove(&mut self, index: usize) {
+		self.tombstones.delete(index);
+		self.metadata_index
+			.remove(index, self.records[index].metadata.as_ref());
+		let record = &mut self.records[index];
+		record.id.clear();
+		record.metadata = None;
+	}
+
+	/// Deletes the embeddings in the slots, the whole collection is cleared, if all
+	/// of them are deleted
+	fn delete_slots(&mut self, indexes: &[usize]) {
+		if !indexes.is_empty() && indexes.len() == self.embedding_count() {
+			tracing::debug!("Deleting {} embeddings", self.embedding_count());
+			// the emptied parts are replaced, not to copy them first
+			self.ids = Shared::default();
+			self.records = Shared::default();
+			self.vectors = VectorStore::new(self.vectors.stride()).into();
+			self.index.clear();
+			self.quantizer.clear();
+			self.metadata_index = InvertedIndex::new(self.indexed_fields().to_vec()).into();
+			self.tombstones.clear();
+			self.head.clear();
+			self.rewrite = true;
+			return;
 		}
-		self.deleted.clear();
-		Ok(())
+
+		for &index in indexes {
+			let id = std::mem::take(&mut self.records[index].id);
+			tracing::debug!("Deleting embedding {}", id);
+			self.ids.remove(&id);
+			self.remove(index);
+		}
+		tracing::debug!("Deleted {} embeddings", indexes.len());
 	}
 
-	fn store_collections(&mut self) -> anyhow::Result<()> {
-		for (name, collection) in &mut self.collections {
-			if collection.is_dirty() {
-				tracing::debug!("Saving collection {} to store", name);
-				let binary = bincode::serialize(&collection)?;
-				let file_name = encode_component(name).to_string();
-				fs::write(STORE_PATH.join(file_name).as_path(), binary)?;
-				collection.unset_dirty();
-			}
+	/// If enough slots were deleted to compact the collection
+	#[allow(clippy::cast_precision_loss)]
+	pub fn needs_compaction(&self) -> bool {
+		self.tombstones.len() as f32 > self.records.len() as f32 * COMPACTION_RATIO
+	}
+
+	/// If the segments should be merged or the checkpoint written again
+	pub fn needs_merge(&self) -> bool {
+		segment::merge_range(&self.segments).is_some()
+			|| segment::needs_checkpoint(&self.segments, self.checkpoint, self.records.len())
+	}
+
+	/// Drops the deleted slots, moving the following embeddings down, and updates
+	/// the index, the quantized vectors and the metadata index to the new slots
+	pub fn compact(&mut self) {
+		if self.tombstones.is_empty() {
+			return;
 		}
-		Ok(())
+		tracing::debug!("Compacting {} deleted slots", self.tombstones.len());
+		let remap = self.tombstones.remap(self.records.len());
+		self.index.compact(&remap, &*self.vectors);
+		self.quantizer.compact(&remap);
+		self.vectors.compact(&remap);
+		let mut slots = remap.iter();
+		self.records
+			.retain(|_| slots.next().is_some_and(Option::is_some));
+		self.tombstones.clear();
+		self.index_embeddings();
+		self.rewrite_all();
 	}
 
-	fn load_collections(&mut self) -> anyhow::Result<()> {
-		for entry in fs::read_dir(STORE_PATH.as_path())? {
-			let entry = entry?;
-			let entry_name = entry.file_name();
-			if entry_name == "._collections" {
-				continue;
+	pub fn rebuild_index(&mut self) {
+		self.compact();
+		tracing::debug!("Rebuilding index of {} embeddings", self.records.len());
+		// the quantizer isn't retrained once the original vectors were dropped, the
+		// vectors decoded from the codes would lose precision with every training
+		if self.quantizer.is_trained() && !self.quantizer.keeps_vectors() {
+			tracing::debug!("Keeping the quantizer trained without the original vectors");
+		} else {
+			self.quantizer.train(&*self.vectors);
+			if self.quantizer.is_trained() && !self.quantizer.keeps_vectors() {
+				self.vectors.drop_vectors();
 			}
-			let file_name = entry_name.to_str().ok_or(Error::NotFound)?;
-			let collection_name = decode(file_name).to_string();
-			tracing::debug!("Loading collection {} from store", collection_name);
-			let binary = fs::read(entry.path())?;
-			let mut collection: Collection = bincode::deserialize(&binary[..])?;
-			collection.index_embeddings();
-			self.collections.insert(collection_name, collection);
 		}
-		Ok(())
+		self.index.rebuild(&*self.vectors);
+		self.rewrite_all();
 	}
 
-	fn convert_old_store() -> anyhow::Result<Self> {
-		let db_path = STORE_PATH.join("db");
-		if db_path.exists() {
-			tracing::debug!("Converting old database store");
-			let binary = fs::read(db_path.clone())?;
-			let mut db: Self = bincode::deserialize(&binary[..])?;
-			for collection in &mut db.collections.values_mut() {
-				collection.index_embeddings();
-				collection.set_dirty();
+	pub fn index_embeddings(&mut self) {
+		self.ids.clear();
+		for (index, record) in self.records.iter().enumerate() {
+			if !self.tombstones.is_deleted(index) {
+				self.ids.insert(record.id.clone(), index);
 			}
-			db.store_collections()?;
-			fs::remove_file(db_path)?;
-			return Ok(db);
 		}
-		Ok(Self::new())
+		self.metadata_index
+			.rebuild(self.records.iter().map(|record| record.metadata.as_ref()));
 	}
-}
 
-impl Drop for Db {
-	fn drop(&mut self) {
-		if self.is_dirty() {
-			self.save_to_store().ok();
+	/// Writes the slots to a new segment and returns their vectors mapped from it,
+	/// the codes of the quantized vectors are written instead of the vectors dropped
+	/// after quantizing for replaying them to the quantizer
+	fn write_segment(
+		&self,
+		dir: &Path,
+		id: u64,
+		slots: &Slots,
+	) -> anyhow::Result<(Segment, Values)> {
+		let mut content = bincode::serialize(slots)?;
+		for &slot in &slots.slots {
+			bincode::serialize_into(&mut content, &self.records[slot])?;
 		}
+		let codes: Vec<u8> = if self.vectors.stride() == 0 {
+			slots
+				.slots
+				.iter()
+				.flat_map(|&slot| self.quantizer.codes(slot))
+				.copied()
+				.collect()
+		} else {
+			Vec::new()
+		};
+		bincode::serialize_into(&mut content, &codes)?;
+		let path = segment::segment_path(dir, id);
+		let values = store::write(
+			&path,
+			&content,
+			slots.slots.iter().map(|&slot| self.vectors.vector(slot)),
+		)?;
+		let segment = Segment {
+			id,
+			len: slots.slots.len(),
+			size: content.len(),
+		};
+		Ok((segment, values))
 	}
-}
 
-pub fn from_store() -> anyhow::Result<Arc<RwLock<Db>>> {
-	Ok(Arc::new(RwLock::new(Db::load_from_store()?)))
-}
+	/// Writes the index and the quantized vectors, which include the slots of all
+	/// segments, to a checkpoint
+	fn write_checkpoint(&self, dir: &Path, segments: &[Segment]) -> anyhow::Result<Checkpoint> {
+		let id = segments.last().map_or(0, |segment| segment.id);
+		let mut content = bincode::serialize(&self.records.len())?;
+		bincode::serialize_into(&mut content, &self.index)?;
+		bincode::serialize_into(&mut content, &self.quantizer)?;
+		store::write(
+			&segment::checkpoint_path(dir, id),
+			&content,
+			iter::empty::<&[f32]>(),
+		)?;
+		Ok(Checkpoint {
+			id,
+			size: content.len(),
+		})
+	}
 
-#[allow(clippy::similar_names)]
-pub fn autosave(db: Arc<RwLock<Db>>, duration: u32) {
-	let mut interval = time::interval(Duration::from_secs(duration.into()));
+	/// Writes the collection file listing the segments, followed by the deleted slots,
+	/// and returns the size of all files of the collection
+	fn write_manifest(
+		&self,
+		path: &Path,
+		segments: &[Segment],
+		checkpoint: Checkpoint,
+	) -> anyhow::Result<usize> {
+		let manifest = Manifest {
+			dimension: self.dimension,
+			distance: self.distance,
+			len: self.records.len(),
+			stride: self.vectors.stride(),
+			metadata_index: InvertedIndex::new(self.indexed_fields().to_vec()),
+			segments: segments.to_vec(),
+			checkpoint,
+		};
+		let mut content = bincode::serialize(&manifest)?;
+		bincode::serialize_into(&mut content, &self.tombstones)?;
+		store::write(path, &content, iter::empty::<&[f32]>())?;
+		Ok(content.len()
+			+ checkpoint.size
+			+ segments.iter().map(|segment| segment.size).sum::<usize>())
+	}
+
+	/// Maps the vectors from the written files and marks the collection saved
+	fn set_saved(&mut self, saved: Saved) {
+		if let Some((values, slots)) = saved.stored {
+			self.vectors.set_stored(values, &slots);
+		}
+		self.segments = saved.segments;
+		self.checkpoint = saved.checkpoint;
+		self.stored_size = saved.stored_size;
+		self.head.clear();
+		self.rewrite = false;
+		self.unset_dirty();
+	}
+
+	/// Reads the collection from its file and its segments in `dir`
+	fn from_stored(stored: &Stored, dir: &Path, string_metadata: bool) -> anyhow::Result<Self> {
+		if stored.version < 2 {
+			let mut collection = Self::from_embedded_vectors(stored.content(), string_metadata)?;
+			collection.stored_size = stored.content().len();
+			return Ok(collection);
+		}
+		if stored.version < 3 {
+			return Self::from_whole(stored);
+		}
+		Self::from_segments(stored, dir)
+	}
+
+	/// Reads the segments listed in the collection file, replaying the slots written
+	/// after the checkpoint to the index and the quantized vectors. The mapped
+	/// vectors are checked in the background.
+	fn from_segments(stored: &Stored, dir: &Path) -> anyhow::Result<Self> {
+		let mut binary = stored.content();
+		let manifest: Manifest = bincode::deserialize_from(&mut binary)?;
+		let tombstones: Tombstones = bincode::deserialize_from(&mut binary)?;
+		let Manifest {
+			dimension,
+			distance,
+			len,
+			stride,
+			metadata_index,
+			segments,
+			checkpoint,
+		} = manifest;
+
+		let mut records = vec![Record::default(); len];
+		let mut slots = Vec::with_capacity(segments.len());
+		let mut files = Vec::with_capacity(segments.len());
+		for segment in &segments {
+			let path = segment::segment_path(dir, segment.id);
+			let stored = store::read(&path)?;
+			let mut binary = stored.content();
+			let segment_slots: Slots = bincode::deserialize_from(&mut binary)?;
+			let values = stored.vectors()?;
+			if segment_slots.vectors.len() != segment_slots.slots.len()
+				|| values.as_slice().len() != segment_slots.slots.len() * stride
+			{
+				anyhow::bail!("the vectors don't match the segment {}", segment.id);
+			}
+			for &slot in &segment_slots.slots {
+				let record = bincode::deserialize_from(&mut binary)?;
+				*records.get_mut(slot).ok_or_else(|| {
+					anyhow::anyhow!("the segment {} has slots beyond the collection", segment.id)
+				})? = record;
+			}
+			let codes: Vec<u8> = bincode::deserialize_from(&mut binary)?;
+			slots.push((segment.id, segment_slots, values, codes));
+			files.push((path, stored));
+		}
+		// deleted records are cleared, the segments keep them
+		for (slot, record) in records.iter_mut().enumerate() {
+			if tombstones.is_deleted(slot) {
+				*record = Record::default();
+			}
+		}
+		let vectors = VectorStore::from_segments(
+			stride,
+			len,
+			slots
+				.iter()
+				.map(|(_, slots, values, _)| (values.clone(), slots.slots.as_slice())),
+		)
+		.ok_or_else(|| anyhow::anyhow!("the vectors don't match the embeddings"))?;
+
+		let stored_checkpoint = store::read(&segment::checkpoint_path(dir, checkpoint.id))?;
+		let mut binary = stored_checkpoint.content();
+		let covered: usize = bincode::deserialize_from(&mut binary)?;
+		let index = bincode::deserialize_from(&mut binary)?;
+		let quantizer = bincode::deserialize_from(&mut binary)?;
+
+		let mut collection = Self {
+			dimension,
+			distance,
+			records: records.into(),
+			vectors: vectors.into(),
+			ids: Shared::default(),
+			index,
+			quantizer,
+			metadata_index: metadata_index.into(),
+			tombstones,
+			dirty: false,
+			stored_size: 0,
+			head: BTreeMap::new(),
+			segments,
+			checkpoint,
+			rewrite: false,
+		};
+		let replayed: Vec<_> = slots
+			.iter()
+			.filter(|(id, ..)| *id > checkpoint.id)
+			.map(|(_, slots, _, codes)| (slots, codes.as_slice()))
+			.collect();
+		collection.replay(&replayed, covered)?;
+		collection.stored_size = stored.content().len()
+			+ checkpoint.size
+			+ collection
+				.segments
+				.iter()
+				.map(|segment| segment.size)
+				.sum::<usize>();
+		collection.index_embeddings();
+		store::check_vectors(files);
+		Ok(collection)
+	}
+
+	/// Replays the slots of the segments following the checkpoint, which covers
+	/// the slots before `covered`, to the index and the quantized vectors. Only
+	/// the last version of each slot is replayed, with the vector modified if it
+	/// was modified in any of the segments. The segments have the codes of the
+	/// quantized vectors, if the vectors were dropped after quantizing.
+	fn replay(&mut self, segments: &[(&Slots, &[u8])], mut covered: usize) -> anyhow::Result<()> {
+		let code_len = if self.vectors.stride() == 0 {
+			self.quantizer.code_len()
+		} else {
+			0
+		};
+		let mut replayed = BTreeMap::new();
+		for &(slots, codes) in segments {
+			if codes.len() != slots.slots.len() * code_len {
+				anyhow::bail!("the codes in the segments don't match the quantizer");
+			}
+			for (position, (slot, vector)) in slots.iter().enumerate() {
+				let modified = replayed.get(&slot).is_some_and(|&(_, modified)| modified);
+				let codes = &codes[position * code_len..(position + 1) * code_len];
+				replayed.insert(slot, (codes, modified || vector));
+			}
+		}
+		tracing::debug!("Replaying {} slots", replayed.len());
+		for (slot, (codes, modified)) in replayed {
+			match slot.cmp(&covered) {
+				Ordering::Less => {
+					if modified {
+						self.replay_slot(slot, codes, false);
+					}
+				},
+				Ordering::Equal => {
+					self.replay_slot(slot, codes, true);
+					covered += 1;
+				},
+				Ordering::Greater => anyhow::bail!("the segments skip the slot {covered}"),
+			}
+		}
+		if covered != self.records.len() {
+			anyhow::bail!("the segments don't match the checkpoint");
+		}
+		Ok(())
+	}
+
+	/// Encodes the vector of the slot again, or sets its codes if the vectors were
+	/// dropped, and updates it in the index, or appends it to both
+	fn replay_slot(&mut self, slot: usize, codes: &[u8], appended: bool) {
+		if self.vectors.stride() == 0 {
+			self.quantizer.set_codes(slot, codes);
+		} else if appended {
+			self.quantizer.insert(self.vectors.vector(slot));
+		} else {
+			self.quantizer.update(slot, self.vectors.vector(slot));
+		}
+		if appended {
+			self.index.insert(slot, &*self.vectors);
+		} else {
+			self.index.update(slot, &*self.vectors);
+		}
+	}
+
+	/// Reads a collection stored whole in its file, before the collections were
+	/// split to segments
+	fn from_whole(stored: &Stored) -> anyhow::Result<Self> {
+		let mut binary = stored.content();
+		let mut collection: Self = bincode::deserialize_from(&mut binary)?;
+		collection.index = bincode::deserialize_from(&mut binary)?;
+		collection.quantizer = bincode::deserialize_from(&mut binary)?;
+		collection.metadata_index = bincode::deserialize_from(&mut binary)?;
+		collection.tombstones = bincode::deserialize_from(&mut binary)?;
+		let layout = bincode::deserialize_from(&mut binary)?;
+		collection.vectors = VectorStore::from_stored(layout, stored.vectors()?)
+			.filter(|vectors| vectors.len() == collection.records.len())
+			.ok_or_else(|| anyhow::anyhow!("the vectors don't match the embeddings"))?
+			.into();
+		collection.stored_size = stored.content().len();
+		collection.index_embeddings();
+		// the collection has to be saved in the current format
+		collection.rewrite_all();
+		Ok(collection)
+	}
+
+	/// Reads a collection stored with the vectors in the embeddings, which may
+	/// miss the sections appended to the collection later
+	fn from_embedded_vectors(mut binary: &[u8], string_metadata: bool) -> bincode::Result<Self> {
+		let mut collection: Self = if string_metadata {
+			bincode::deserialize_from::<_, StringMetadataCollection>(&mut binary)?.into()
+		} else {
+			bincode::deserialize_from::<_, EmbeddedVectorsCollection>(&mut binary)?.into()
+		};
+		if !binary.is_empty() {
+			collection.index = bincode::deserialize_from(&mut binary)?;
+		}
+		if !binary.is_empty() {
+			collection.quantizer = bincode::deserialize_from(&mut binary)?;
+		}
+		if !binary.is_empty() {
+			collection.metadata_index = bincode::deserialize_from(&mut binary)?;
+		}
+		if !binary.is_empty() {
+			collection.tombstones = bincode::deserialize_from(&mut binary)?;
+		}
+		collection.index_embeddings();
+		Ok(collection)
+	}
+
+	/// Creates a collection with the embeddings, which have either all vectors
+	/// or none, if they were dropped after quantizing
+	fn from_embeddings(dimension: usize, distance: Distance, embeddings: Vec<Embedding>) -> Self {
+		let stride = if embeddings
+			.first()
+			.is_some_and(|embedding| embedding.vector.is_empty())
+		{
+			0
+		} else {
+			dimension
+		};
+		let vectors = VectorStore::from_vectors(
+			stride,
+			embeddings.len(),
+			embeddings
+				.iter()
+				.map(|embedding| embedding.vector.as_slice()),
+		);
+		Self {
+			dimension,
+			distance,
+			records: embeddings
+				.into_iter()
+				.map(|Embedding { id, metadata, .. }| Record { id, metadata })
+				.collect::<Vec<_>>()
+				.into(),
+			vectors: vectors.into(),
+			ids: Shared::default(),
+			index: Shared::default(),
+			quantizer: Shared::default(),
+			metadata_index: Shared::default(),
+			tombstones: Tombstones::default(),
+			// the collection has to be saved in the current format
+			dirty: true,
+			stored_size: 0,
+			head: BTreeMap::new(),
+			segments: Vec::new(),
+			checkpoint: Checkpoint::default(),
+			rewrite: true,
+		}
+	}
+}
+
+fn match_embedding(record: &Record, filter: &Filter) -> bool {
+	filter.matches(record.metadata.as_ref())
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, JsonSchema)]
+pub struct Embedding {
+	/// Unique identifier
+	pub id: String,
+	/// Vector computed from a text chunk
+	pub vector: Vec<f32>,
+	/// Metadata about the source text
+	#[serde(default, with = "metadata")]
+	#[schemars(with = "Option<Metadata>")]
+	pub metadata: Option<Metadata>,
+}
+
+/// Identifier and metadata of an embedding in a collection, which keeps the vectors
+/// separately
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize, JsonSchema)]
+struct Record {
+	id: String,
+	#[serde(default, with = "metadata")]
+	#[schemars(with = "Option<Metadata>")]
+	metadata: Option<Metadata>,
+}
+
+/// Collection stored by the previous versions, which kept the vectors in the embeddings
+#[derive(serde::Deserialize)]
+struct EmbeddedVectorsCollection {
+	dimension: usize,
+	distance: Distance,
+	#[serde(default)]
+	embeddings: Vec<Embedding>,
+}
+
+impl From<EmbeddedVectorsCollection> for Collection {
+	fn from(collection: EmbeddedVectorsCollection) -> Self {
+		Self::from_embeddings(
+			collection.dimension,
+			collection.distance,
+			collection.embeddings,
+		)
+	}
+}
+
+/// Collection stored by the previous versions, which allowed only strings in metadata
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StringMetadataCollection {
+	dimension: usize,
+	distance: Distance,
+	#[serde(default)]
+	embeddings: Vec<StringMetadataEmbedding>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StringMetadataEmbedding {
+	id: String,
+	vector: Vec<f32>,
+	metadata: Option<HashMap<String, String>>,
+}
+
+impl From<StringMetadataCollection> for Collection {
+	fn from(collection: StringMetadataCollection) -> Self {
+		Self::from_embeddings(
+			collection.dimension,
+			collection.distance,
+			collection
+				.embeddings
+				.into_iter()
+				.map(|embedding| Embedding {
+					id: embedding.id,
+					vector: embedding.vector,
+					metadata: embedding.metadata.map(metadata::from_strings),
+				})
+				.collect(),
+		)
+	}
+}
+
+impl CollectionCell {
+	fn new(slot: Slot) -> Self {
+		Self {
+			slot: Mutex::new(slot),
+			published: Notify::new(),
+			writer: Arc::new(tokio::sync::Mutex::new(())),
+		}
+	}
+
+	fn lock(&self) -> MutexGuard<'_, Slot> {
+		self.slot.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+
+	/// Returns the latest version of the collection, if it is loaded
+	fn current(&self) -> Option<Arc<Collection>> {
+		match &*self.lock() {
+			Slot::Loaded(collection) => Some(Arc::clone(collection)),
+			_ => None,
+		}
+	}
+
+	/// Returns the latest version of the collection, waiting for it if its writer
+	/// is modifying it, or `None` if it isn't loaded
+	async fn published(&self) -> Option<Arc<Collection>> {
+		loop {
+			let published = self.published.notified();
+			match &*self.lock() {
+				Slot::Loaded(collection) => return Some(Arc::clone(collection)),
+				Slot::Writing => {},
+				_ => return None,
+			}
+			published.await;
+		}
+	}
+
+	fn publish(&self, slot: Slot) {
+		*self.lock() = slot;
+		self.published.notify_waiters();
+	}
+}
+
+impl CollectionWriter {
+	/// Locks the loaded collection, or returns `None` if it isn't loaded
+	fn new(cell: CollectionLock, writer: OwnedMutexGuard<()>) -> Option<Self> {
+		let next = cell.current()?;
+		Some(Self {
+			cell,
+			next,
+			taken: false,
+			_writer: writer,
+		})
+	}
+
+	/// Puts the collection back to its slot, queries, which started before it was
+	/// modified, keep using the previous version
+	pub fn publish(&mut self) {
+		self.cell.publish(Slot::Loaded(Arc::clone(&self.next)));
+		self.taken = false;
+	}
+
+	/// Removes the collection from its slot and returns it, queries waiting for it
+	/// won't find it
+	fn remove(&mut self) -> Arc<Collection> {
+		self.cell.publish(Slot::Removed);
+		self.taken = false;
+		Arc::clone(&self.next)
+	}
+}
+
+impl Deref for CollectionWriter {
+	type Target = Collection;
+
+	fn deref(&self) -> &Collection {
+		&self.next
+	}
+}
+
+impl DerefMut for CollectionWriter {
+	fn deref_mut(&mut self) -> &mut Collection {
+		// the slot lets the collection go, so that it isn't copied, unless a query
+		// is still using it
+		if !self.taken {
+			self.cell.publish(Slot::Writing);
+			self.taken = true;
+		}
+		Arc::make_mut(&mut self.next)
+	}
+}
+
+impl Drop for CollectionWriter {
+	/// Publishes the collection, if it was taken, but not published, so that the
+	/// queries don't wait for it forever. Mutations are checked before the collection
+	/// is taken, so that they don't fail once it has been.
+	fn drop(&mut self) {
+		if self.taken {
+			self.publish();
+		}
+	}
+}
+
+impl Db {
+	pub fn new() -> Self {
+		Self {
+			collections: Mutex::new(HashMap::new()),
+			lru: Lru::default(),
+			memory_budget: None,
+			deleted: Mutex::new(HashSet::new()),
+			wal: None,
+			saving: tokio::sync::Mutex::new(()),
+		}
+	}
+
+	pub async fn create_collection(
+		&self,
+		name: String,
+		dimension: usize,
+		distance: Distance,
+		index: IndexOptions,
+		quantization: Option<QuantizationOptions>,
+		metadata_index: Vec<String>,
+	) -> Result<Collection, Error> {
+		tracing::debug!("Creating collection {name}");
+
+		if self.contains(&name) {
+			return Err(Error::UniqueViolation);
+		}
+
+		if !distance.is_valid() {
+			return Err(Error::InvalidDistance);
+		}
+
+		if !index.is_valid() {
+			return Err(Error::InvalidIndex);
+		}
+
+		// indexes navigate by the original vectors
+		if let Some(quantization) = quantization {
+			if !quantization.is_valid(dimension, distance)
+				|| (!quantization.keeps_vectors() && index != IndexOptions::Flat)
+			{
+				return Err(Error::InvalidQuantization);
+			}
+		}
+
+		let collection = Collection {
+			dimension,
+			distance,
+			records: Shared::default(),
+			vectors: VectorStore::new(dimension).into(),
+			ids: Shared::default(),
+			index: Index::new(index, distance).into(),
+			quantizer: Quantizer::new(quantization, distance, dimension).into(),
+			metadata_index: InvertedIndex::new(metadata_index).into(),
+			tombstones: Tombstones::default(),
+			dirty: true,
+			stored_size: 0,
+			head: BTreeMap::new(),
+			segments: Vec::new(),
+			checkpoint: Checkpoint::default(),
+			rewrite: true,
+		};
+
+		// the name is taken by an empty slot, so that the collection is published
+		// only after its creation has been logged
+		let cell = Arc::new(CollectionCell::new(Slot::Removed));
+		let writer = Arc::clone(&cell.writer).lock_owned().await;
+		{
+			let mut collections = self.lock_collections();
+			if collections.contains_key(&name) {
+				return Err(Error::UniqueViolation);
+			}
+			collections.insert(name.clone(), Arc::clone(&cell));
+		}
+
+		let logged = self
+			.log(|| Operation::CreateCollection {
+				name: name.clone(),
+				dimension,
+				distance,
+				index,
+				quantization,
+				metadata_index: collection.indexed_fields().to_vec(),
+			})
+			.await;
+		if let Err(error) = logged {
+			self.lock_collections().remove(&name);
+			return Err(error);
+		}
+		self.lock_deleted().remove(&name);
+		self.lru.insert(&name, 0);
+		cell.publish(Slot::Loaded(Arc::new(collection.clone())));
+		drop(writer);
+		Ok(collection)
+	}
+
+	pub async fn rename_collection(&self, name: &str, new_name: String) -> Result<(), Error> {
+		tracing::debug!("Renaming collection {name} to {new_name}");
+
+		if self.contains(&new_name) {
+			return Err(Error::UniqueViolation);
+		}
+
+		let mut collection = self.write_collection(name).await?;
+		// the new name is locked, so that the collection is modified only after its
+		// renaming has been logged
+		let new_cell = Arc::new(CollectionCell::new(Slot::Removed));
+		let new_writer = Arc::clone(&new_cell.writer).lock_owned().await;
+		{
+			let mut collections = self.lock_collections();
+			if collections.contains_key(&new_name) {
+				return Err(Error::UniqueViolation);
+			}
+			collections.insert(new_name.clone(), Arc::clone(&new_cell));
+		}
+
+		// the old name is recorded as deleted before the renaming is logged, so that
+		// a saving, which drops it from the log, deletes the file
+		self.lock_deleted().insert(name.to_string());
+		let logged = self
+			.log(|| Operation::RenameCollection {
+				name: name.to_string(),
+				new_name: new_name.clone(),
+			})
+			.await;
+		if let Err(error) = logged {
+			self.lock_collections().remove(&new_name);
+			self.lock_deleted().remove(name);
+			// a saving could delete the file meanwhile
+			collection.rewrite_all();
+			collection.publish();
+			return Err(error);
+		}
+
+		// the collection is modified in place, unless a query is still using it, and
+		// its segments are left to the deleted name
+		collection.rewrite_all();
+		let renamed = collection.remove();
+		self.lock_deleted().remove(&new_name);
+		self.lru.remove(name);
+		self.lru.insert(&new_name, renamed.memory_size());
+		new_cell.publish(Slot::Loaded(renamed));
+		// the old name stays taken until the renaming has been logged, so that
+		// a collection created with it is logged after
+		self.lock_collections().remove(name);
+		drop(new_writer);
+		drop(collection);
+		Ok(())
+	}
+
+	pub async fn delete_collection(&self, name: &str) -> Result<(), Error> {
+		tracing::debug!("Deleting collection {name}");
+
+		let cell = self.slot(name).ok_or(Error::NotFound)?;
+		let writer = cell.writer.lock().await;
+		if matches!(*cell.lock(), Slot::Removed) {
+			return Err(Error::NotFound);
+		}
+
+		// the deletion is recorded before it is logged, so that a saving, which drops
+		// it from the log, deletes the file
+		self.lock_deleted().insert(name.to_string());
+		let logged = self
+			.log(|| Operation::DeleteCollection {
+				name: name.to_string(),
+			})
+			.await;
+		if let Err(error) = logged {
+			self.lock_deleted().remove(name);
+			// a saving could delete the file of a loaded collection meanwhile
+			if let Slot::Loaded(collection) = &mut *cell.lock() {
+				Arc::make_mut(collection).rewrite_all();
+			}
+			return Err(error);
+		}
+
+		cell.publish(Slot::Removed);
+		self.lru.remove(name);
+		// the name stays taken until the deletion has been logged, so that
+		// a collection created with it is logged after
+		self.lock_collections().remove(name);
+		drop(writer);
+		Ok(())
+	}
+
+	/// Inserts an embedding, or replaces an existing one if `upsert` is set
+	pub async fn insert_into_collection(
+		&self,
+		collection_name: &str,
+		embedding: Embedding,
+		upsert: bool,
+	) -> Result<Upserted, Error> {
+		let mut collection = self.write_collection(collection_name).await?;
+
+		tracing::debug!(
+			"Inserting embedding {} to collection {}",
+			embedding.id,
+			collection_name
+		);
+		collection.check_upsert(&embedding, upsert)?;
+		self.log(|| Operation::Upsert {
+			collection: collection_name.to_string(),
+			embeddings: vec![embedding.clone()],
+			upsert,
+		})
+		.await?;
+
+		let upserted = collection.upsert(embedding, upsert)?;
+		collection.publish();
+		drop(collection);
+		Ok(upserted)
+	}
+
+	/// Inserts or replaces embeddings one by one, returning the result for each of them
+	pub async fn insert_many_into_collection(
+		&self,
+		collection_name: &str,
+		embeddings: Vec<Embedding>,
+		upsert: bool,
+	) -> Result<Vec<Result<Upserted, Error>>, Error> {
+		let mut collection = self.write_collection(collection_name).await?;
+
+		tracing::debug!(
+			"Inserting {} embeddings to collection {}",
+			embeddings.len(),
+			collection_name
+		);
+		// embeddings inserted earlier in the batch exist, when the later ones are
+		let mut inserted = HashSet::new();
+		let checked = embeddings
+			.iter()
+			.map(|embedding| {
+				collection.check_upsert(embedding, upsert)?;
+				if !upsert && !inserted.insert(embedding.id.as_str()) {
+					return Err(Error::UniqueViolation);
+				}
+				Ok(())
+			})
+			.collect::<Vec<_>>();
+		if checked.iter().any(Result::is_ok) {
+			self.log(|| Operation::Upsert {
+				collection: collection_name.to_string(),
+				embeddings: embeddings
+					.iter()
+					.zip(&checked)
+					.filter(|(_, result)| result.is_ok())
+					.map(|(embedding, _)| embedding.clone())
+					.collect(),
+				upsert,
+			})
+			.await?;
+		}
+
+		let results = embeddings
+			.into_iter()
+			.zip(checked)
+			.map(|(embedding, checked)| checked.and_then(|()| collection.upsert(embedding, upsert)))
+			.collect();
+		collection.publish();
+		drop(collection);
+		Ok(results)
+	}
+
+	/// Replaces the vector, the metadata or both of an existing embedding, the metadata
+	/// are merged into the current ones as a patch if `merge` is set
+	#[allow(clippy::option_option)]
+	pub async fn update_embedding(
+		&self,
+		collection_name: &str,
+		id: &str,
+		vector: Option<Vec<f32>>,
+		metadata: Option<Option<Metadata>>,
+		merge: bool,
+	) -> Result<(), Error> {
+		let mut collection = self.write_collection(collection_name).await?;
+
+		collection.check_update(id, vector.as_deref())?;
+		self.log(|| Operation::Update {
+			collection: collection_name.to_string(),
+			id: id.to_string(),
+			vector: vector.clone(),
+			metadata: metadata.clone(),
+			merge,
+		})
+		.await?;
+
+		let (metadata, patch) = match metadata {
+			Some(Some(patch)) if merge => (None, Some(patch)),
+			metadata => (metadata, None),
+		};
+		collection.update(id, vector, metadata)?;
+		if let Some(patch) = patch {
+			collection.merge_metadata(id, &patch);
+		}
+		collection.set_dirty();
+		collection.publish();
+		drop(collection);
+		Ok(())
+	}
+
+	/// Merges the patch into the metadata of an embedding, returns `false` if it doesn't exist
+	pub async fn merge_metadata(
+		&self,
+		collection_name: &str,
+		id: &str,
+		patch: Metadata,
+	) -> Result<bool, Error> {
+		let mut collection = self.write_collection(collection_name).await?;
+
+		if !collection.ids.contains_key(id) {
+			return Ok(false);
+		}
+		self.log(|| Operation::MergeMetadata {
+			collection: collection_name.to_string(),
+			ids: vec![id.to_string()],
+			patch: patch.clone(),
+		})
+		.await?;
+
+		collection.merge_metadata(id, &patch);
+		collection.set_dirty();
+		collection.publish();
+		drop(collection);
+		Ok(true)
+	}
+
+	/// Merges the patch into the metadata of all embeddings matching the filter
+	/// and returns their number
+	pub async fn merge_metadata_by_filter(
+		&self,
+		collection_name: &str,
+		filter: &Filter,
+		patch: Metadata,
+	) -> Result<usize, Error> {
+		let mut collection = self.write_collection(collection_name).await?;
+
+		let indexes = collection.matching_slots(filter);
+		if indexes.is_empty() {
+			return Ok(0);
+		}
+		self.log(|| Operation::MergeMetadata {
+			collection: collection_name.to_string(),
+			ids: collection.ids_of(&indexes),
+			patch: patch.clone(),
+		})
+		.await?;
+
+		collection.patch_slots(&indexes, &patch);
+		collection.set_dirty();
+		collection.publish();
+		drop(collection);
+		Ok(indexes.len())
+	}
+
+	/// Deletes an embedding, returns `false` if it doesn't exist
+	pub async fn delete_embedding(&self, collection_name: &str, id: &str) -> Result<bool, Error> {
+		let mut collection = self.write_collection(collection_name).await?;
+
+		if !collection.ids.contains_key(id) {
+			return Ok(false);
+		}
+		self.log(|| Operation::Delete {
+			collection: collection_name.to_string(),
+			ids: vec![id.to_string()],
+		})
+		.await?;
+
+		collection.delete(id);
+		collection.set_dirty();
+		collection.publish();
+		drop(collection);
+		Ok(true)
+	}
+
+	/// Deletes all embeddings matching the filter
+	pub async fn delete_by_metadata(
+		&self,
+		collection_name: &str,
+		filter: &Filter,
+	) -> Result<(), Error> {
+		let mut collection = self.write_collection(collection_name).await?;
+
+		let indexes = collection.matching_slots(filter);
+		if indexes.is_empty() {
+			return Ok(());
+		}
+		self.log(|| Operation::Delete {
+			collection: collection_name.to_string(),
+			ids: collection.ids_of(&indexes),
+		})
+		.await?;
+
+		collection.delete_slots(&indexes);
+		collection.set_dirty();
+		collection.publish();
+		drop(collection);
+		Ok(())
+	}
+
+	pub async fn rebuild_index(&self, collection_name: &str) -> Result<(), Error> {
+		let mut collection = self.write_collection(collection_name).await?;
+
+		self.log(|| Operation::RebuildIndex {
+			collection: collection_name.to_string(),
+		})
+		.await?;
+
+		collection.rebuild_index();
+		collection.publish();
+		drop(collection);
+		Ok(())
+	}
+
+	/// Appends the mutation to the write-ahead log, unless the database isn't stored
+	/// or the log is being replayed. Mutations are checked and logged while their
+	/// collection is locked, before they are applied, so that the log keeps their
+	/// order and a mutation, which failed to be logged, doesn't change the collection.
+	async fn log(&self, operation: impl FnOnce() -> Operation) -> Result<(), Error> {
+		if let Some(wal) = &self.wal {
+			wal.append(&operation()).await?;
+		}
+		Ok(())
+	}
+
+	/// Applies a mutation read from the write-ahead log
+	async fn apply(&self, operation: Operation) -> Result<(), Error> {
+		match operation {
+			Operation::CreateCollection {
+				name,
+				dimension,
+				distance,
+				index,
+				quantization,
+				metadata_index,
+			} => self
+				.create_collection(
+					name,
+					dimension,
+					distance,
+					index,
+					quantization,
+					metadata_index,
+				)
+				.await
+				.map(drop),
+			Operation::RenameCollection { name, new_name } => {
+				self.rename_collection(&name, new_name).await
+			},
+			Operation::DeleteCollection { name } => self.delete_collection(&name).await,
+			Operation::RebuildIndex { collection } => self.rebuild_index(&collection).await,
+			Operation::Upsert {
+				collection,
+				embeddings,
+				upsert,
+			} => self
+				.insert_many_into_collection(&collection, embeddings, upsert)
+				.await
+				.map(drop),
+			Operation::Update {
+				collection,
+				id,
+				vector,
+				metadata,
+				merge,
+			} => {
+				self.update_embedding(&collection, &id, vector, metadata, merge)
+					.await
+			},
+			Operation::MergeMetadata {
+				collection,
+				ids,
+				patch,
+			} => {
+				for id in ids {
+					self.merge_metadata(&collection, &id, patch.clone()).await?;
+				}
+				Ok(())
+			},
+			Operation::Delete { collection, ids } => {
+				for id in ids {
+					self.delete_embedding(&collection, &id).await?;
+				}
+				Ok(())
+			},
+		}
+	}
+
+	/// Returns the latest version of the collection, loading it if needed, which
+	/// stays the same while it is used, without blocking the writers
+	pub async fn read_collection(&self, name: &str) -> Option<Arc<Collection>> {
+		tracing::debug!("Getting collection {}", name);
+		let collection = match self.slot(name)?.published().await {
+			Some(collection) => collection,
+			None => Arc::clone(&self.write_collection(name).await.ok()?.next),
+		};
+		self.lru.touch(name);
+		Some(collection)
+	}
+
+	/// Locks the collection for making its next version, loading it if needed
+	pub async fn write_collection(&self, name: &str) -> Result<CollectionWriter, Error> {
+		let cell = self.slot(name).ok_or(Error::NotFound)?;
+		let writer = Arc::clone(&cell.writer).lock_owned().await;
+		let unloaded = matches!(*cell.lock(), Slot::Unloaded(_));
+		if unloaded {
+			self.load(name, &cell);
+		}
+		let collection = CollectionWriter::new(cell, writer).ok_or(Error::NotFound)?;
+		self.lru.touch(name);
+		Ok(collection)
+	}
+
+	/// If the collection exists, whether it is loaded or not
+	pub fn contains(&self, name: &str) -> bool {
+		self.lock_collections().contains_key(name)
+	}
+
+	/// Returns the summary of the collection and whether it is loaded, the collection
+	/// is loaded only if it hasn't been loaded yet
+	pub async fn summary(&self, name: &str) -> Option<(Summary, bool)> {
+		let loaded = match &*self.slot(name)?.lock() {
+			Slot::Loaded(collection) => Some(Arc::clone(collection)),
+			Slot::Unloaded(Some(summary)) => return Some((summary.clone(), false)),
+			Slot::Writing | Slot::Unloaded(None) => None,
+			Slot::Removed => return None,
+		};
+		let collection = match loaded {
+			Some(collection) => collection,
+			None => self.read_collection(name).await?,
+		};
+		Some((collection.summary(), true))
+	}
+
+	pub fn list(&self) -> Vec<String> {
+		let collections = self.lock_collections();
+		tracing::debug!("Listing {} collections", collections.len());
+		collections.keys().map(ToOwned::to_owned).collect()
+	}
+
+	fn lock_collections(&self) -> MutexGuard<'_, HashMap<String, CollectionLock>> {
+		self.collections
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+	}
+
+	fn lock_deleted(&self) -> MutexGuard<'_, HashSet<String>> {
+		self.deleted.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+
+	fn slot(&self, name: &str) -> Option<CollectionLock> {
+		self.lock_collections().get(name).cloned()
+	}
+
+	/// Returns the collections with their locks, which can be locked one by one
+	fn slots(&self) -> Vec<(String, CollectionLock)> {
+		self.lock_collections()
+			.iter()
+			.map(|(name, lock)| (name.clone(), Arc::clone(lock)))
+			.collect()
+	}
+
+	/// Loads the stored collection to its cell and unloads other collections, if
+	/// the loaded ones exceed the memory budget
+	fn load(&self, name: &str, cell: &CollectionCell) {
+		match read_collection_file(name, false) {
+			Ok(Some(collection)) => {
+				self.lru.insert(name, collection.memory_size());
+				cell.publish(Slot::Loaded(Arc::new(collection)));
+				self.unload_idle();
+			},
+			// the collection was moved to the quarantine
+			Ok(None) => {
+				self.lock_collections().remove(name);
+				cell.publish(Slot::Removed);
+			},
+			Err(error) => tracing::error!("Collection {} couldn't be loaded: {error}", name),
+		}
+	}
+
+	/// Unloads the least recently used collections, which have been saved and aren't
+	/// being modified, while the loaded collections take more memory than the budget.
+	/// The last used collection is kept, even if it doesn't fit in the budget alone.
+	/// Queries, which still use an unloaded collection, keep it until they end.
+	fn unload_idle(&self) {
+		let Some(budget) = self.memory_budget else {
+			return;
+		};
+		let mut size = self.lru.size();
+		if size <= budget {
+			return;
+		}
+		let mut sorted = self.lru.sorted();
+		sorted.pop();
+		for (name, collection_size) in sorted {
+			if size <= budget {
+				break;
+			}
+			let Some(cell) = self.slot(&name) else {
+				continue;
+			};
+			let Ok(writer) = cell.writer.try_lock() else {
+				continue;
+			};
+			let mut slot = cell.lock();
+			if let Slot::Loaded(collection) = &*slot {
+				if !collection.is_dirty() {
+					tracing::debug!("Unloading collection {}", name);
+					let summary = collection.summary();
+					*slot = Slot::Unloaded(Some(summary));
+					self.lru.remove(&name);
+					size -= collection_size;
+				}
+			}
+			drop(slot);
+			drop(writer);
+		}
+	}
+
+	/// Loads the collections, replays the mutations logged since they were saved
+	/// and starts logging the next ones
+	async fn load_from_store(
+		durability: Durability,
+		memory_budget: Option<usize>,
+	) -> anyhow::Result<Self> {
+		let mut db = Self::load_store()?;
+		db.memory_budget = memory_budget;
+
+		let wal_path = STORE_PATH.join("._wal");
+		let operations = Wal::read(&wal_path)?;
+		if !operations.is_empty() {
+			tracing::debug!("Replaying {} logged mutations", operations.len());
+			for operation in operations {
+				if let Err(error) = db.apply(operation).await {
+					tracing::warn!("Skipping logged mutation: {error}");
+				}
+			}
+		}
+
+		let wal = Wal::open(&wal_path, durability)?;
+		let logged = wal.len()? > 0;
+		db.wal = Some(wal);
+		// the log is emptied once the replayed mutations have been saved, which
+		// also drops a line that wasn't written completely
+		if logged {
+			db.save_to_store().await?;
+		}
+		Ok(db)
+	}
+
+	fn load_store() -> anyhow::Result<Self> {
+		migration::migrate()?;
+		let db = Self::new();
+		let mut collections = db.lock_collections();
+		for name in stored_collections()? {
+			collections.insert(name, Arc::new(CollectionCell::new(Slot::Unloaded(None))));
+		}
+		drop(collections);
+		Ok(db)
+	}
+
+	/// If any collection was modified since it was saved, collections, which are
+	/// being modified, count as modified
+	pub fn is_dirty(&self) -> bool {
+		!self.lock_deleted().is_empty()
+			|| self.slots().iter().any(|(_, cell)| {
+				cell.writer.try_lock().is_err()
+					|| cell
+						.current()
+						.is_some_and(|collection| collection.is_dirty())
+			})
+	}
+
+	pub async fn save_to_store(&self) -> anyhow::Result<()> {
+		let saving = self.saving.lock().await;
+		self.save().await?;
+		drop(saving);
+		Ok(())
+	}
+
+	/// Saves the modified collections one by one, blocking only the writers of the
+	/// one being saved, and drops the mutations logged before the saving started
+	/// from the log. The saved collection maps the vectors from the saved file,
+	/// queries wait for it only while the written files are applied to it.
+	async fn save(&self) -> anyhow::Result<()> {
+		let mark = self.wal.as_ref().map(Wal::len).transpose()?;
+		self.delete_collections()?;
+		for (name, cell) in self.slots() {
+			if let Some(collection) = self.write_saved(&name, cell).await {
+				if collection.is_dirty() {
+					let mut collection =
+						write_blocking(name.clone(), collection, store_collection).await?;
+					self.lru.resize(&name, collection.memory_size());
+					collection.publish();
+				}
+			}
+		}
+		if let (Some(wal), Some(mark)) = (&self.wal, mark) {
+			wal.truncate(mark)?;
+		}
+		// the collections can be unloaded only after they have been saved
+		self.unload_idle();
+		Ok(())
+	}
+
+	/// Merges the segments of the saved collections, which have too many of them,
+	/// blocking only the writers of the one being merged
+	pub async fn merge_segments(&self) -> anyhow::Result<()> {
+		let saving = self.saving.lock().await;
+		for (name, cell) in self.slots() {
+			if let Some(collection) = self.write_saved(&name, cell).await {
+				// modified collections are merged after their next saving
+				if !collection.is_dirty() && collection.needs_merge() {
+					let mut collection =
+						write_blocking(name.clone(), collection, merge_collection).await?;
+					self.lru.resize(&name, collection.memory_size());
+					collection.publish();
+				}
+			}
+		}
+		drop(saving);
+		Ok(())
+	}
+
+	/// Saves the collections and takes a snapshot of the named ones, or of all
+	pub async fn create_snapshot(&self, names: Option<Vec<String>>) -> anyhow::Result<Snapshot> {
+		let mut names = names.unwrap_or_else(|| self.list());
+		names.sort();
+		names.dedup();
+		tracing::debug!("Taking snapshot of {} collections", names.len());
+		// another saving could replace the files before they are linked
+		let saving = self.saving.lock().await;
+		self.save().await?;
+		let snapshot = snapshot::create(&STORE_PATH, names)?;
+		drop(saving);
+		Ok(snapshot)
+	}
+
+	/// Replaces the collections by the ones loaded from a snapshot and saves them
+	pub async fn restore_snapshot(
+		&self,
+		collections: HashMap<String, Collection>,
+	) -> anyhow::Result<()> {
+		tracing::debug!("Restoring {} collections from snapshot", collections.len());
+		for (name, mut collection) in collections {
+			collection.rewrite_all();
+			self.lru.insert(&name, collection.memory_size());
+			self.replace_collection(name, collection).await;
+		}
+		// the saving drops the logged mutations of the replaced collections, the
+		// restoring isn't logged
+		self.save_to_store().await
+	}
+
+	/// Locks a loaded collection for saving it, unloaded collections haven't been
+	/// modified since they were saved
+	async fn write_saved(&self, name: &str, cell: CollectionLock) -> Option<CollectionWriter> {
+		let writer = Arc::clone(&cell.writer).lock_owned().await;
+		let collection = CollectionWriter::new(cell, writer)?;
+		tracing::trace!("Locked collection {} for saving", name);
+		Some(collection)
+	}
+
+	/// Puts the collection in place of the one with the same name, or adds it
+	async fn replace_collection(&self, name: String, collection: Collection) {
+		let collection = Arc::new(collection);
+		loop {
+			let cell = match self.lock_collections().entry(name.clone()) {
+				Entry::Occupied(entry) => Arc::clone(entry.get()),
+				Entry::Vacant(entry) => {
+					self.lock_deleted().remove(&name);
+					entry.insert(Arc::new(CollectionCell::new(Slot::Loaded(collection))));
+					return;
+				},
+			};
+			let writer = cell.writer.lock().await;
+			// the collection could be deleted while it was waited for
+			let removed = matches!(*cell.lock(), Slot::Removed);
+			if !removed {
+				cell.publish(Slot::Loaded(collection));
+				return;
+			}
+			drop(writer);
+		}
+	}
+
+	fn delete_collections(&self) -> anyhow::Result<()> {
+		let deleted = mem::take(&mut *self.lock_deleted());
+		for name in &deleted {
+			tracing::debug!("Deleting collection {} from store", name);
+			// collections created after the last storing haven't been stored yet
+			let removed = match fs::remove_file(collection_path(&STORE_PATH, name)) {
+				Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+				_ => segment::remove(&segment::dir(&STORE_PATH, name)),
+			};
+			if let Err(error) = removed {
+				self.lock_deleted().extend(deleted);
+				return Err(error.into());
+			}
+		}
+		Ok(())
+	}
+
+	/// Saves the modified collections, while the database isn't shared
+	pub fn store_collections(&self) -> anyhow::Result<()> {
+		for (name, cell) in self.slots() {
+			let Ok(writer) = Arc::clone(&cell.writer).try_lock_owned() else {
+				anyhow::bail!("collection {name} is locked");
+			};
+			if let Some(mut collection) = CollectionWriter::new(cell, writer) {
+				if collection.is_dirty() {
+					store_collection(&name, &mut collection)?;
+					collection.publish();
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Marks the loaded collections to be saved again whole
+	pub fn rewrite_all(&self) {
+		for (_, cell) in self.slots() {
+			if let Slot::Loaded(collection) = &mut *cell.lock() {
+				Arc::make_mut(collection).rewrite_all();
+			}
+		}
+	}
+
+	/// Loads all stored collections at once
+	pub fn load_collections(&self, string_metadata: bool) -> anyhow::Result<()> {
+		for name in stored_collections()? {
+			if let Some(collection) = read_collection_file(&name, string_metadata)? {
+				self.lock_collections().insert(
+					name,
+					Arc::new(CollectionCell::new(Slot::Loaded(Arc::new(collection)))),
+				);
+			}
+		}
+		Ok(())
+	}
+
+	/// Splits the single file of the oldest store to a file per collection, keeping
+	/// the format of the collections
+	pub fn convert_old_store() -> anyhow::Result<()> {
+		let db_path = STORE_PATH.join("db");
+		if db_path.exists() {
+			let binary = fs::read(db_path.clone())?;
+			let collections: HashMap<String, StringMetadataCollection> =
+				bincode::deserialize(&binary[..])?;
+			for (name, collection) in collections {
+				let file_name = encode_component(&name).to_string();
+				fs::write(STORE_PATH.join(file_name), bincode::serialize(&collection)?)?;
+			}
+			fs::remove_file(db_path)?;
+		}
+		Ok(())
+	}
+}
+
+impl Drop for Db {
+	fn drop(&mut self) {
+		if self.is_dirty() {
+			self.delete_collections().ok();
+			if self.store_collections().is_ok() {
+				if let Some(wal) = &self.wal {
+					wal.len().and_then(|mark| wal.truncate(mark)).ok();
+				}
+			}
+		}
+	}
+}
+
+pub async fn from_store(
+	durability: Durability,
+	memory_budget: Option<usize>,
+) -> anyhow::Result<Arc<Db>> {
+	Ok(Arc::new(
+		Db::load_from_store(durability, memory_budget).await?,
+	))
+}
+
+/// Saves or merges the collection on a blocking thread, so that writing the files
+/// doesn't block the other tasks, and returns the collection still locked
+async fn write_blocking(
+	name: String,
+	mut collection: CollectionWriter,
+	write: fn(&str, &mut CollectionWriter) -> anyhow::Result<()>,
+) -> anyhow::Result<CollectionWriter> {
+	tokio::task::spawn_blocking(move || {
+		write(&name, &mut collection)?;
+		Ok(collection)
+	})
+	.await?
+}
+
+/// Saves the collection to its file, dropping its deleted slots first, if there are
+/// too many of them. Only the slots modified since the last saving are written to
+/// a new segment, unless the whole collection has to be written again.
+fn store_collection(name: &str, collection: &mut CollectionWriter) -> anyhow::Result<()> {
+	if collection.needs_compaction() {
+		collection.compact();
+	}
+	tracing::debug!("Saving collection {} to store", name);
+	let dir = segment::dir(&STORE_PATH, name);
+	fs::create_dir_all(&dir)?;
+	let mut segments = collection.segments.clone();
+	let mut checkpoint = collection.checkpoint;
+	let mut stored = None;
+	if collection.rewrite {
+		let id = segment::next_id(&dir)?;
+		let slots: Slots = (0..collection.records.len())
+			.map(|slot| (slot, true))
+			.collect();
+		let (segment, values) = collection.write_segment(&dir, id, &slots)?;
+		segments = vec![segment];
+		checkpoint = collection.write_checkpoint(&dir, &segments)?;
+		stored = Some((values, slots.slots));
+	} else if !collection.head.is_empty() {
+		let id = match segments.last() {
+			Some(segment) => segment.id + 1,
+			None => segment::next_id(&dir)?,
+		};
+		let slots: Slots = collection
+			.head
+			.iter()
+			.map(|(&slot, &vector)| (slot, vector))
+			.collect();
+		let (segment, values) = collection.write_segment(&dir, id, &slots)?;
+		segments.push(segment);
+		stored = Some((values, slots.slots));
+	}
+	let stored_size =
+		collection.write_manifest(&collection_path(&STORE_PATH, name), &segments, checkpoint)?;
+	segment::remove_unused(&dir, &segments, checkpoint)?;
+	collection.set_saved(Saved {
+		stored,
+		segments,
+		checkpoint,
+		stored_size,
+	});
+	Ok(())
+}
+
+/// Merges the newest segments of a saved collection to one and writes a new
+/// checkpoint, so that loading the collection doesn't replay them
+fn merge_collection(name: &str, collection: &mut CollectionWriter) -> anyhow::Result<()> {
+	let dir = segment::dir(&STORE_PATH, name);
+	let Some(last) = collection.segments.last().map(|segment| segment.id) else {
+		return Ok(());
+	};
+	let mut segments = collection.segments.clone();
+	let stored = if let Some(range) = segment::merge_range(&segments) {
+		tracing::debug!("Merging {} segments of collection {}", range.len(), name);
+		let mut slots = BTreeMap::new();
+		for segment in &segments[range.clone()] {
+			let stored = store::read(&segment::segment_path(&dir, segment.id))?;
+			let segment_slots: Slots = bincode::deserialize_from(stored.content())?;
+			for (slot, vector) in segment_slots.iter() {
+				*slots.entry(slot).or_default() |= vector;
+			}
+		}
+		segments.truncate(range.start);
+		let slots: Slots = slots.into_iter().collect();
+		let (segment, values) = collection.write_segment(&dir, last + 1, &slots)?;
+		segments.push(segment);
+		Some((values, slots.slots))
+	} else {
+		None
+	};
+	tracing::debug!("Writing checkpoint of collection {}", name);
+	let checkpoint = collection.write_checkpoint(&dir, &segments)?;
+	let stored_size =
+		collection.write_manifest(&collection_path(&STORE_PATH, name), &segments, checkpoint)?;
+	segment::remove_unused(&dir, &segments, checkpoint)?;
+	collection.set_saved(Saved {
+		stored,
+		segments,
+		checkpoint,
+		stored_size,
+	});
+	Ok(())
+}
+
+fn collection_path(store_path: &Path, name: &str) -> PathBuf {
+	store_path.join(encode_component(name).to_string())
+}
+
+/// Returns the names of the collection files in the store, removing the files left
+/// behind by an interrupted saving
+fn stored_collections() -> anyhow::Result<Vec<String>> {
+	store::clean(&STORE_PATH)?;
+	segment::clean(&STORE_PATH)?;
+	snapshot::clean(&STORE_PATH)?;
+	let mut collections = Vec::new();
+	for entry in fs::read_dir(STORE_PATH.as_path())? {
+		let entry = entry?;
+		let entry_name = entry.file_name();
+		if entry_name == "._collections"
+			|| entry_name == "._metadata"
+			|| entry_name == "._wal"
+			|| entry.file_type()?.is_dir()
+		{
+			continue;
+		}
+		let file_name = entry_name.to_str().ok_or(Error::NotFound)?;
+		collections.push(decode(file_name).to_string());
+	}
+	Ok(collections)
+}
+
+/// Reads a collection file with its segments from the store, or moves them to the
+/// quarantine and returns `None` if they can't be loaded, so that a damaged collection
+/// doesn't prevent the others from being served
+fn read_collection_file(name: &str, string_metadata: bool) -> io::Result<Option<Collection>> {
+	tracing::debug!("Loading collection {} from store", name);
+	let path = collection_path(&STORE_PATH, name);
+	let dir = segment::dir(&STORE_PATH, name);
+	match read_collection(&path, &dir, string_metadata) {
+		Ok(collection) => Ok(Some(collection)),
+		Err(error) => {
+			let quarantine_path = store::quarantine(&path)?;
+			segment::quarantine(&dir)?;
+			tracing::error!(
+				"Collection {} is corrupted ({error}), moved it to {}",
+				name,
+				quarantine_path.display()
+			);
+			Ok(None)
+		},
+	}
+}
+
+fn read_collection(path: &Path, dir: &Path, string_metadata: bool) -> anyhow::Result<Collection> {
+	let stored = store::read(path)?;
+	Collection::from_stored(&stored, dir, string_metadata)
+}
+
+/// Loads the collections from a snapshot without locking the database
+pub fn load_snapshot(snapshot: &Snapshot) -> anyhow::Result<HashMap<String, Collection>> {
+	let snapshot_path = snapshot::dir(&STORE_PATH, snapshot);
+	snapshot
+		.collections
+		.iter()
+		.map(|name| {
+			let collection = read_collection(
+				&collection_path(&snapshot_path, name),
+				&segment::dir(&snapshot_path, name),
+				false,
+			)?;
+			Ok((name.clone(), collection))
+		})
+		.collect()
+}
+
+/// Flushes the write-ahead log to the disk every second
+pub fn sync_log(db: Arc<Db>) {
+	let mut interval = time::interval(Duration::from_secs(1));
 	tokio::spawn(async move {
 		loop {
 			interval.tick().await;
-			let dbr = db.read().await;
-			let dirty = dbr.is_dirty();
-			drop(dbr);
-			if dirty {
-				let mut dbw = db.write().await;
-				if dbw.is_dirty() {
-					dbw.save_to_store().ok();
+			if let Some(wal) = &db.wal {
+				if let Err(error) = wal.sync().await {
+					tracing::error!("Flushing the log failed: {error}");
 				}
-				drop(dbw);
 			}
 		}
 	});
 }
+
+pub fn autosave(db: Arc<Db>, duration: u32) {
+	let mut interval = time::interval(Duration::from_secs(duration.into()));
+	tokio::spawn(async move {
+		loop {
+			interval.tick().await;
+			if db.is_dirty() {
+				db.save_to_store().await.ok();
+			}
+			if let Err(error) = db.merge_segments().await {
+				tracing::error!("Merging segments failed: {error}");
+			}
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{quantization::Calibration, testing};
+	use serde_json::json;
+	use std::io::Write;
+
+	fn embedding(id: &str, vector: Vec<f32>) -> Embedding {
+		Embedding {
+			id: id.to_string(),
+			vector,
+			metadata: None,
+		}
+	}
+
+	/// Returns a collection of the vectors, normalised for the cosine similarity
+	fn collection(distance: Distance, vectors: &[[f32; 2]]) -> Collection {
+		let embeddings = vectors
+			.iter()
+			.enumerate()
+			.map(|(index, vector)| {
+				let vector = if distance == Distance::Cosine {
+					normalize(vector)
+				} else {
+					vector.to_vec()
+				};
+				embedding(&index.to_string(), vector)
+			})
+			.collect();
+		let mut collection = Collection::from_embeddings(2, distance, embeddings);
+		collection.index_embeddings();
+		collection
+	}
+
+	/// Returns a collection of random vectors identified by their slots
+	fn random(len: usize, distance: Distance) -> Collection {
+		let vectors = testing::vectors(len, 8, 1);
+		let embeddings = (0..vectors.len())
+			.map(|index| embedding(&index.to_string(), vectors.vector(index).to_vec()))
+			.collect();
+		let mut collection = Collection::from_embeddings(8, distance, embeddings);
+		collection.index_embeddings();
+		collection
+	}
+
+	fn search(collection: &Collection, query: &[f32]) -> Vec<(String, f32, ScoreType)> {
+		collection
+			.get_by_metadata_and_similarity(&Filter::default(), query, 3, SearchParams::default())
+			.0
+			.into_iter()
+			.map(|result| (result.embedding.id, result.score, result.score_type))
+			.collect()
+	}
+
+	#[test]
+	fn sorts_distances_from_lowest_and_similarities_from_highest() {
+		let vectors = [[0.0, 1.0], [1.0, 0.0], [3.0, 4.0]];
+		let found = search(&collection(Distance::Euclidean, &vectors), &[3.0, 0.0]);
+		let ids: Vec<&str> = found.iter().map(|(id, ..)| id.as_str()).collect();
+		assert_eq!(ids, ["1", "0", "2"]);
+		assert!((found[0].1 - 2.0).abs() < 1e-6);
+		assert!((found[2].1 - 4.0).abs() < 1e-6);
+		assert!(found
+			.iter()
+			.all(|(.., score_type)| *score_type == ScoreType::Distance));
+
+		// the query is normalised like the stored vectors
+		let found = search(&collection(Distance::Cosine, &vectors), &[3.0, 0.0]);
+		let ids: Vec<&str> = found.iter().map(|(id, ..)| id.as_str()).collect();
+		assert_eq!(ids, ["1", "2", "0"]);
+		assert!((found[0].1 - 1.0).abs() < 1e-6);
+		assert!((found[1].1 - 0.6).abs() < 1e-6);
+		assert!(found
+			.iter()
+			.all(|(.., score_type)| *score_type == ScoreType::Similarity));
+	}
+
+	#[test]
+	fn prefilters_by_metadata_index() {
+		let vectors: Vec<[f32; 2]> = (0..20_u8).map(|index| [f32::from(index), 1.0]).collect();
+		let mut scanned = collection(Distance::Euclidean, &vectors);
+		for (index, record) in scanned.records.iter_mut().enumerate() {
+			let metadata = json!({
+				"page": index % 5,
+				"tag": if index % 2 == 0 { "even" } else { "odd" }
+			});
+			record.metadata = Some(serde_json::from_value(metadata).unwrap());
+		}
+		let mut indexed = scanned.clone();
+		indexed.metadata_index = InvertedIndex::new(vec!["page".to_string()]).into();
+		indexed.index_embeddings();
+
+		let filter: Filter = serde_json::from_value(json!({
+			"page": { "$gte": 3 },
+			"tag": "odd"
+		}))
+		.unwrap();
+		let candidates = indexed.candidates(&filter);
+		assert_eq!(candidates, [3, 4, 8, 9, 13, 14, 18, 19]);
+		let ids = |embeddings: Vec<Embedding>| -> Vec<String> {
+			embeddings
+				.into_iter()
+				.map(|embedding| embedding.id)
+				.collect()
+		};
+		let found = ids(indexed.get_by_metadata(&filter, 10, true));
+		assert_eq!(found, ["3", "9", "13", "19"]);
+		assert_eq!(found, ids(scanned.get_by_metadata(&filter, 10, true)));
+
+		let similar = |collection: &Collection| -> Vec<String> {
+			collection
+				.get_by_metadata_and_similarity(&filter, &[10.0, 1.0], 3, SearchParams::default())
+				.0
+				.into_iter()
+				.map(|result| result.embedding.id)
+				.collect()
+		};
+		assert_eq!(similar(&indexed), ["9", "13", "3"]);
+		assert_eq!(similar(&indexed), similar(&scanned));
+
+		indexed.update_metadata("13", None);
+		indexed.delete("19");
+		assert_eq!(similar(&indexed), ["9", "3"]);
+	}
+
+	/// Returns a collection of random vectors compressed by the quantization
+	fn quantized(quantization: QuantizationOptions, distance: Distance) -> Collection {
+		let mut collection = random(500, distance);
+		collection.quantizer = Quantizer::new(Some(quantization), distance, 8).into();
+		collection.rebuild_index();
+		collection
+	}
+
+	#[test]
+	fn upserts_embeddings_into_index() {
+		let mut collection = random(200, Distance::Cosine);
+		collection.index = Index::new(
+			IndexOptions::Hnsw {
+				m: 16,
+				ef_construction: 64,
+			},
+			Distance::Cosine,
+		)
+		.into();
+		collection.rebuild_index();
+		let queries = testing::vectors(1, 8, 2);
+		let query = queries.vector(0);
+		let nearest = |collection: &Collection| {
+			let found = collection
+				.search(query, 1, SearchParams::default(), |_| true)
+				.unwrap();
+			collection.records[found[0].index].id.clone()
+		};
+
+		let replaced = embedding("5", query.to_vec());
+		assert!(matches!(
+			collection.upsert(replaced.clone(), false),
+			Err(Error::UniqueViolation)
+		));
+		assert!(matches!(
+			collection.upsert(embedding("5", vec![1.0; 4]), true),
+			Err(Error::DimensionMismatch)
+		));
+		assert_eq!(
+			collection.upsert(replaced, true).unwrap(),
+			Upserted::Updated
+		);
+		assert_eq!(collection.records.len(), 200);
+		assert_eq!(nearest(&collection), "5");
+
+		let created = embedding("new", query.iter().map(|value| value * 2.0).collect());
+		assert_eq!(collection.upsert(created, true).unwrap(), Upserted::Created);
+		assert_eq!(collection.records.len(), 201);
+		assert_eq!(collection.ids["new"], 200);
+		assert!(collection.is_dirty());
+	}
+
+	#[test]
+	fn updates_vectors_and_metadata() {
+		let mut collection = collection(Distance::Cosine, &[[1.0, 0.0], [0.0, 1.0]]);
+		let metadata: Metadata = serde_json::from_value(json!({ "page": 1 })).unwrap();
+		collection.update_metadata("0", Some(metadata.clone()));
+
+		// the metadata are kept if only the vector is replaced
+		collection.update("0", Some(vec![0.0, 2.0]), None).unwrap();
+		assert_eq!(collection.vectors.vector(0), [0.0, 1.0]);
+		assert_eq!(collection.records[0].metadata, Some(metadata));
+		let found = search(&collection, &[0.0, 1.0]);
+		assert!(found.iter().all(|(_, score, _)| (score - 1.0).abs() < 1e-6));
+
+		collection.update("0", None, Some(None)).unwrap();
+		assert_eq!(collection.records[0].metadata, None);
+		assert_eq!(collection.vectors.vector(0), [0.0, 1.0]);
+
+		assert!(matches!(
+			collection.update("0", Some(vec![1.0]), None),
+			Err(Error::DimensionMismatch)
+		));
+		assert!(matches!(
+			collection.update("2", None, Some(None)),
+			Err(Error::NotFound)
+		));
+	}
+
+	#[test]
+	fn merges_metadata_of_filtered_embeddings() {
+		let mut collection = collection(Distance::Cosine, &[[1.0, 0.0]; 6]);
+		collection.metadata_index = InvertedIndex::new(vec!["page".into(), "done".into()]).into();
+		for index in 0..6 {
+			let metadata = serde_json::from_value(json!({ "page": index % 3 })).unwrap();
+			collection.update_metadata(&index.to_string(), Some(metadata));
+		}
+		let done: Metadata = serde_json::from_value(json!({ "done": true })).unwrap();
+		let filter: Filter = serde_json::from_value(json!({ "page": { "$lt": 2 } })).unwrap();
+		let indexes = collection.matching_slots(&filter);
+		collection.patch_slots(&indexes, &done);
+		let mut patched = collection.ids_of(&indexes);
+		patched.sort();
+		assert_eq!(patched, ["0", "1", "3", "4"]);
+
+		let filter: Filter = serde_json::from_value(json!({ "done": true })).unwrap();
+		let mut ids: Vec<String> = collection
+			.get_by_metadata(&filter, 10, false)
+			.into_iter()
+			.map(|embedding| embedding.id)
+			.collect();
+		ids.sort();
+		assert_eq!(ids, ["0", "1", "3", "4"]);
+		assert_eq!(collection.records[3].metadata.as_ref().unwrap()["page"], 0);
+
+		let undone: Metadata = serde_json::from_value(json!({ "done": null })).unwrap();
+		assert!(collection.merge_metadata("3", &undone));
+		assert!(!collection.merge_metadata("6", &undone));
+		assert_eq!(collection.get_by_metadata(&filter, 10, false).len(), 3);
+		assert_eq!(
+			collection.records[3].metadata,
+			Some(serde_json::from_value(json!({ "page": 0 })).unwrap())
+		);
+	}
+
+	#[test]
+	fn reuses_slots_and_compacts_them() {
+		let vectors = testing::vectors(100, 8, 1);
+		let mut collection = random(100, Distance::Cosine);
+		collection.index = Index::new(
+			IndexOptions::Hnsw {
+				m: 8,
+				ef_construction: 32,
+			},
+			Distance::Cosine,
+		)
+		.into();
+		collection.rebuild_index();
+
+		assert!(collection.delete("5"));
+		assert!(!collection.delete("5"));
+		collection
+			.upsert(embedding("new", vectors.vector(5).to_vec()), false)
+			.unwrap();
+		assert_eq!(collection.records.len(), 100);
+		assert_eq!(collection.ids["new"], 5);
+
+		for index in (0..100).step_by(3) {
+			collection.delete(&index.to_string());
+		}
+		assert!(collection.needs_compaction());
+		let query = vectors.vector(1);
+		let found = collection.search(query, 1, SearchParams::default(), |index| {
+			!collection.tombstones.is_deleted(index)
+		});
+		assert_eq!(found.unwrap()[0].index, 1);
+
+		collection.compact();
+		assert_eq!(collection.records.len(), 66);
+		assert!(collection.tombstones.is_empty());
+		let new = collection.get("new", false).unwrap();
+		assert_eq!(new.vector, vectors.vector(5));
+		for index in (1..100).filter(|index| index % 3 != 0 && *index != 5) {
+			let embedding = collection.get(&index.to_string(), false).unwrap();
+			assert_eq!(embedding.vector, vectors.vector(index));
+		}
+		let found = collection.search(query, 1, SearchParams::default(), |_| true);
+		assert_eq!(found.unwrap()[0].index, collection.ids["1"]);
+	}
+
+	/// Checks that the candidates found in the quantized vectors are scored again
+	/// by the original vectors and that they include most of the exact results
+	fn assert_rescored(collection: &Collection, params: SearchParams) {
+		let queries = testing::vectors(20, 8, 2);
+		let distance_fn = get_distance_fn(collection.distance);
+		let mut recall = 0.0;
+		for query in 0..queries.len() {
+			let query = queries.vector(query);
+			let memo_attr = get_cache_attr(collection.distance, query);
+			let found = collection.search(query, 10, params, |_| true).unwrap();
+			for result in &found {
+				let vector = collection.vectors.vector(result.index);
+				let score = distance_fn(vector, query, memo_attr);
+				assert!((result.score - score).abs() <= f32::EPSILON);
+			}
+			let exact = testing::nearest(&*collection.vectors, query, 10, collection.distance);
+			recall += testing::recall(&found, &exact);
+		}
+		assert!(recall / 20.0 >= 0.9);
+	}
+
+	#[test]
+	fn rescores_product_quantization() {
+		let collection = quantized(
+			QuantizationOptions::Product { m: 4, rerank: true },
+			Distance::Cosine,
+		);
+		assert!(collection.quantizer.is_trained());
+		assert_rescored(&collection, SearchParams::default());
+	}
+
+	#[test]
+	fn rescores_scalar_quantization() {
+		let collection = quantized(
+			QuantizationOptions::Scalar {
+				calibration: Calibration::Dimension,
+				rerank: true,
+			},
+			Distance::Cosine,
+		);
+		assert!(collection.quantizer.is_trained());
+		assert_rescored(&collection, SearchParams::default());
+	}
+
+	#[test]
+	fn rescores_binary_quantization() {
+		let collection = quantized(QuantizationOptions::Binary, Distance::DotProduct);
+		// the signs of 8 values tell little about the vectors, more candidates are needed
+		assert_rescored(
+			&collection,
+			SearchParams {
+				oversampling: Some(16.0),
+				..SearchParams::default()
+			},
+		);
+	}
+
+	#[test]
+	fn converts_collections_with_string_metadata() {
+		// bincode stores the fields of a struct like the items of a tuple
+		let embeddings = vec![
+			(
+				"a".to_string(),
+				vec![1.0_f32, 0.0],
+				Some(HashMap::from([("page".to_string(), "1".to_string())])),
+			),
+			("b".to_string(), vec![0.0, 1.0], None),
+		];
+		let binary = bincode::serialize(&(2_usize, Distance::Euclidean, embeddings)).unwrap();
+		let collection = Collection::from_embedded_vectors(&binary, true).unwrap();
+		assert!(collection.is_dirty());
+		let metadata = collection.get("a", false).unwrap().metadata.unwrap();
+		assert_eq!(metadata["page"], json!("1"));
+		assert_eq!(collection.get("b", false).unwrap().metadata, None);
+		assert_eq!(collection.get("b", false).unwrap().vector, [0.0, 1.0]);
+	}
+
+	#[tokio::test]
+	async fn reads_vectors_mapped_from_file() {
+		let _store = testing::store().await;
+		let mut collection = random(100, Distance::Euclidean);
+		collection.delete("3");
+		let db = Db::new();
+		db.restore_snapshot(HashMap::from([("test".to_string(), collection)]))
+			.await
+			.unwrap();
+		let stored = store::read(&collection_path(&STORE_PATH, "test")).unwrap();
+		assert_eq!(stored.version, store::VERSION);
+		drop(db);
+
+		let db = from_store(Durability::Off, None).await.unwrap();
+		let loaded = db.read_collection("test").await.unwrap();
+		let vectors = testing::vectors(100, 8, 1);
+		assert_eq!(loaded.get("10", false).unwrap().vector, vectors.vector(10));
+		assert!(loaded.get("3", false).is_none());
+		drop(loaded);
+
+		// replaced and appended vectors are kept in memory until the next saving
+		db.update_embedding("test", "10", Some(vectors.vector(20).to_vec()), None, false)
+			.await
+			.unwrap();
+		db.insert_into_collection("test", embedding("new", vectors.vector(30).to_vec()), false)
+			.await
+			.unwrap();
+		let loaded = db.read_collection("test").await.unwrap();
+		assert_eq!(loaded.ids["new"], 3);
+		assert_eq!(loaded.get("10", false).unwrap().vector, vectors.vector(20));
+		assert_eq!(loaded.get("new", false).unwrap().vector, vectors.vector(30));
+		assert_eq!(loaded.get("11", false).unwrap().vector, vectors.vector(11));
+		drop(loaded);
+
+		db.save_to_store().await.unwrap();
+		let saved = db.read_collection("test").await.unwrap();
+		assert_eq!(saved.get("10", false).unwrap().vector, vectors.vector(20));
+		drop(saved);
+		let reloaded = read_collection_file("test", false).unwrap().unwrap();
+		assert_eq!(
+			reloaded.get("new", false).unwrap().vector,
+			vectors.vector(30)
+		);
+		assert_eq!(
+			reloaded.get("10", false).unwrap().vector,
+			vectors.vector(20)
+		);
+		assert_eq!(reloaded.embedding_count(), 100);
+	}
+
+	#[tokio::test]
+	async fn replays_only_modified_vectors() {
+		let _store = testing::store().await;
+		let collection = quantized(
+			QuantizationOptions::Product { m: 4, rerank: true },
+			Distance::Euclidean,
+		);
+		let db = Db::new();
+		db.restore_snapshot(HashMap::from([("test".to_string(), collection)]))
+			.await
+			.unwrap();
+		let vectors = testing::vectors(100, 8, 1);
+		db.update_embedding("test", "10", Some(vectors.vector(20).to_vec()), None, false)
+			.await
+			.unwrap();
+		db.save_to_store().await.unwrap();
+		let patch: Metadata = serde_json::from_value(json!({"page": 1})).unwrap();
+		db.merge_metadata("test", "10", patch).await.unwrap();
+		db.save_to_store().await.unwrap();
+
+		let saved = db.read_collection("test").await.unwrap();
+		let dir = segment::dir(&STORE_PATH, "test");
+		let written: Vec<Slots> = saved.segments[1..]
+			.iter()
+			.map(|segment| {
+				let stored = store::read(&segment::segment_path(&dir, segment.id)).unwrap();
+				bincode::deserialize_from(stored.content()).unwrap()
+			})
+			.collect();
+		assert_eq!(written[0].iter().collect::<Vec<_>>(), [(10, true)]);
+		assert_eq!(written[1].iter().collect::<Vec<_>>(), [(10, false)]);
+
+		let reloaded = read_collection_file("test", false).unwrap().unwrap();
+		let embedding = reloaded.get("10", false).unwrap();
+		assert_eq!(embedding.vector, vectors.vector(20));
+		assert_eq!(embedding.metadata, saved.get("10", false).unwrap().metadata);
+		assert_eq!(reloaded.quantizer.decode(10), saved.quantizer.decode(10));
+	}
+
+	#[tokio::test]
+	async fn replays_codes_of_dropped_vectors() {
+		let _store = testing::store().await;
+		let collection = quantized(
+			QuantizationOptions::Scalar {
+				calibration: Calibration::Dimension,
+				rerank: false,
+			},
+			Distance::Euclidean,
+		);
+		assert_eq!(collection.vectors.stride(), 0);
+		let db = Db::new();
+		db.restore_snapshot(HashMap::from([("test".to_string(), collection)]))
+			.await
+			.unwrap();
+		let vectors = testing::vectors(100, 8, 2);
+		db.update_embedding("test", "10", Some(vectors.vector(0).to_vec()), None, false)
+			.await
+			.unwrap();
+		db.insert_into_collection("test", embedding("new", vectors.vector(1).to_vec()), false)
+			.await
+			.unwrap();
+		db.save_to_store().await.unwrap();
+
+		let saved = db.read_collection("test").await.unwrap();
+		let last = saved.segments.last().unwrap();
+		let stored = store::read(&segment::segment_path(
+			&segment::dir(&STORE_PATH, "test"),
+			last.id,
+		))
+		.unwrap();
+		assert!(stored.vectors().unwrap().as_slice().is_empty());
+		let reloaded = read_collection_file("test", false).unwrap().unwrap();
+		assert_eq!(reloaded.ids["new"], 500);
+		for slot in [10, 11, 500] {
+			assert_eq!(reloaded.quantizer.codes(slot), saved.quantizer.codes(slot));
+		}
+	}
+
+	async fn create(db: &Db) {
+		db.create_collection(
+			"test".into(),
+			2,
+			Distance::Euclidean,
+			IndexOptions::Flat,
+			None,
+			vec!["page".into()],
+		)
+		.await
+		.unwrap();
+	}
+
+	/// Returns the embeddings of the collection sorted by their identifiers
+	#[allow(clippy::significant_drop_tightening)]
+	async fn sorted_embeddings(db: &Db, name: &str) -> Vec<serde_json::Value> {
+		let collection = db.read_collection(name).await.unwrap();
+		let mut ids: Vec<&String> = collection.ids.keys().collect();
+		ids.sort();
+		ids.into_iter()
+			.map(|id| serde_json::to_value(collection.get(id, false)).unwrap())
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn replays_logged_mutations() {
+		let _store = testing::store().await;
+		let wal_path = STORE_PATH.join("._wal");
+		let mut db = Db::new();
+		db.wal = Some(Wal::open(&wal_path, Durability::Off).unwrap());
+		create(&db).await;
+		let inserted = (0..10_u8)
+			.map(|index| embedding(&index.to_string(), vec![f32::from(index), 1.0]))
+			.collect();
+		db.insert_many_into_collection("test", inserted, false)
+			.await
+			.unwrap();
+		let metadata: Metadata = serde_json::from_value(json!({ "page": 1 })).unwrap();
+		db.update_embedding(
+			"test",
+			"1",
+			Some(vec![2.0, 2.0]),
+			Some(Some(metadata)),
+			true,
+		)
+		.await
+		.unwrap();
+		let filter: Filter = serde_json::from_value(json!({ "page": 1 })).unwrap();
+		let patch: Metadata = serde_json::from_value(json!({ "tag": "a" })).unwrap();
+		db.merge_metadata_by_filter("test", &filter, patch)
+			.await
+			.unwrap();
+		db.delete_embedding("test", "2").await.unwrap();
+		db.rename_collection("test", "renamed".to_string())
+			.await
+			.unwrap();
+		create(&db).await;
+		db.delete_collection("test").await.unwrap();
+		// a line written incompletely by a crash
+		fs::OpenOptions::new()
+			.append(true)
+			.open(&wal_path)
+			.unwrap()
+			.write_all(b"{\"op\":\"delete\",\"coll")
+			.unwrap();
+
+		let replayed = Db::new();
+		for operation in Wal::read(&wal_path).unwrap() {
+			replayed.apply(operation).await.unwrap();
+		}
+		assert_eq!(replayed.list(), ["renamed"]);
+		assert_eq!(
+			sorted_embeddings(&replayed, "renamed").await,
+			sorted_embeddings(&db, "renamed").await
+		);
+		assert_eq!(sorted_embeddings(&replayed, "renamed").await.len(), 9);
+		let renamed = replayed.read_collection("renamed").await.unwrap();
+		let metadata = renamed.get("1", false).unwrap().metadata.unwrap();
+		drop(renamed);
+		assert_eq!(metadata["tag"], "a");
+	}
+
+	#[cfg(target_os = "linux")]
+	#[tokio::test]
+	async fn keeps_collections_unchanged_if_logging_fails() {
+		let _store = testing::store().await;
+		let mut db = Db::new();
+		create(&db).await;
+		let inserted = (0..4_u8)
+			.map(|index| Embedding {
+				metadata: Some(serde_json::from_value(json!({ "page": index % 2 })).unwrap()),
+				..embedding(&index.to_string(), vec![f32::from(index), 1.0])
+			})
+			.collect();
+		db.insert_many_into_collection("test", inserted, false)
+			.await
+			.unwrap();
+		let expected = sorted_embeddings(&db, "test").await;
+		// writing to the device fails as if the disk was full
+		db.wal = Some(Wal::open(Path::new("/dev/full"), Durability::Off).unwrap());
+
+		let failed = |result| matches!(result, Err(Error::Log(_)));
+		assert!(failed(
+			db.insert_into_collection("test", embedding("4", vec![4.0, 1.0]), false)
+				.await
+				.map(drop)
+		));
+		let inserted = vec![embedding("5", vec![5.0, 1.0])];
+		assert!(failed(
+			db.insert_many_into_collection("test", inserted, false)
+				.await
+				.map(drop)
+		));
+		assert!(failed(
+			db.update_embedding("test", "1", Some(vec![5.0, 5.0]), None, false)
+				.await
+		));
+		let patch: Metadata = serde_json::from_value(json!({ "tag": "a" })).unwrap();
+		assert!(failed(
+			db.merge_metadata("test", "1", patch.clone())
+				.await
+				.map(drop)
+		));
+		let filter: Filter = serde_json::from_value(json!({ "page": 1 })).unwrap();
+		assert!(failed(
+			db.merge_metadata_by_filter("test", &filter, patch)
+				.await
+				.map(drop)
+		));
+		assert!(failed(db.delete_embedding("test", "2").await.map(drop)));
+		assert!(failed(db.delete_by_metadata("test", &filter).await));
+		assert!(failed(
+			db.rename_collection("test", "renamed".to_string()).await
+		));
+		assert!(failed(db.delete_collection("test").await));
+		assert!(failed(
+			db.create_collection(
+				"other".to_string(),
+				2,
+				Distance::Euclidean,
+				IndexOptions::Flat,
+				None,
+				Vec::new(),
+			)
+			.await
+			.map(drop)
+		));
+
+		assert_eq!(db.list(), ["test"]);
+		assert_eq!(sorted_embeddings(&db, "test").await, expected);
+		// the log isn't flushed, nor truncated, when the database is dropped
+		db.wal = None;
+	}
+
+	#[tokio::test]
+	async fn locks_collections_separately() {
+		let _store = testing::store().await;
+		let db = Db::new();
+		create(&db).await;
+		db.rename_collection("test", "other".to_string())
+			.await
+			.unwrap();
+		create(&db).await;
+
+		let locked = db.write_collection("test").await.unwrap();
+		let modified = time::timeout(
+			Duration::from_secs(1),
+			db.insert_into_collection("other", embedding("a", vec![1.0, 1.0]), false),
+		)
+		.await;
+		assert!(matches!(modified, Ok(Ok(Upserted::Created))));
+		// queries use the published version, other writers wait
+		assert!(db.read_collection("test").await.is_some());
+		let blocked = time::timeout(Duration::from_millis(100), db.write_collection("test"));
+		assert!(blocked.await.is_err());
+		drop(locked);
+		assert!(db.write_collection("test").await.is_ok());
+	}
+
+	#[tokio::test]
+	async fn modifies_collection_in_place() {
+		let _store = testing::store().await;
+		let db = Db::new();
+		create(&db).await;
+		let published = Arc::as_ptr(&db.read_collection("test").await.unwrap());
+		db.insert_into_collection("test", embedding("a", vec![1.0, 2.0]), false)
+			.await
+			.unwrap();
+		let collection = db.read_collection("test").await.unwrap();
+		assert_eq!(Arc::as_ptr(&collection), published);
+		assert_eq!(collection.embedding_count(), 1);
+	}
+
+	#[tokio::test]
+	async fn copies_collection_used_by_query() {
+		let _store = testing::store().await;
+		let db = Db::new();
+		create(&db).await;
+		let query = db.read_collection("test").await.unwrap();
+		db.insert_into_collection("test", embedding("a", vec![1.0, 2.0]), false)
+			.await
+			.unwrap();
+		let collection = db.read_collection("test").await.unwrap();
+		assert!(!Arc::ptr_eq(&collection, &query));
+		assert_eq!(query.embedding_count(), 0);
+		assert_eq!(collection.embedding_count(), 1);
+	}
+
+	#[tokio::test]
+	async fn shares_unmodified_parts_with_query() {
+		let _store = testing::store().await;
+		let db = Db::new();
+		create(&db).await;
+		db.insert_into_collection("test", embedding("a", vec![1.0, 2.0]), false)
+			.await
+			.unwrap();
+		let query = db.read_collection("test").await.unwrap();
+		let patch: Metadata = serde_json::from_value(json!({"page": 1})).unwrap();
+		assert!(db.merge_metadata("test", "a", patch).await.unwrap());
+		let collection = db.read_collection("test").await.unwrap();
+		assert!(!Arc::ptr_eq(&collection, &query));
+		assert!(query.records[0].metadata.is_none());
+		assert!(collection.records[0].metadata.is_some());
+		assert!(collection.vectors.ptr_eq(&query.vectors));
+		assert!(collection.index.ptr_eq(&query.index));
+		assert!(collection.quantizer.ptr_eq(&query.quantizer));
+	}
+
+	#[tokio::test]
+	async fn publishes_collection_after_failed_modification() {
+		let _store = testing::store().await;
+		let db = Db::new();
+		create(&db).await;
+		let inserted = db
+			.insert_into_collection("test", embedding("a", vec![1.0]), false)
+			.await;
+		assert!(matches!(inserted, Err(Error::DimensionMismatch)));
+		assert_eq!(
+			db.read_collection("test").await.unwrap().embedding_count(),
+			0
+		);
+	}
+
+	#[tokio::test]
+	async fn quarantines_corrupted_collections() {
+		let _store = testing::store().await;
+		let db = Db::new();
+		create(&db).await;
+		db.rename_collection("test", "damaged".to_string())
+			.await
+			.unwrap();
+		create(&db).await;
+		db.save_to_store().await.unwrap();
+
+		let path = STORE_PATH.join("damaged");
+		let mut binary = fs::read(&path).unwrap();
+		// a byte of the content following the header
+		binary[24] ^= 1;
+		fs::write(&path, binary).unwrap();
+
+		let loaded = from_store(Durability::Off, None).await.unwrap();
+		assert!(loaded.read_collection("damaged").await.is_none());
+		assert_eq!(loaded.list(), ["test"]);
+		assert!(!path.exists());
+		assert!(STORE_PATH.join("._quarantine").join("damaged").exists());
+	}
+
+	#[tokio::test]
+	async fn restores_collections_from_snapshot() {
+		let _store = testing::store().await;
+		let db = Db::new();
+		create(&db).await;
+		let inserted = (0..4_u8)
+			.map(|index| embedding(&index.to_string(), vec![f32::from(index), 1.0]))
+			.collect();
+		db.insert_many_into_collection("test", inserted, false)
+			.await
+			.unwrap();
+		let snapshot = db.create_snapshot(None).await.unwrap();
+		assert_eq!(snapshot.collections, ["test"]);
+		let taken = sorted_embeddings(&db, "test").await;
+
+		// saving the changes must not modify the files linked by the snapshot
+		db.update_embedding("test", "1", Some(vec![5.0, 5.0]), None, false)
+			.await
+			.unwrap();
+		db.delete_embedding("test", "2").await.unwrap();
+		db.insert_into_collection("test", embedding("4", vec![4.0, 1.0]), false)
+			.await
+			.unwrap();
+		db.rename_collection("test", "kept".to_string())
+			.await
+			.unwrap();
+		create(&db).await;
+		db.save_to_store().await.unwrap();
+		let kept = sorted_embeddings(&db, "kept").await;
+
+		let collections = load_snapshot(&snapshot).unwrap();
+		db.restore_snapshot(collections).await.unwrap();
+		assert_eq!(sorted_embeddings(&db, "test").await, taken);
+		assert_eq!(sorted_embeddings(&db, "kept").await, kept);
+		drop(db);
+
+		let loaded = from_store(Durability::Off, None).await.unwrap();
+		let mut names = loaded.list();
+		names.sort();
+		assert_eq!(names, ["kept", "test"]);
+		assert_eq!(sorted_embeddings(&loaded, "test").await, taken);
+		assert_eq!(sorted_embeddings(&loaded, "kept").await, kept);
+	}
+
+	#[tokio::test]
+	async fn unloads_idle_collections_and_loads_them_again() {
+		let _store = testing::store().await;
+		let mut db = Db::new();
+		// only the last used collection fits in the budget
+		db.memory_budget = Some(1);
+		create(&db).await;
+		db.create_collection(
+			"other".to_string(),
+			2,
+			Distance::Euclidean,
+			IndexOptions::Flat,
+			None,
+			Vec::new(),
+		)
+		.await
+		.unwrap();
+		let inserted = (0..4_u8)
+			.map(|index| embedding(&index.to_string(), vec![f32::from(index), 1.0]))
+			.collect();
+		db.insert_many_into_collection("test", inserted, false)
+			.await
+			.unwrap();
+		db.insert_into_collection("other", embedding("a", vec![1.0, 1.0]), false)
+			.await
+			.unwrap();
+		let expected = sorted_embeddings(&db, "test").await;
+		db.read_collection("other").await.unwrap();
+
+		// collections are unloaded only after they have been saved
+		let (_, loaded) = db.summary("test").await.unwrap();
+		assert!(loaded);
+		db.save_to_store().await.unwrap();
+		let (summary, loaded) = db.summary("test").await.unwrap();
+		assert!(!loaded);
+		assert_eq!(summary.embedding_count, 4);
+		let (_, loaded) = db.summary("other").await.unwrap();
+		assert!(loaded);
+		let mut names = db.list();
+		names.sort();
+		assert_eq!(names, ["other", "test"]);
+
+		assert_eq!(sorted_embeddings(&db, "test").await, expected);
+		let (_, loaded) = db.summary("test").await.unwrap();
+		assert!(loaded);
+		let (summary, loaded) = db.summary("other").await.unwrap();
+		assert!(!loaded);
+		assert_eq!(summary.embedding_count, 1);
+	}
+}
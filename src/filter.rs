@@ -0,0 +1,438 @@
+//! Filter expressions matching the metadata of embeddings, written in JSON like:
+//!
+//! ```json
+//! { "title": "Barbarian", "page": { "$gte": 1, "$lt": 5 },
+//!   "$or": [ { "name": { "$prefix": "classes/" } }, { "tags": { "$exists": false } } ] }
+//! ```
+//!
+//! An array of objects is accepted too, matching if any of the objects matches, which
+//! keeps the original filters with exact string values working. Fields of nested objects
+//! are addressed by paths with dots, like `author.name`, and values in arrays are matched
+//! one by one.
+
+use schemars::{
+	gen::SchemaGenerator,
+	schema::{InstanceType, Schema, SchemaObject},
+	JsonSchema,
+};
+use serde::{de, Deserialize, Deserializer};
+use serde_json::{Map, Value as Json};
+use std::cmp::Ordering;
+
+use crate::metadata::Metadata;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+	/// All filters have to match, no filter matches any embedding
+	And(Vec<Self>),
+	/// At least one filter has to match
+	Or(Vec<Self>),
+	/// The filter must not match
+	Not(Box<Self>),
+	/// The value of a metadata field has to match the operator
+	Field(String, Operator),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operator {
+	Eq(Value),
+	Ne(Value),
+	In(Vec<Value>),
+	Nin(Vec<Value>),
+	Gt(Value),
+	Gte(Value),
+	Lt(Value),
+	Lte(Value),
+	Exists(bool),
+	Prefix(String),
+	Contains(String),
+}
+
+/// Value to compare the metadata with
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+	String(String),
+	Number(f64),
+	Bool(bool),
+}
+
+impl Default for Filter {
+	fn default() -> Self {
+		Self::And(Vec::new())
+	}
+}
+
+impl Filter {
+	/// If the filter matches any embedding
+	pub const fn is_empty(&self) -> bool {
+		matches!(self, Self::And(filters) if filters.is_empty())
+	}
+
+	/// If the filter matches any embedding, like an empty object, an empty array
+	/// or an array containing an empty object
+	pub fn matches_all(&self) -> bool {
+		match self {
+			Self::And(filters) => filters.iter().all(Self::matches_all),
+			Self::Or(filters) => filters.iter().any(Self::matches_all),
+			Self::Not(_) | Self::Field(..) => false,
+		}
+	}
+
+	/// Embeddings without metadata are matched like if they had no fields
+	pub fn matches(&self, metadata: Option<&Metadata>) -> bool {
+		match self {
+			Self::And(filters) => filters.iter().all(|filter| filter.matches(metadata)),
+			Self::Or(filters) => filters.iter().any(|filter| filter.matches(metadata)),
+			Self::Not(filter) => !filter.matches(metadata),
+			Self::Field(key, operator) => {
+				operator.matches(metadata.and_then(|metadata| field(metadata, key)))
+			},
+		}
+	}
+
+	fn parse(json: Json) -> Result<Self, String> {
+		match json {
+			// the original filter, where an empty array matches any embedding
+			Json::Array(filters) if filters.is_empty() => Ok(Self::default()),
+			Json::Array(filters) => Ok(Self::Or(parse_list(filters)?)),
+			Json::Object(fields) => Self::parse_object(fields),
+			_ => Err("filter has to be an object or an array".into()),
+		}
+	}
+
+	fn parse_object(fields: Map<String, Json>) -> Result<Self, String> {
+		let mut filters = Vec::with_capacity(fields.len());
+		for (key, value) in fields {
+			match key.as_str() {
+				"$and" => filters.push(Self::And(parse_array(&key, value)?)),
+				"$or" => filters.push(Self::Or(parse_array(&key, value)?)),
+				"$not" => {
+					let negated = Self::parse_object(expect_object(&key, value)?)?;
+					filters.push(Self::Not(Box::new(negated)));
+				},
+				_ if key.starts_with('$') => return Err(format!("unknown operator {key}")),
+				_ => Self::parse_field(key, value, &mut filters)?,
+			}
+		}
+		Ok(Self::flatten(filters))
+	}
+
+	/// Adds a filter for each operator, a plain value means equality
+	fn parse_field(key: String, value: Json, filters: &mut Vec<Self>) -> Result<(), String> {
+		let operators = match value {
+			Json::Object(operators) => operators,
+			value => {
+				filters.push(Self::Field(key, Operator::Eq(Value::parse(value)?)));
+				return Ok(());
+			},
+		};
+		if operators.is_empty() {
+			return Err(format!("no operator for {key}"));
+		}
+		for (name, value) in operators {
+			let filter = if name == "$not" {
+				let mut negated = Vec::new();
+				Self::parse_field(key.clone(), value, &mut negated)?;
+				Self::Not(Box::new(Self::flatten(negated)))
+			} else {
+				Self::Field(key.clone(), Operator::parse(&name, value)?)
+			};
+			filters.push(filter);
+		}
+		Ok(())
+	}
+
+	fn flatten(mut filters: Vec<Self>) -> Self {
+		if filters.len() == 1 {
+			filters.remove(0)
+		} else {
+			Self::And(filters)
+		}
+	}
+}
+
+impl Operator {
+	fn matches(&self, actual: Option<&Json>) -> bool {
+		match actual {
+			// missing fields are different from any value
+			None => matches!(self, Self::Ne(_) | Self::Nin(_) | Self::Exists(false)),
+			// an array matches if any of its items matches, or if none of them
+			// is equal to the excluded values
+			Some(Json::Array(items)) if !matches!(self, Self::Exists(_)) => match self {
+				Self::Ne(_) | Self::Nin(_) => items.iter().all(|item| self.matches_value(item)),
+				_ => items.iter().any(|item| self.matches_value(item)),
+			},
+			Some(actual) => self.matches_value(actual),
+		}
+	}
+
+	fn matches_value(&self, actual: &Json) -> bool {
+		match self {
+			Self::Eq(expected) => expected.compare(actual) == Some(Ordering::Equal),
+			Self::Ne(expected) => expected.compare(actual) != Some(Ordering::Equal),
+			Self::In(expected) => expected
+				.iter()
+				.any(|expected| expected.compare(actual) == Some(Ordering::Equal)),
+			Self::Nin(expected) => expected
+				.iter()
+				.all(|expected| expected.compare(actual) != Some(Ordering::Equal)),
+			// the comparison is reversed, the expected value is compared with the actual one
+			Self::Gt(expected) => expected.compare(actual) == Some(Ordering::Less),
+			Self::Gte(expected) => {
+				matches!(
+					expected.compare(actual),
+					Some(Ordering::Less | Ordering::Equal)
+				)
+			},
+			Self::Lt(expected) => expected.compare(actual) == Some(Ordering::Greater),
+			Self::Lte(expected) => matches!(
+				expected.compare(actual),
+				Some(Ordering::Greater | Ordering::Equal)
+			),
+			Self::Exists(expected) => *expected,
+			Self::Prefix(expected) => actual
+				.as_str()
+				.is_some_and(|actual| actual.starts_with(expected.as_str())),
+			Self::Contains(expected) => actual
+				.as_str()
+				.is_some_and(|actual| actual.contains(expected.as_str())),
+		}
+	}
+
+	fn parse(name: &str, value: Json) -> Result<Self, String> {
+		Ok(match name {
+			"$eq" => Self::Eq(Value::parse(value)?),
+			"$ne" => Self::Ne(Value::parse(value)?),
+			"$in" => Self::In(Value::parse_list(name, value)?),
+			"$nin" => Self::Nin(Value::parse_list(name, value)?),
+			"$gt" => Self::Gt(Value::parse(value)?),
+			"$gte" => Self::Gte(Value::parse(value)?),
+			"$lt" => Self::Lt(Value::parse(value)?),
+			"$lte" => Self::Lte(Value::parse(value)?),
+			"$exists" => match value {
+				Json::Bool(exists) => Self::Exists(exists),
+				_ => return Err("$exists expects a boolean".into()),
+			},
+			"$prefix" => Self::Prefix(expect_string(name, value)?),
+			"$contains" => Self::Contains(expect_string(name, value)?),
+			_ => return Err(format!("unknown operator {name}")),
+		})
+	}
+}
+
+impl Value {
+	/// Values of the same type are comparable. Numbers and booleans are compared with
+	/// strings parsed to the same type too, which were the only metadata values before.
+	fn compare(&self, actual: &Json) -> Option<Ordering> {
+		match (self, actual) {
+			(Self::String(expected), Json::String(actual)) => Some(expected.as_str().cmp(actual)),
+			(Self::Number(expected), Json::Number(actual)) => {
+				expected.partial_cmp(&actual.as_f64()?)
+			},
+			(Self::Number(expected), Json::String(actual)) => {
+				expected.partial_cmp(&actual.parse::<f64>().ok()?)
+			},
+			(Self::Bool(expected), Json::Bool(actual)) => Some(expected.cmp(actual)),
+			(Self::Bool(expected), Json::String(actual)) => {
+				Some(expected.cmp(&actual.parse::<bool>().ok()?))
+			},
+			_ => None,
+		}
+	}
+
+	fn parse(json: Json) -> Result<Self, String> {
+		match json {
+			Json::String(value) => Ok(Self::String(value)),
+			Json::Number(value) => value
+				.as_f64()
+				.map(Self::Number)
+				.ok_or_else(|| format!("{value} is not a valid number")),
+			Json::Bool(value) => Ok(Self::Bool(value)),
+			_ => Err("values have to be strings, numbers or booleans".into()),
+		}
+	}
+
+	fn parse_list(name: &str, json: Json) -> Result<Vec<Self>, String> {
+		match json {
+			Json::Array(values) => values.into_iter().map(Self::parse).collect(),
+			_ => Err(format!("{name} expects an array")),
+		}
+	}
+}
+
+/// Looks up a field by its name, or by a path with dots to a field in nested objects
+pub fn field<'a>(metadata: &'a Metadata, key: &str) -> Option<&'a Json> {
+	metadata.get(key).or_else(|| {
+		let mut path = key.split('.');
+		let first = metadata.get(path.next()?)?;
+		path.try_fold(first, |value, name| value.as_object()?.get(name))
+	})
+}
+
+fn parse_list(filters: Vec<Json>) -> Result<Vec<Filter>, String> {
+	filters
+		.into_iter()
+		.map(|filter| Filter::parse_object(expect_object("filter", filter)?))
+		.collect()
+}
+
+fn parse_array(name: &str, json: Json) -> Result<Vec<Filter>, String> {
+	match json {
+		Json::Array(filters) if !filters.is_empty() => parse_list(filters),
+		_ => Err(format!("{name} expects a non-empty array")),
+	}
+}
+
+fn expect_object(name: &str, json: Json) -> Result<Map<String, Json>, String> {
+	match json {
+		Json::Object(fields) => Ok(fields),
+		_ => Err(format!("{name} expects an object")),
+	}
+}
+
+fn expect_string(name: &str, json: Json) -> Result<String, String> {
+	match json {
+		Json::String(value) => Ok(value),
+		_ => Err(format!("{name} expects a string")),
+	}
+}
+
+impl<'de> Deserialize<'de> for Filter {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		Self::parse(Json::deserialize(deserializer)?).map_err(de::Error::custom)
+	}
+}
+
+impl JsonSchema for Filter {
+	fn schema_name() -> String {
+		"Filter".into()
+	}
+
+	fn json_schema(_: &mut SchemaGenerator) -> Schema {
+		let mut schema = SchemaObject {
+			instance_type: Some(vec![InstanceType::Object, InstanceType::Array].into()),
+			..Default::default()
+		};
+		schema.metadata().description = Some(
+			"Object with metadata fields and operators $and, $or and $not, or an array \
+			 of such objects, one of which has to match"
+				.into(),
+		);
+		schema.into()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	fn filter(json: Json) -> Filter {
+		serde_json::from_value(json).unwrap()
+	}
+
+	fn metadata(json: Json) -> Metadata {
+		serde_json::from_value(json).unwrap()
+	}
+
+	#[test]
+	fn parses_operators() {
+		assert_eq!(
+			filter(json!({ "page": { "$gte": 1, "$lt": 5 } })),
+			Filter::And(vec![
+				Filter::Field("page".into(), Operator::Gte(Value::Number(1.0))),
+				Filter::Field("page".into(), Operator::Lt(Value::Number(5.0))),
+			])
+		);
+		assert_eq!(
+			filter(json!([{ "name": "a" }, { "name": "b" }])),
+			Filter::Or(vec![
+				Filter::Field("name".into(), Operator::Eq(Value::String("a".into()))),
+				Filter::Field("name".into(), Operator::Eq(Value::String("b".into()))),
+			])
+		);
+		assert_eq!(
+			filter(json!({ "tags": { "$not": { "$in": ["x"] } } })),
+			Filter::Not(Box::new(Filter::Field(
+				"tags".into(),
+				Operator::In(vec![Value::String("x".into())])
+			)))
+		);
+	}
+
+	#[test]
+	fn rejects_invalid_filters() {
+		for json in [
+			json!("name"),
+			json!({ "$nor": [] }),
+			json!({ "$or": [] }),
+			json!({ "page": {} }),
+			json!({ "page": { "$gt": [1] } }),
+			json!({ "page": { "$exists": 1 } }),
+			json!({ "name": { "$prefix": 1 } }),
+		] {
+			assert!(
+				serde_json::from_value::<Filter>(json.clone()).is_err(),
+				"{json}"
+			);
+		}
+	}
+
+	#[test]
+	fn matches_metadata() {
+		let metadata = metadata(json!({
+			"name": "classes/bard",
+			"page": 3,
+			"old": "7",
+			"tags": ["magic", "music"],
+			"author": { "name": "Gary" },
+		}));
+		for (json, expected) in [
+			(json!({ "name": "classes/bard", "page": 3 }), true),
+			(json!({ "name": "classes/bard", "page": 4 }), false),
+			(json!({ "page": { "$gt": 2, "$lte": 3 } }), true),
+			(json!({ "old": { "$gt": 6 } }), true),
+			(json!({ "tags": "music" }), true),
+			(json!({ "tags": { "$nin": ["magic"] } }), false),
+			(json!({ "author.name": { "$prefix": "Ga" } }), true),
+			(json!({ "missing": { "$exists": false } }), true),
+			(json!({ "missing": { "$ne": 1 } }), true),
+			(
+				json!({ "$or": [{ "page": 1 }, { "name": { "$contains": "bard" } }] }),
+				true,
+			),
+			(json!({ "$not": { "page": 3 } }), false),
+		] {
+			assert_eq!(
+				filter(json.clone()).matches(Some(&metadata)),
+				expected,
+				"{json}"
+			);
+		}
+		assert!(!filter(json!({ "page": 3 })).matches(None));
+		assert!(filter(json!({ "page": { "$exists": false } })).matches(None));
+	}
+
+	#[test]
+	fn matches_all_by_empty_filters() {
+		for json in [
+			json!({}),
+			json!([]),
+			json!([{}]),
+			json!([{ "page": 1 }, {}]),
+		] {
+			let filter = filter(json.clone());
+			assert!(filter.matches_all(), "{json}");
+			assert!(filter.matches(None), "{json}");
+		}
+		for json in [
+			json!({ "page": 1 }),
+			json!([{ "page": 1 }]),
+			json!({ "$not": { "page": 1 } }),
+		] {
+			assert!(!filter(json.clone()).matches_all(), "{json}");
+		}
+		assert!(filter(json!({})).is_empty());
+		assert!(!filter(json!([{}])).is_empty());
+	}
+}
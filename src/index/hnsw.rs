@@ -0,0 +1,451 @@
+use serde::{Deserialize, Serialize};
+use std::{
+	cmp::Reverse,
+	collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use super::{IndexOptions, Vectors};
+use crate::{
+	random,
+	similarity::{get_cache_attr, get_distance_fn, Distance, ScoreIndex},
+};
+
+/// Default size of the candidate list when searching
+const DEFAULT_EF: usize = 64;
+
+/// Hierarchical navigable small world graph over the vectors of a collection.
+/// Nodes are identified by the positions of the embeddings in the collection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hnsw {
+	/// Maximum number of neighbours of a node in the upper layers
+	m: usize,
+	/// Number of candidates to consider when connecting a new node
+	ef_construction: usize,
+	/// Distance metric used for scoring the neighbours
+	distance: Distance,
+	/// Node on the highest layer where every search starts
+	entry_point: Option<usize>,
+	/// Neighbours of every node, one list for each layer that the node is present in
+	nodes: Vec<Vec<Vec<usize>>>,
+	/// State of the generator of random node levels
+	seed: u64,
+}
+
+impl Hnsw {
+	pub const fn new(m: usize, ef_construction: usize, distance: Distance) -> Self {
+		Self {
+			m,
+			ef_construction,
+			distance,
+			entry_point: None,
+			nodes: Vec::new(),
+			seed: random::SEED,
+		}
+	}
+
+	pub const fn options(&self) -> IndexOptions {
+		IndexOptions::Hnsw {
+			m: self.m,
+			ef_construction: self.ef_construction,
+		}
+	}
+
+	pub fn clear(&mut self) {
+		self.entry_point = None;
+		self.nodes.clear();
+	}
+
+	pub fn rebuild(&mut self, vectors: &impl Vectors) {
+		self.clear();
+		self.seed = random::SEED;
+		for index in 0..vectors.len() {
+			self.insert(index, vectors);
+		}
+	}
+
+	/// Connects the vector at `index`, which has to be the next position after the last node
+	pub fn insert(&mut self, index: usize, vectors: &impl Vectors) {
+		debug_assert_eq!(index, self.nodes.len());
+		let level = self.random_level();
+		self.nodes.push(vec![Vec::new(); level + 1]);
+		self.connect_node(index, level, vectors);
+	}
+
+	/// Disconnects the node at `index` and connects it again on the same layers,
+	/// after its vector has been replaced
+	pub fn update(&mut self, index: usize, vectors: &impl Vectors) {
+		let layers = self.disconnect_node(index, vectors);
+		self.nodes[index] = vec![Vec::new(); layers];
+		self.connect_node(index, layers - 1, vectors);
+	}
+
+	/// Links the node at `index`, which has no links yet, to its closest nodes
+	/// on each layer up to `level`
+	fn connect_node(&mut self, index: usize, level: usize, vectors: &impl Vectors) {
+		let Some(entry_point) = self.entry_point else {
+			self.entry_point = Some(index);
+			return;
+		};
+
+		let vector = vectors.vector(index);
+		let score = self.scorer(vectors, vector);
+		let top_level = self.level(entry_point);
+		let mut entry_points = vec![ScoreIndex {
+			score: score(entry_point),
+			index: entry_point,
+		}];
+		for layer in (level + 1..=top_level).rev() {
+			entry_points = self.search_layer(&score, entry_points, 1, layer, |_| true);
+		}
+
+		for layer in (0..=level.min(top_level)).rev() {
+			let candidates =
+				self.search_layer(&score, entry_points, self.ef_construction, layer, |_| true);
+			let neighbours = self.select_neighbours(vectors, &candidates, self.max_links(layer));
+			for &neighbour in &neighbours {
+				self.connect(vectors, neighbour, index, layer);
+			}
+			self.nodes[index][layer] = neighbours;
+			entry_points = candidates;
+		}
+
+		if level > top_level {
+			self.entry_point = Some(index);
+		}
+	}
+
+	/// Drops the nodes of the deleted vectors, bridging the gaps by their neighbours, and
+	/// moves the other nodes to their new slots. Deleted nodes stay in the graph until then,
+	/// they are still visited when searching, but never returned.
+	pub fn compact(&mut self, remap: &[Option<usize>], vectors: &impl Vectors) {
+		let removed = (0..self.nodes.len())
+			.filter(|&node| remap[node].is_none())
+			.map(|node| (node, std::mem::take(&mut self.nodes[node])))
+			.collect::<HashMap<_, _>>();
+
+		for node in 0..self.nodes.len() {
+			for layer in 0..self.nodes[node].len() {
+				let links = &self.nodes[node][layer];
+				if links.iter().all(|&link| remap[link].is_some()) {
+					continue;
+				}
+
+				// offer the neighbours of the removed nodes instead of them
+				let mut candidates = HashSet::new();
+				for &link in links {
+					match removed.get(&link) {
+						Some(bridges) => {
+							candidates.extend(bridges.get(layer).into_iter().flatten());
+						},
+						None => {
+							candidates.insert(link);
+						},
+					}
+				}
+				let score = self.scorer(vectors, vectors.vector(node));
+				let mut candidates = candidates
+					.into_iter()
+					.filter(|&link| link != node && remap[link].is_some())
+					.map(|link| ScoreIndex {
+						score: score(link),
+						index: link,
+					})
+					.collect::<Vec<_>>();
+				candidates.sort_unstable();
+				self.nodes[node][layer] =
+					self.select_neighbours(vectors, &candidates, self.max_links(layer));
+			}
+		}
+
+		self.nodes = std::mem::take(&mut self.nodes)
+			.into_iter()
+			.enumerate()
+			.filter(|(node, _)| remap[*node].is_some())
+			.map(|(_, layers)| layers)
+			.collect();
+		for link in self.nodes.iter_mut().flatten().flatten() {
+			*link = remap[*link].unwrap_or_default();
+		}
+		self.entry_point = self
+			.entry_point
+			.and_then(|entry_point| remap[entry_point])
+			.or_else(|| (0..self.nodes.len()).max_by_key(|&node| self.nodes[node].len()));
+	}
+
+	/// Removes the links of the neighbours to the node at `index` and its own links,
+	/// bridging the gaps by its neighbours, and returns the number of layers, which
+	/// the node was present in. Only the neighbours are visited, the nodes linking
+	/// to the node without being linked back keep the links to the updated node.
+	fn disconnect_node(&mut self, index: usize, vectors: &impl Vectors) -> usize {
+		let removed = std::mem::take(&mut self.nodes[index]);
+
+		for (layer, bridges) in removed.iter().enumerate() {
+			for &node in bridges {
+				let links = &mut self.nodes[node][layer];
+				let Some(position) = links.iter().position(|&link| link == index) else {
+					continue;
+				};
+				links.swap_remove(position);
+
+				// bridge the gap by offering the neighbours of the removed node
+				let score = self.scorer(vectors, vectors.vector(node));
+				let mut candidates = self.nodes[node][layer]
+					.iter()
+					.chain(bridges)
+					.copied()
+					.filter(|&link| link != node && link != index)
+					.collect::<HashSet<_>>()
+					.into_iter()
+					.map(|link| ScoreIndex {
+						score: score(link),
+						index: link,
+					})
+					.collect::<Vec<_>>();
+				candidates.sort_unstable();
+				self.nodes[node][layer] =
+					self.select_neighbours(vectors, &candidates, self.max_links(layer));
+			}
+		}
+
+		// the neighbour on the highest layer leads to the node, until the node becomes
+		// the entry point again by connecting it
+		if self.entry_point == Some(index) {
+			self.entry_point = removed.iter().rev().flatten().next().copied();
+		}
+
+		removed.len()
+	}
+
+	/// Returns at most `k` nodes accepted by the filter, sorted from the most similar one
+	pub fn search(
+		&self,
+		vectors: &impl Vectors,
+		query: &[f32],
+		k: usize,
+		ef: Option<usize>,
+		accept: impl Fn(usize) -> bool,
+	) -> Vec<ScoreIndex> {
+		let Some(entry_point) = self.entry_point else {
+			return Vec::new();
+		};
+
+		let score = self.scorer(vectors, query);
+		let mut entry_points = vec![ScoreIndex {
+			score: score(entry_point),
+			index: entry_point,
+		}];
+		for layer in (1..=self.level(entry_point)).rev() {
+			entry_points = self.search_layer(&score, entry_points, 1, layer, |_| true);
+		}
+
+		let ef = ef.unwrap_or(DEFAULT_EF).max(k);
+		let mut results = self.search_layer(&score, entry_points, ef, 0, accept);
+		results.truncate(k);
+		results
+	}
+
+	fn scorer<'a>(
+		&self,
+		vectors: &'a impl Vectors,
+		query: &'a [f32],
+	) -> impl Fn(usize) -> f32 + 'a {
+		let memo_attr = get_cache_attr(self.distance, query);
+		let distance_fn = get_distance_fn(self.distance);
+		move |index| distance_fn(vectors.vector(index), query, memo_attr)
+	}
+
+	/// Returns the `ef` nodes most similar to the query, sorted from the most similar one
+	fn search_layer(
+		&self,
+		score: &impl Fn(usize) -> f32,
+		entry_points: Vec<ScoreIndex>,
+		ef: usize,
+		layer: usize,
+		accept: impl Fn(usize) -> bool,
+	) -> Vec<ScoreIndex> {
+		let mut visited = entry_points
+			.iter()
+			.map(|entry| entry.index)
+			.collect::<HashSet<_>>();
+		// the best candidate is on the top of the heap
+		let mut candidates = BinaryHeap::new();
+		// the worst result is on the top of the heap
+		let mut results = BinaryHeap::new();
+		for entry in entry_points {
+			candidates.push(Reverse(entry));
+			if accept(entry.index) {
+				results.push(entry);
+			}
+		}
+		while results.len() > ef {
+			results.pop();
+		}
+
+		while let Some(Reverse(current)) = candidates.pop() {
+			if results.len() >= ef && results.peek().is_some_and(|worst| current > *worst) {
+				break;
+			}
+
+			for &neighbour in &self.nodes[current.index][layer] {
+				if !visited.insert(neighbour) {
+					continue;
+				}
+
+				let candidate = ScoreIndex {
+					score: score(neighbour),
+					index: neighbour,
+				};
+				if results.len() < ef || results.peek().is_some_and(|worst| candidate < *worst) {
+					candidates.push(Reverse(candidate));
+					if accept(neighbour) {
+						results.push(candidate);
+						if results.len() > ef {
+							results.pop();
+						}
+					}
+				}
+			}
+		}
+
+		results.into_sorted_vec()
+	}
+
+	/// Picks neighbours that are more similar to the node than to each other, so that
+	/// the graph stays navigable across clusters, and tops them up with the rest
+	fn select_neighbours(
+		&self,
+		vectors: &impl Vectors,
+		candidates: &[ScoreIndex],
+		max_links: usize,
+	) -> Vec<usize> {
+		let mut selected: Vec<usize> = Vec::with_capacity(max_links);
+		let mut skipped = Vec::new();
+
+		for candidate in candidates {
+			if selected.len() >= max_links {
+				break;
+			}
+			let score = self.scorer(vectors, vectors.vector(candidate.index));
+			if selected.iter().all(|&other| score(other) < candidate.score) {
+				selected.push(candidate.index);
+			} else {
+				skipped.push(candidate.index);
+			}
+		}
+
+		let missing = max_links - selected.len();
+		selected.extend(skipped.into_iter().take(missing));
+		selected
+	}
+
+	fn connect(&mut self, vectors: &impl Vectors, node: usize, link: usize, layer: usize) {
+		let max_links = self.max_links(layer);
+		self.nodes[node][layer].push(link);
+		if self.nodes[node][layer].len() <= max_links {
+			return;
+		}
+
+		let score = self.scorer(vectors, vectors.vector(node));
+		let mut candidates = self.nodes[node][layer]
+			.iter()
+			.map(|&index| ScoreIndex {
+				score: score(index),
+				index,
+			})
+			.collect::<Vec<_>>();
+		candidates.sort_unstable();
+		self.nodes[node][layer] = self.select_neighbours(vectors, &candidates, max_links);
+	}
+
+	const fn max_links(&self, layer: usize) -> usize {
+		if layer == 0 {
+			self.m * 2
+		} else {
+			self.m
+		}
+	}
+
+	fn level(&self, node: usize) -> usize {
+		self.nodes[node].len() - 1
+	}
+
+	#[allow(
+		clippy::cast_possible_truncation,
+		clippy::cast_precision_loss,
+		clippy::cast_sign_loss
+	)]
+	fn random_level(&mut self) -> usize {
+		let level_mult = 1.0 / (self.m as f64).ln();
+		(-random::uniform(&mut self.seed).ln() * level_mult).floor() as usize
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing;
+
+	fn recall(hnsw: &Hnsw, vectors: &impl Vectors, distance: Distance) -> f32 {
+		let queries = testing::vectors(20, 16, 2);
+		let recall: f32 = (0..queries.len())
+			.map(|query| {
+				let query = queries.vector(query);
+				let found = hnsw.search(vectors, query, 10, None, |_| true);
+				testing::recall(&found, &testing::nearest(vectors, query, 10, distance))
+			})
+			.sum();
+		recall / 20.0
+	}
+
+	#[test]
+	fn finds_nearest_neighbours() {
+		let vectors = testing::vectors(500, 16, 1);
+		for distance in [Distance::Cosine, Distance::DotProduct] {
+			let mut hnsw = Hnsw::new(16, 64, distance);
+			for index in 0..vectors.len() {
+				hnsw.insert(index, &vectors);
+			}
+			assert!(recall(&hnsw, &vectors, distance) >= 0.95);
+		}
+	}
+
+	#[test]
+	fn keeps_finding_after_compaction() {
+		let mut vectors = testing::vectors(500, 16, 1);
+		let mut hnsw = Hnsw::new(16, 64, Distance::Cosine);
+		for index in 0..vectors.len() {
+			hnsw.insert(index, &vectors);
+		}
+
+		let mut slots = 0..;
+		let remap: Vec<Option<usize>> = (0..vectors.len())
+			.map(|index| (index % 3 != 0).then(|| slots.next().unwrap()))
+			.collect();
+		hnsw.compact(&remap, &vectors);
+		vectors.compact(&remap);
+		assert_eq!(hnsw.nodes.len(), vectors.len());
+		assert!(recall(&hnsw, &vectors, Distance::Cosine) >= 0.95);
+	}
+
+	#[test]
+	fn keeps_finding_after_updates() {
+		let mut vectors = testing::vectors(500, 16, 1);
+		let mut hnsw = Hnsw::new(16, 64, Distance::Cosine);
+		for index in 0..vectors.len() {
+			hnsw.insert(index, &vectors);
+		}
+
+		let replacements = testing::vectors(100, 16, 3);
+		for index in 0..replacements.len() {
+			let slot = index * 5;
+			vectors.set(slot, replacements.vector(index).to_vec());
+			hnsw.update(slot, &vectors);
+		}
+		let entry_point = hnsw.entry_point.unwrap();
+		assert_eq!(
+			hnsw.level(entry_point),
+			hnsw.nodes.iter().map(Vec::len).max().unwrap() - 1
+		);
+		assert!(recall(&hnsw, &vectors, Distance::Cosine) >= 0.95);
+	}
+}
@@ -0,0 +1,218 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{IndexOptions, Vectors};
+use crate::{
+	kmeans, random,
+	similarity::{get_cache_attr, get_distance_fn, top_k, Distance, ScoreIndex},
+};
+
+/// Default number of posting lists to scan when searching
+const DEFAULT_NPROBE: usize = 4;
+
+/// Maximum number of vectors per centroid sampled for the training
+const MAX_TRAINING_POINTS: usize = 256;
+
+/// Inverted file of posting lists, each one gathering the vectors closest to its centroid.
+/// Vectors are identified by the positions of the embeddings in the collection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ivf {
+	/// Number of centroids to train
+	nlist: usize,
+	/// Distance metric used for assigning the vectors to the centroids
+	distance: Distance,
+	/// Trained centroids, empty until the index is trained
+	centroids: Vec<Vec<f32>>,
+	/// Positions of the vectors assigned to each centroid
+	lists: Vec<Vec<usize>>,
+}
+
+impl Ivf {
+	pub const fn new(nlist: usize, distance: Distance) -> Self {
+		Self {
+			nlist,
+			distance,
+			centroids: Vec::new(),
+			lists: Vec::new(),
+		}
+	}
+
+	pub const fn options(&self) -> IndexOptions {
+		IndexOptions::Ivf { nlist: self.nlist }
+	}
+
+	pub const fn is_trained(&self) -> bool {
+		!self.centroids.is_empty()
+	}
+
+	pub fn clear(&mut self) {
+		for list in &mut self.lists {
+			list.clear();
+		}
+	}
+
+	/// Trains the centroids by k-means over a sample of the vectors and assigns all vectors
+	/// to the posting lists of the centroids
+	pub fn train(&mut self, vectors: &impl Vectors) {
+		let len = vectors.len();
+		let mut seed = random::SEED;
+		let sample = random::sample(&mut seed, len, self.nlist * MAX_TRAINING_POINTS)
+			.into_iter()
+			.map(|index| vectors.vector(index))
+			.collect::<Vec<_>>();
+		tracing::debug!(
+			"Training {} centroids from {} vectors",
+			self.nlist,
+			sample.len()
+		);
+
+		let distance = self.distance;
+		let similarity = move |centroid: &[f32], vector: &[f32]| {
+			get_distance_fn(distance)(centroid, vector, get_cache_attr(distance, vector))
+		};
+		self.centroids = kmeans::train(
+			&sample,
+			self.nlist,
+			&similarity,
+			distance == Distance::Cosine,
+		);
+
+		self.lists = vec![Vec::new(); self.centroids.len()];
+		let assignments = (0..len)
+			.into_par_iter()
+			.map(|index| self.nearest(vectors.vector(index)))
+			.collect::<Vec<_>>();
+		for (index, list) in assignments.into_iter().enumerate() {
+			self.lists[list].push(index);
+		}
+	}
+
+	pub fn insert(&mut self, index: usize, vectors: &impl Vectors) {
+		if self.is_trained() {
+			let list = self.nearest(vectors.vector(index));
+			self.lists[list].push(index);
+		}
+	}
+
+	/// Moves the vector, which replaced the one at `index`, to the posting list
+	/// of its closest centroid
+	pub fn update(&mut self, index: usize, vectors: &impl Vectors) {
+		if self.is_trained() {
+			for list in &mut self.lists {
+				list.retain(|&other| other != index);
+			}
+			self.insert(index, vectors);
+		}
+	}
+
+	/// Drops the deleted vectors and moves the others to their new slots, deleted vectors
+	/// stay in the posting lists until then and are skipped when searching
+	pub fn compact(&mut self, remap: &[Option<usize>]) {
+		for list in &mut self.lists {
+			*list = list.iter().filter_map(|&index| remap[index]).collect();
+		}
+	}
+
+	/// Returns at most `k` vectors accepted by the filter from the `nprobe` posting lists
+	/// closest to the query, sorted from the most similar one, or `None` if not trained
+	pub fn search(
+		&self,
+		vectors: &impl Vectors,
+		query: &[f32],
+		k: usize,
+		nprobe: Option<usize>,
+		accept: impl Fn(usize) -> bool + Sync,
+	) -> Option<Vec<ScoreIndex>> {
+		if !self.is_trained() {
+			return None;
+		}
+
+		let memo_attr = get_cache_attr(self.distance, query);
+		let distance_fn = get_distance_fn(self.distance);
+		let probes = top_k(
+			self.centroids
+				.iter()
+				.enumerate()
+				.map(|(index, centroid)| ScoreIndex {
+					score: distance_fn(centroid, query, memo_attr),
+					index,
+				}),
+			nprobe.unwrap_or(DEFAULT_NPROBE).max(1),
+		);
+
+		let scores = probes
+			.par_iter()
+			.flat_map_iter(|probe| &self.lists[probe.index])
+			.filter(|&&index| accept(index))
+			.map(|&index| ScoreIndex {
+				score: distance_fn(vectors.vector(index), query, memo_attr),
+				index,
+			})
+			.collect::<Vec<_>>();
+		Some(top_k(scores, k))
+	}
+
+	fn nearest(&self, vector: &[f32]) -> usize {
+		let memo_attr = get_cache_attr(self.distance, vector);
+		let distance_fn = get_distance_fn(self.distance);
+		kmeans::nearest(
+			self.centroids.iter().map(Vec::as_slice),
+			vector,
+			&|centroid, vector| distance_fn(centroid, vector, memo_attr),
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing;
+
+	fn recall(ivf: &Ivf, vectors: &impl Vectors, nprobe: usize) -> f32 {
+		let queries = testing::vectors(20, 8, 2);
+		let recall: f32 = (0..queries.len())
+			.map(|query| {
+				let query = queries.vector(query);
+				let found = ivf.search(vectors, query, 10, Some(nprobe), |_| true);
+				let exact = testing::nearest(vectors, query, 10, Distance::Cosine);
+				testing::recall(&found.unwrap(), &exact)
+			})
+			.sum();
+		recall / 20.0
+	}
+
+	#[test]
+	fn finds_nearest_neighbours() {
+		let vectors = testing::vectors(2000, 8, 1);
+		let mut ivf = Ivf::new(16, Distance::Cosine);
+		assert!(ivf
+			.search(&vectors, vectors.vector(0), 10, None, |_| true)
+			.is_none());
+		ivf.train(&vectors);
+		assert!((recall(&ivf, &vectors, 16) - 1.0).abs() < f32::EPSILON);
+		assert!(recall(&ivf, &vectors, 8) >= 0.95);
+		assert!(recall(&ivf, &vectors, DEFAULT_NPROBE) >= 0.8);
+	}
+
+	#[test]
+	fn assigns_inserted_and_compacted_vectors() {
+		let mut vectors = testing::vectors(1000, 8, 1);
+		let mut ivf = Ivf::new(16, Distance::Cosine);
+		ivf.train(&vectors);
+		let inserted = testing::vectors(1000, 8, 3);
+		for index in 0..inserted.len() {
+			vectors.push(inserted.vector(index));
+			ivf.insert(vectors.len() - 1, &vectors);
+		}
+		assert!((recall(&ivf, &vectors, 16) - 1.0).abs() < f32::EPSILON);
+
+		let mut slots = 0..;
+		let remap: Vec<Option<usize>> = (0..vectors.len())
+			.map(|index| (index % 3 != 0).then(|| slots.next().unwrap()))
+			.collect();
+		ivf.compact(&remap);
+		vectors.compact(&remap);
+		assert_eq!(ivf.lists.iter().map(Vec::len).sum::<usize>(), vectors.len());
+		assert!((recall(&ivf, &vectors, 16) - 1.0).abs() < f32::EPSILON);
+	}
+}
@@ -0,0 +1,181 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::similarity::{Distance, ScoreIndex};
+
+mod hnsw;
+mod ivf;
+
+pub use hnsw::Hnsw;
+pub use ivf::Ivf;
+
+/// Access to the vectors of a collection by their position
+pub trait Vectors: Sync {
+	fn len(&self) -> usize;
+
+	fn vector(&self, index: usize) -> &[f32];
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum IndexOptions {
+	/// Exact search scoring every embedding in the collection
+	#[default]
+	Flat,
+	/// Approximate search in a hierarchical navigable small world graph
+	Hnsw {
+		/// Maximum number of neighbours of a node in the upper layers of the graph
+		#[serde(default = "default_m")]
+		m: usize,
+		/// Number of candidates to consider when connecting a new node
+		#[serde(default = "default_ef_construction")]
+		ef_construction: usize,
+	},
+	/// Approximate search in the posting lists of the centroids closest to the query
+	Ivf {
+		/// Number of centroids trained by k-means clustering
+		#[serde(default = "default_nlist")]
+		nlist: usize,
+	},
+}
+
+const fn default_m() -> usize {
+	16
+}
+
+const fn default_ef_construction() -> usize {
+	200
+}
+
+const fn default_nlist() -> usize {
+	100
+}
+
+impl IndexOptions {
+	pub const fn is_valid(&self) -> bool {
+		match self {
+			Self::Flat => true,
+			Self::Hnsw { m, ef_construction } => *m >= 2 && *ef_construction >= 1,
+			Self::Ivf { nlist } => *nlist >= 1,
+		}
+	}
+}
+
+/// Parameters of a single similarity search
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SearchParams {
+	/// Number of candidates to consider in a graph search
+	pub ef: Option<usize>,
+	/// Number of posting lists to scan in an inverted file search
+	pub nprobe: Option<usize>,
+	/// How many more candidates to find in quantized vectors for re-ranking
+	pub oversampling: Option<f32>,
+	/// Score all embeddings instead of using the approximate index
+	pub exact: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum Index {
+	#[default]
+	Flat,
+	Hnsw(Hnsw),
+	Ivf(Ivf),
+}
+
+impl Index {
+	pub const fn new(options: IndexOptions, distance: Distance) -> Self {
+		match options {
+			IndexOptions::Flat => Self::Flat,
+			IndexOptions::Hnsw { m, ef_construction } => {
+				Self::Hnsw(Hnsw::new(m, ef_construction, distance))
+			},
+			IndexOptions::Ivf { nlist } => Self::Ivf(Ivf::new(nlist, distance)),
+		}
+	}
+
+	pub const fn options(&self) -> IndexOptions {
+		match self {
+			Self::Flat => IndexOptions::Flat,
+			Self::Hnsw(hnsw) => hnsw.options(),
+			Self::Ivf(ivf) => ivf.options(),
+		}
+	}
+
+	/// If the index can answer queries, an inverted file has to be trained first
+	pub const fn is_searchable(&self) -> bool {
+		match self {
+			Self::Flat => false,
+			Self::Hnsw(_) => true,
+			Self::Ivf(ivf) => ivf.is_trained(),
+		}
+	}
+
+	/// If the index doesn't need training, or has been trained
+	pub const fn is_trained(&self) -> bool {
+		match self {
+			Self::Flat | Self::Hnsw(_) => true,
+			Self::Ivf(ivf) => ivf.is_trained(),
+		}
+	}
+
+	/// Builds the index from scratch, retraining the centroids of an inverted file
+	pub fn rebuild(&mut self, vectors: &impl Vectors) {
+		match self {
+			Self::Flat => {},
+			Self::Hnsw(hnsw) => hnsw.rebuild(vectors),
+			Self::Ivf(ivf) => ivf.train(vectors),
+		}
+	}
+
+	pub fn insert(&mut self, index: usize, vectors: &impl Vectors) {
+		match self {
+			Self::Flat => {},
+			Self::Hnsw(hnsw) => hnsw.insert(index, vectors),
+			Self::Ivf(ivf) => ivf.insert(index, vectors),
+		}
+	}
+
+	/// Connects the vector again, which replaced the one at `index`
+	pub fn update(&mut self, index: usize, vectors: &impl Vectors) {
+		match self {
+			Self::Flat => {},
+			Self::Hnsw(hnsw) => hnsw.update(index, vectors),
+			Self::Ivf(ivf) => ivf.update(index, vectors),
+		}
+	}
+
+	/// Drops the deleted vectors and moves the others to the new slots in `remap`
+	pub fn compact(&mut self, remap: &[Option<usize>], vectors: &impl Vectors) {
+		match self {
+			Self::Flat => {},
+			Self::Hnsw(hnsw) => hnsw.compact(remap, vectors),
+			Self::Ivf(ivf) => ivf.compact(remap),
+		}
+	}
+
+	pub fn clear(&mut self) {
+		match self {
+			Self::Flat => {},
+			Self::Hnsw(hnsw) => hnsw.clear(),
+			Self::Ivf(ivf) => ivf.clear(),
+		}
+	}
+
+	/// Returns the best matches sorted from the most similar one, or `None` if the index
+	/// can't answer the query and all embeddings have to be scored
+	pub fn search(
+		&self,
+		vectors: &impl Vectors,
+		query: &[f32],
+		k: usize,
+		params: SearchParams,
+		accept: impl Fn(usize) -> bool + Sync,
+	) -> Option<Vec<ScoreIndex>> {
+		match self {
+			Self::Flat => None,
+			_ if params.exact => None,
+			Self::Hnsw(hnsw) => Some(hnsw.search(vectors, query, k, params.ef, accept)),
+			Self::Ivf(ivf) => ivf.search(vectors, query, k, params.nprobe, accept),
+		}
+	}
+}
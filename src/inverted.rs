@@ -0,0 +1,386 @@
+//! Inverted index of metadata values pointing to the embeddings, which contain them,
+//! for finding the candidates of a filter without scanning the whole collection
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+use std::{
+	cmp::Ordering,
+	collections::{BTreeMap, BTreeSet, HashMap},
+	ops::Bound,
+};
+
+use crate::{
+	filter::{self, Filter, Operator, Value},
+	metadata::Metadata,
+};
+
+/// Indexes values of chosen metadata fields. Only the names of the fields are stored,
+/// the values are indexed again when the collection is loaded. Embeddings are identified
+/// by their positions in the collection.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InvertedIndex {
+	/// Names of the indexed fields, or paths with dots to fields in nested objects
+	fields: Vec<String>,
+	/// Positions of the embeddings by the values of each field, sorted in ascending order
+	#[serde(skip)]
+	postings: HashMap<String, BTreeMap<Key, BTreeSet<usize>>>,
+}
+
+/// Indexed value, ordered by the type first, numbers compared including their sign
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+	Bool(bool),
+	Number(Number),
+	String(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Number(f64);
+
+impl PartialEq for Number {
+	fn eq(&self, other: &Self) -> bool {
+		self.cmp(other) == Ordering::Equal
+	}
+}
+
+impl Eq for Number {}
+
+impl PartialOrd for Number {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Number {
+	fn cmp(&self, other: &Self) -> Ordering {
+		self.0.total_cmp(&other.0)
+	}
+}
+
+impl Key {
+	/// Values are indexed by the keys, which filter values compare with them equal.
+	/// Strings, which can be parsed to numbers or booleans, are indexed as those too.
+	fn from_json(value: &Json) -> Vec<Self> {
+		match value {
+			Json::Bool(value) => vec![Self::Bool(*value)],
+			Json::Number(value) => value.as_f64().map(Self::number).into_iter().collect(),
+			Json::String(value) => {
+				let mut keys = vec![Self::String(value.clone())];
+				if let Ok(number) = value.parse::<f64>() {
+					keys.push(Self::number(number));
+				}
+				if let Ok(value) = value.parse::<bool>() {
+					keys.push(Self::Bool(value));
+				}
+				keys
+			},
+			Json::Array(items) => items.iter().flat_map(Self::from_json).collect(),
+			Json::Null | Json::Object(_) => Vec::new(),
+		}
+	}
+
+	fn from_value(value: &Value) -> Self {
+		match value {
+			Value::Bool(value) => Self::Bool(*value),
+			Value::Number(value) => Self::number(*value),
+			Value::String(value) => Self::String(value.clone()),
+		}
+	}
+
+	const fn same_type(&self, other: &Self) -> bool {
+		matches!(
+			(self, other),
+			(Self::Bool(_), Self::Bool(_))
+				| (Self::Number(_), Self::Number(_))
+				| (Self::String(_), Self::String(_))
+		)
+	}
+
+	/// Negative zero is equal to zero in filters
+	const fn number(value: f64) -> Self {
+		Self::Number(Number(if value == 0.0 { 0.0 } else { value }))
+	}
+}
+
+impl InvertedIndex {
+	pub fn new(fields: Vec<String>) -> Self {
+		Self {
+			fields,
+			postings: HashMap::new(),
+		}
+	}
+
+	pub fn fields(&self) -> &[String] {
+		&self.fields
+	}
+
+	pub fn clear(&mut self) {
+		self.postings.clear();
+	}
+
+	pub fn rebuild<'a>(&mut self, metadata: impl Iterator<Item = Option<&'a Metadata>>) {
+		self.clear();
+		for (index, metadata) in metadata.enumerate() {
+			self.insert(index, metadata);
+		}
+	}
+
+	/// Adds the indexed values of the embedding at `index`
+	pub fn insert(&mut self, index: usize, metadata: Option<&Metadata>) {
+		for (field, key) in keys(&self.fields, metadata) {
+			self.postings
+				.entry(field.clone())
+				.or_default()
+				.entry(key)
+				.or_default()
+				.insert(index);
+		}
+	}
+
+	/// Replaces the indexed values of the embedding at `index`, which had the
+	/// `previous` metadata
+	pub fn update(
+		&mut self,
+		index: usize,
+		previous: Option<&Metadata>,
+		metadata: Option<&Metadata>,
+	) {
+		self.remove(index, previous);
+		self.insert(index, metadata);
+	}
+
+	/// Drops the indexed values of the embedding at `index`, looking up only the
+	/// postings of the values in its metadata
+	pub fn remove(&mut self, index: usize, metadata: Option<&Metadata>) {
+		for (field, key) in keys(&self.fields, metadata) {
+			let Some(postings) = self.postings.get_mut(field) else {
+				continue;
+			};
+			if let Some(positions) = postings.get_mut(&key) {
+				positions.remove(&index);
+				if positions.is_empty() {
+					postings.remove(&key);
+				}
+			}
+		}
+	}
+
+	/// Returns the positions of the embeddings, which can match the filter, sorted
+	/// in ascending order, or `None` if the filter cannot be evaluated by the index.
+	/// The embeddings still have to be checked by the filter.
+	pub fn candidates(&self, filter: &Filter) -> Option<Vec<usize>> {
+		match filter {
+			Filter::And(filters) => filters
+				.iter()
+				.filter_map(|filter| self.candidates(filter))
+				.reduce(|a, b| intersect(&a, &b)),
+			Filter::Or(filters) => filters
+				.iter()
+				.map(|filter| self.candidates(filter))
+				.try_fold(Vec::new(), |a, b| Some(unite(&a, &b?))),
+			Filter::Not(_) => None,
+			Filter::Field(field, operator) => {
+				if !self.fields.contains(field) {
+					return None;
+				}
+				let empty = BTreeMap::new();
+				let postings = self.postings.get(field).unwrap_or(&empty);
+				lookup(postings, operator)
+			},
+		}
+	}
+}
+
+/// Indexed fields of the metadata with the keys of their values
+fn keys<'a>(
+	fields: &'a [String],
+	metadata: Option<&'a Metadata>,
+) -> impl Iterator<Item = (&'a String, Key)> + 'a {
+	metadata.into_iter().flat_map(move |metadata| {
+		fields.iter().flat_map(move |field| {
+			filter::field(metadata, field)
+				.into_iter()
+				.flat_map(Key::from_json)
+				.map(move |key| (field, key))
+		})
+	})
+}
+
+fn lookup(postings: &BTreeMap<Key, BTreeSet<usize>>, operator: &Operator) -> Option<Vec<usize>> {
+	let exact = |value: &Value| postings.get(&Key::from_value(value));
+	let range = |lower: Bound<&Value>, upper: Bound<&Value>, value: &Value| {
+		// keys are grouped by their types, only values of the same type are comparable
+		let expected = Key::from_value(value);
+		merge(
+			postings
+				.range((lower.map(Key::from_value), upper.map(Key::from_value)))
+				.skip_while(|(key, _)| !key.same_type(&expected))
+				.take_while(|(key, _)| key.same_type(&expected))
+				.map(|(_, positions)| positions)
+				.collect(),
+		)
+	};
+	match operator {
+		Operator::Eq(value) => Some(
+			exact(value)
+				.map(|positions| positions.iter().copied().collect())
+				.unwrap_or_default(),
+		),
+		Operator::In(values) => Some(merge(values.iter().filter_map(exact).collect())),
+		Operator::Gt(value) => Some(range(Bound::Excluded(value), Bound::Unbounded, value)),
+		Operator::Gte(value) => Some(range(Bound::Included(value), Bound::Unbounded, value)),
+		Operator::Lt(value) => Some(range(Bound::Unbounded, Bound::Excluded(value), value)),
+		Operator::Lte(value) => Some(range(Bound::Unbounded, Bound::Included(value), value)),
+		Operator::Prefix(prefix) => Some(merge(
+			postings
+				.range(Key::String(prefix.clone())..)
+				.take_while(|(key, _)| matches!(key, Key::String(key) if key.starts_with(prefix)))
+				.map(|(_, positions)| positions)
+				.collect(),
+		)),
+		Operator::Ne(_) | Operator::Nin(_) | Operator::Exists(_) | Operator::Contains(_) => None,
+	}
+}
+
+/// Merges posting lists of more values of a field to a sorted list without duplicates
+fn merge(lists: Vec<&BTreeSet<usize>>) -> Vec<usize> {
+	let mut result = lists.into_iter().flatten().copied().collect::<Vec<_>>();
+	result.sort_unstable();
+	result.dedup();
+	result
+}
+
+/// Merges two sorted lists of positions without duplicates
+fn unite(a: &[usize], b: &[usize]) -> Vec<usize> {
+	let mut result = Vec::with_capacity(a.len() + b.len());
+	let (mut i, mut j) = (0, 0);
+	while i < a.len() && j < b.len() {
+		match a[i].cmp(&b[j]) {
+			Ordering::Less => {
+				result.push(a[i]);
+				i += 1;
+			},
+			Ordering::Greater => {
+				result.push(b[j]);
+				j += 1;
+			},
+			Ordering::Equal => {
+				result.push(a[i]);
+				i += 1;
+				j += 1;
+			},
+		}
+	}
+	result.extend_from_slice(&a[i..]);
+	result.extend_from_slice(&b[j..]);
+	result
+}
+
+/// Returns positions present in both sorted lists
+fn intersect(a: &[usize], b: &[usize]) -> Vec<usize> {
+	let mut result = Vec::with_capacity(a.len().min(b.len()));
+	let (mut i, mut j) = (0, 0);
+	while i < a.len() && j < b.len() {
+		match a[i].cmp(&b[j]) {
+			Ordering::Less => i += 1,
+			Ordering::Greater => j += 1,
+			Ordering::Equal => {
+				result.push(a[i]);
+				i += 1;
+				j += 1;
+			},
+		}
+	}
+	result
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	fn index(metadata: &[Json]) -> InvertedIndex {
+		let metadata: Vec<Metadata> = metadata
+			.iter()
+			.map(|metadata| serde_json::from_value(metadata.clone()).unwrap())
+			.collect();
+		let mut index = InvertedIndex::new(vec!["page".to_string(), "author.name".to_string()]);
+		index.rebuild(metadata.iter().map(Some));
+		index
+	}
+
+	fn candidates(index: &InvertedIndex, filter: Json) -> Option<Vec<usize>> {
+		index.candidates(&serde_json::from_value(filter).unwrap())
+	}
+
+	#[test]
+	fn finds_candidates_of_filters() {
+		let index = index(&[
+			json!({ "page": 1, "author": { "name": "Ann" } }),
+			json!({ "page": "2", "author": { "name": "Bob" } }),
+			json!({ "page": [3, 1], "title": "Intro" }),
+			json!({ "author": { "name": "Anna" } }),
+		]);
+		assert_eq!(candidates(&index, json!({ "page": 1 })), Some(vec![0, 2]));
+		// strings are indexed as the numbers, which they contain, too
+		assert_eq!(candidates(&index, json!({ "page": 2 })), Some(vec![1]));
+		assert_eq!(
+			candidates(&index, json!({ "page": { "$in": [2, 3] } })),
+			Some(vec![1, 2])
+		);
+		assert_eq!(
+			candidates(&index, json!({ "page": { "$gt": 1, "$lte": 3 } })),
+			Some(vec![1, 2])
+		);
+		assert_eq!(
+			candidates(&index, json!({ "author.name": { "$prefix": "Ann" } })),
+			Some(vec![0, 3])
+		);
+		assert_eq!(
+			candidates(
+				&index,
+				json!({ "$or": [{ "page": 3 }, { "author.name": "Bob" }] })
+			),
+			Some(vec![1, 2])
+		);
+		// fields without the index don't narrow the candidates
+		assert_eq!(
+			candidates(&index, json!({ "page": 1, "title": "Intro" })),
+			Some(vec![0, 2])
+		);
+		assert_eq!(candidates(&index, json!({ "title": "Intro" })), None);
+		assert_eq!(
+			candidates(
+				&index,
+				json!({ "$or": [{ "page": 3 }, { "title": "Intro" }] })
+			),
+			None
+		);
+		assert_eq!(candidates(&index, json!({ "page": { "$ne": 1 } })), None);
+	}
+
+	#[test]
+	fn follows_changes_of_embeddings() {
+		let mut index = index(&[
+			json!({ "page": 1 }),
+			json!({ "page": 2 }),
+			json!({ "page": 1 }),
+		]);
+		let metadata: Metadata = serde_json::from_value(json!({ "page": 2 })).unwrap();
+		let previous: Metadata = serde_json::from_value(json!({ "page": 1 })).unwrap();
+		index.update(0, Some(&previous), Some(&metadata));
+		assert_eq!(candidates(&index, json!({ "page": 1 })), Some(vec![2]));
+		assert_eq!(candidates(&index, json!({ "page": 2 })), Some(vec![0, 1]));
+
+		index.remove(1, Some(&metadata));
+		assert_eq!(candidates(&index, json!({ "page": 1 })), Some(vec![2]));
+		assert_eq!(candidates(&index, json!({ "page": 2 })), Some(vec![0]));
+		index.insert(1, Some(&metadata));
+		assert_eq!(candidates(&index, json!({ "page": 2 })), Some(vec![0, 1]));
+
+		// the postings of the values, which no embedding has any more, are dropped
+		index.remove(2, Some(&previous));
+		assert_eq!(candidates(&index, json!({ "page": 1 })), Some(vec![]));
+		assert!(!index.postings["page"].contains_key(&Key::number(1.0)));
+	}
+}
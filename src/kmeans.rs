@@ -0,0 +1,75 @@
+use rayon::prelude::*;
+
+use crate::{random, similarity::normalize};
+
+/// Maximum number of iterations, if the assignments don't settle earlier
+const MAX_ITERATIONS: usize = 25;
+
+/// Trains at most `k` centroids by Lloyd's algorithm. The `similarity` of a centroid
+/// and a point has to be higher for closer points. Centroids can be kept normalised
+/// to stay on the unit sphere like the points of cosine collections.
+#[allow(clippy::cast_precision_loss)]
+pub fn train(
+	points: &[&[f32]],
+	k: usize,
+	similarity: &(impl Fn(&[f32], &[f32]) -> f32 + Sync),
+	normalized: bool,
+) -> Vec<Vec<f32>> {
+	let mut seed = random::SEED;
+	let mut centroids = random::sample(&mut seed, points.len(), k)
+		.into_iter()
+		.map(|index| points[index].to_vec())
+		.collect::<Vec<_>>();
+	let Some(dimension) = centroids.first().map(Vec::len) else {
+		return centroids;
+	};
+
+	let mut assignments = Vec::new();
+	for _ in 0..MAX_ITERATIONS {
+		let next_assignments = points
+			.par_iter()
+			.map(|point| nearest(centroids.iter().map(Vec::as_slice), point, similarity))
+			.collect::<Vec<_>>();
+		if next_assignments == assignments {
+			break;
+		}
+		assignments = next_assignments;
+
+		let mut sums = vec![vec![0.0; dimension]; centroids.len()];
+		let mut counts = vec![0usize; centroids.len()];
+		for (point, &centroid) in points.iter().zip(&assignments) {
+			for (sum, value) in sums[centroid].iter_mut().zip(*point) {
+				*sum += value;
+			}
+			counts[centroid] += 1;
+		}
+
+		for ((centroid, mut sum), count) in centroids.iter_mut().zip(sums).zip(counts) {
+			if count == 0 {
+				// restart an empty cluster from a random point
+				let index = random::below(&mut seed, points.len());
+				centroid.copy_from_slice(points[index]);
+				continue;
+			}
+			for value in &mut sum {
+				*value /= count as f32;
+			}
+			*centroid = if normalized { normalize(&sum) } else { sum };
+		}
+	}
+
+	centroids
+}
+
+/// Returns the position of the centroid most similar to the point
+pub fn nearest<'a>(
+	centroids: impl Iterator<Item = &'a [f32]>,
+	point: &[f32],
+	similarity: &impl Fn(&[f32], &[f32]) -> f32,
+) -> usize {
+	centroids
+		.map(|centroid| similarity(centroid, point))
+		.enumerate()
+		.max_by(|(_, left), (_, right)| left.total_cmp(right))
+		.map_or(0, |(index, _)| index)
+}
@@ -0,0 +1,78 @@
+//! Loaded collections with their memory and the order in which they were used,
+//! which decides the ones to unload first. Collections are used by concurrent
+//! requests, so the order is kept behind its own lock.
+
+use std::{
+	collections::HashMap,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Mutex, MutexGuard, PoisonError,
+	},
+};
+
+#[derive(Debug, Default)]
+pub struct Lru {
+	/// Number of uses so far
+	clock: AtomicU64,
+	/// Loaded collections by their names
+	loaded: Mutex<HashMap<String, Use>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Use {
+	/// Value of the clock when the collection was used last
+	tick: u64,
+	/// Estimated memory taken by the collection in bytes
+	size: usize,
+}
+
+impl Lru {
+	/// Records a use of the collection, if it is loaded
+	pub fn touch(&self, name: &str) {
+		let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+		if let Some(used) = self.lock().get_mut(name) {
+			used.tick = tick;
+		}
+	}
+
+	/// Records a loaded collection as used, or updates its memory
+	pub fn insert(&self, name: &str, size: usize) {
+		let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+		self.lock().insert(name.to_string(), Use { tick, size });
+	}
+
+	/// Updates the memory of a loaded collection
+	pub fn resize(&self, name: &str, size: usize) {
+		if let Some(used) = self.lock().get_mut(name) {
+			used.size = size;
+		}
+	}
+
+	pub fn remove(&self, name: &str) {
+		self.lock().remove(name);
+	}
+
+	/// Memory taken by all loaded collections in bytes
+	pub fn size(&self) -> usize {
+		self.lock().values().map(|used| used.size).sum()
+	}
+
+	/// Returns the names and memory of the loaded collections from the least
+	/// recently used one
+	pub fn sorted(&self) -> Vec<(String, usize)> {
+		let mut sorted: Vec<_> = self
+			.lock()
+			.iter()
+			.map(|(name, used)| (name.clone(), *used))
+			.collect();
+		sorted.sort_by_key(|(_, used)| used.tick);
+		sorted
+			.into_iter()
+			.map(|(name, used)| (name, used.size))
+			.collect()
+	}
+
+	fn lock(&self) -> MutexGuard<'_, HashMap<String, Use>> {
+		self.loaded.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+}
@@ -1,17 +1,38 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery)]
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use dotenvy::dotenv;
+use std::env;
 use tracing_subscriber::{
 	prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
 };
 
 mod db;
 mod errors;
+mod filter;
+mod index;
+mod inverted;
+mod kmeans;
+mod lru;
+mod metadata;
+mod migration;
+mod planner;
+mod quantization;
+mod random;
 mod routes;
+mod segment;
 mod server;
+mod shared;
 mod shutdown;
+mod simd;
 mod similarity;
+mod snapshot;
+mod store;
+#[cfg(test)]
+mod testing;
+mod tombstones;
+mod vectors;
+mod wal;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -26,5 +47,11 @@ async fn main() -> Result<()> {
 		)
 		.init();
 
-	server::start().await
+	let args: Vec<String> = env::args().skip(1).collect();
+	match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+		[] => server::start().await,
+		["migrate"] => migration::run(false),
+		["migrate", "--dry-run"] => migration::run(true),
+		_ => bail!("Usage: litevec [migrate [--dry-run]]"),
+	}
 }
@@ -0,0 +1,262 @@
+//! Metadata of embeddings with values of any JSON type. Bincode cannot store JSON values
+//! directly, because it does not describe the types of the values, which are stored
+//! as tagged enums instead.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+
+pub type Metadata = HashMap<String, Value>;
+
+/// Serialises metadata to JSON as-is and to bincode by [`StoredRef`]
+#[allow(clippy::ref_option)]
+pub fn serialize<S: Serializer>(
+	metadata: &Option<Metadata>,
+	serializer: S,
+) -> Result<S::Ok, S::Error> {
+	if serializer.is_human_readable() {
+		return metadata.serialize(serializer);
+	}
+	metadata
+		.as_ref()
+		.map(|metadata| {
+			metadata
+				.iter()
+				.map(|(key, value)| (key.as_str(), StoredRef::from(value)))
+				.collect::<Vec<_>>()
+		})
+		.serialize(serializer)
+}
+
+/// Deserialises metadata from JSON as-is and from bincode by [`Stored`]
+pub fn deserialize<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Option<Metadata>, D::Error> {
+	if deserializer.is_human_readable() {
+		return Option::<Metadata>::deserialize(deserializer);
+	}
+	Ok(
+		Option::<Vec<(String, Stored)>>::deserialize(deserializer)?.map(|fields| {
+			fields
+				.into_iter()
+				.map(|(key, value)| (key, value.into()))
+				.collect()
+		}),
+	)
+}
+
+/// Deserialises metadata, which may be null, telling it apart from missing metadata
+/// together with `#[serde(default)]`
+#[allow(clippy::option_option)]
+pub fn deserialize_nullable<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Option<Option<Metadata>>, D::Error> {
+	Option::<Metadata>::deserialize(deserializer).map(Some)
+}
+
+/// Applies a JSON merge patch (RFC 7396) to the metadata. Fields set to null are removed,
+/// objects are merged recursively and any other value replaces the current one.
+pub fn merge(metadata: &mut Metadata, patch: &Metadata) {
+	for (key, value) in patch {
+		if value.is_null() {
+			metadata.remove(key);
+		} else {
+			merge_value(metadata.entry(key.clone()).or_insert(Value::Null), value);
+		}
+	}
+}
+
+fn merge_value(target: &mut Value, patch: &Value) {
+	let Value::Object(patch) = patch else {
+		target.clone_from(patch);
+		return;
+	};
+	if !target.is_object() {
+		*target = Value::Object(Map::new());
+	}
+	if let Value::Object(target) = target {
+		for (key, value) in patch {
+			if value.is_null() {
+				target.remove(key);
+			} else {
+				merge_value(target.entry(key.clone()).or_insert(Value::Null), value);
+			}
+		}
+	}
+}
+
+/// Converts metadata with only string values stored by the previous versions
+pub fn from_strings(metadata: HashMap<String, String>) -> Metadata {
+	metadata
+		.into_iter()
+		.map(|(key, value)| (key, Value::String(value)))
+		.collect()
+}
+
+/// JSON value borrowed for storing, the variants have to match [`Stored`]
+#[derive(Serialize)]
+enum StoredRef<'a> {
+	Null,
+	Bool(bool),
+	Integer(i64),
+	Unsigned(u64),
+	Float(f64),
+	String(&'a str),
+	Array(Vec<Self>),
+	Object(Vec<(&'a str, Self)>),
+}
+
+/// JSON value loaded from the store, the variants have to match [`StoredRef`]
+#[derive(Deserialize)]
+enum Stored {
+	Null,
+	Bool(bool),
+	Integer(i64),
+	Unsigned(u64),
+	Float(f64),
+	String(String),
+	Array(Vec<Self>),
+	Object(Vec<(String, Self)>),
+}
+
+impl<'a> From<&'a Value> for StoredRef<'a> {
+	fn from(value: &'a Value) -> Self {
+		match value {
+			Value::Null => Self::Null,
+			Value::Bool(value) => Self::Bool(*value),
+			Value::Number(value) => value
+				.as_i64()
+				.map(Self::Integer)
+				.or_else(|| value.as_u64().map(Self::Unsigned))
+				.unwrap_or_else(|| Self::Float(value.as_f64().unwrap_or_default())),
+			Value::String(value) => Self::String(value),
+			Value::Array(items) => Self::Array(items.iter().map(Self::from).collect()),
+			Value::Object(fields) => Self::Object(
+				fields
+					.iter()
+					.map(|(key, value)| (key.as_str(), Self::from(value)))
+					.collect(),
+			),
+		}
+	}
+}
+
+impl From<Stored> for Value {
+	fn from(value: Stored) -> Self {
+		match value {
+			Stored::Null => Self::Null,
+			Stored::Bool(value) => Self::Bool(value),
+			Stored::Integer(value) => Self::Number(value.into()),
+			Stored::Unsigned(value) => Self::Number(value.into()),
+			Stored::Float(value) => Number::from_f64(value).map_or(Self::Null, Self::Number),
+			Stored::String(value) => Self::String(value),
+			Stored::Array(items) => Self::Array(items.into_iter().map(Self::from).collect()),
+			Stored::Object(fields) => Self::Object(
+				fields
+					.into_iter()
+					.map(|(key, value)| (key, value.into()))
+					.collect::<Map<_, _>>(),
+			),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	#[derive(Serialize, Deserialize)]
+	struct Stored {
+		#[serde(with = "super")]
+		metadata: Option<Metadata>,
+	}
+
+	#[test]
+	fn stores_values_of_any_type() {
+		let metadata: Metadata = serde_json::from_value(json!({
+			"null": null,
+			"bool": true,
+			"integer": -1,
+			"unsigned": u64::MAX,
+			"float": 0.5,
+			"string": "text",
+			"array": [1, "a", [false]],
+			"object": { "nested": { "value": 2 } }
+		}))
+		.unwrap();
+		let stored = Stored {
+			metadata: Some(metadata.clone()),
+		};
+
+		let binary = bincode::serialize(&stored).unwrap();
+		let loaded: Stored = bincode::deserialize(&binary).unwrap();
+		assert_eq!(loaded.metadata, Some(metadata.clone()));
+
+		let text = serde_json::to_string(&stored).unwrap();
+		let loaded: Stored = serde_json::from_str(&text).unwrap();
+		assert_eq!(loaded.metadata, Some(metadata));
+
+		let binary = bincode::serialize(&Stored { metadata: None }).unwrap();
+		let loaded: Stored = bincode::deserialize(&binary).unwrap();
+		assert_eq!(loaded.metadata, None);
+	}
+
+	#[test]
+	fn converts_string_values() {
+		let metadata = from_strings(HashMap::from([("page".to_string(), "1".to_string())]));
+		assert_eq!(metadata["page"], json!("1"));
+	}
+
+	#[test]
+	#[allow(clippy::option_option)]
+	fn tells_null_from_missing_metadata() {
+		#[derive(Deserialize)]
+		struct Update {
+			#[serde(default, deserialize_with = "super::deserialize_nullable")]
+			metadata: Option<Option<Metadata>>,
+		}
+
+		let missing: Update = serde_json::from_value(json!({})).unwrap();
+		assert_eq!(missing.metadata, None);
+		let null: Update = serde_json::from_value(json!({ "metadata": null })).unwrap();
+		assert_eq!(null.metadata, Some(None));
+		let set: Update = serde_json::from_value(json!({ "metadata": { "page": 1 } })).unwrap();
+		assert_eq!(set.metadata.unwrap().unwrap()["page"], json!(1));
+	}
+
+	#[test]
+	fn merges_patches() {
+		let mut metadata: Metadata = serde_json::from_value(json!({
+			"title": "Goodbye!",
+			"author": { "givenName": "John", "familyName": "Doe" },
+			"tags": ["example", "sample"],
+			"content": "This will be unchanged"
+		}))
+		.unwrap();
+		let patch: Metadata = serde_json::from_value(json!({
+			"title": "Hello!",
+			"phoneNumber": "+01-123-456-7890",
+			"author": { "familyName": null },
+			"tags": ["example"],
+			"content": { "text": "replaced" }
+		}))
+		.unwrap();
+		merge(&mut metadata, &patch);
+		assert_eq!(
+			serde_json::to_value(metadata).unwrap(),
+			json!({
+				"title": "Hello!",
+				"author": { "givenName": "John" },
+				"tags": ["example"],
+				"content": { "text": "replaced" },
+				"phoneNumber": "+01-123-456-7890"
+			})
+		);
+
+		let mut metadata: Metadata = serde_json::from_value(json!({ "a": 1, "b": 2 })).unwrap();
+		let patch: Metadata = serde_json::from_value(json!({ "a": null, "c": null })).unwrap();
+		merge(&mut metadata, &patch);
+		assert_eq!(serde_json::to_value(metadata).unwrap(), json!({ "b": 2 }));
+	}
+}
@@ -0,0 +1,313 @@
+//! Upgrades of the database store to the current format. The version of the store
+//! is kept in the manifest `._collections` and the store is upgraded by running
+//! the migrations from its version one after another, recording the version after
+//! each of them, so that an interrupted upgrade continues with the failed step.
+//! Versions, which changed only the format of the collection files, are upgraded
+//! by a single migration, which writes the collections in the current format.
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::{fs, ops::Range};
+
+use crate::{
+	db::{Db, STORE_PATH},
+	store,
+};
+
+/// Version of the store, which is written by this build
+pub const VERSION: u32 = 5;
+
+/// Contents of the `._collections` file
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+	/// Version of the format of the store
+	version: u32,
+}
+
+pub struct Migration {
+	/// Versions of the store, which the migration upgrades
+	pub from: Range<u32>,
+	/// Version of the store after the migration
+	pub to: u32,
+	/// What the migration changes
+	pub description: &'static str,
+	run: fn() -> anyhow::Result<()>,
+}
+
+/// Migrations upgrading the store, sorted by the versions they upgrade
+const MIGRATIONS: [Migration; 3] = [
+	Migration {
+		from: 0..1,
+		to: 1,
+		description: "split the single database file to a file per collection",
+		run: split_collections,
+	},
+	// the converted collections are saved in the current format
+	Migration {
+		from: 1..2,
+		to: VERSION,
+		description: "convert metadata values from strings to values of any type",
+		run: convert_metadata,
+	},
+	// the collection files got headers with checksums in the version 3, vectors
+	// mapped to memory in the version 4 and segments in the version 5
+	Migration {
+		from: 2..VERSION,
+		to: VERSION,
+		description: "write the collection files in the current format",
+		run: rewrite_collections,
+	},
+];
+
+/// Returns the version of the store, or `None` if there is no store yet
+pub fn version() -> anyhow::Result<Option<u32>> {
+	let manifest_path = STORE_PATH.join("._collections");
+	if manifest_path.exists() {
+		let content = fs::read(&manifest_path)?;
+		// the manifest used to be an empty marker, followed by one of metadata
+		if content.is_empty() {
+			let metadata_marker = STORE_PATH.join("._metadata");
+			return Ok(Some(if metadata_marker.exists() { 2 } else { 1 }));
+		}
+		let manifest: Manifest = serde_json::from_slice(&content)?;
+		return Ok(Some(manifest.version));
+	}
+	if STORE_PATH.join("db").exists() {
+		return Ok(Some(0));
+	}
+	Ok(None)
+}
+
+/// Returns the migrations, which the store needs to be upgraded to the current version,
+/// with the versions, which they upgrade from
+pub fn pending() -> anyhow::Result<Vec<(u32, &'static Migration)>> {
+	let Some(mut version) = version()? else {
+		return Ok(Vec::new());
+	};
+	if version > VERSION {
+		bail!("The store version {version} is newer than the supported version {VERSION}");
+	}
+	let mut migrations = Vec::new();
+	for migration in &MIGRATIONS {
+		if migration.from.contains(&version) {
+			migrations.push((version, migration));
+			version = migration.to;
+		}
+	}
+	Ok(migrations)
+}
+
+/// Upgrades the store to the current version, or creates an empty one
+pub fn migrate() -> anyhow::Result<()> {
+	if version()?.is_none() {
+		tracing::debug!("Creating database store");
+		fs::create_dir_all(STORE_PATH.as_path())?;
+		return write_version(VERSION);
+	}
+	for (version, migration) in pending()? {
+		tracing::info!(
+			"Migrating database store from version {} to {}: {}",
+			version,
+			migration.to,
+			migration.description
+		);
+		(migration.run)()?;
+		write_version(migration.to)?;
+	}
+	Ok(())
+}
+
+/// Prints the migrations, which the store needs, or runs them
+pub fn run(dry_run: bool) -> anyhow::Result<()> {
+	let path = STORE_PATH.display();
+	match version()? {
+		None => println!("There is no store in {path}"),
+		Some(version) => {
+			let migrations = pending()?;
+			if migrations.is_empty() {
+				println!("The store in {path} has the current version {version}");
+				return Ok(());
+			}
+			println!("The store in {path} has version {version}, the current is {VERSION}");
+			for (version, migration) in migrations {
+				println!(
+					"  {} -> {}: {}",
+					version, migration.to, migration.description
+				);
+			}
+			if !dry_run {
+				migrate()?;
+				println!("The store has been migrated");
+			}
+		},
+	}
+	Ok(())
+}
+
+fn write_version(version: u32) -> anyhow::Result<()> {
+	let manifest = serde_json::to_vec(&Manifest { version })?;
+	store::replace(&STORE_PATH.join("._collections"), &manifest)?;
+	// the version in the manifest replaces the marker of converted metadata
+	let metadata_marker = STORE_PATH.join("._metadata");
+	if metadata_marker.exists() {
+		fs::remove_file(metadata_marker)?;
+	}
+	Ok(())
+}
+
+fn split_collections() -> anyhow::Result<()> {
+	Db::convert_old_store()
+}
+
+fn convert_metadata() -> anyhow::Result<()> {
+	let db = Db::new();
+	db.load_collections(true)?;
+	db.store_collections()
+}
+
+/// Saves all collections in the current format
+fn rewrite_collections() -> anyhow::Result<()> {
+	let db = Db::new();
+	db.load_collections(false)?;
+	db.rewrite_all();
+	db.store_collections()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		db::{self, Embedding},
+		metadata,
+		similarity::Distance,
+		testing,
+		wal::Durability,
+	};
+	use serde_json::json;
+	use std::collections::HashMap;
+
+	/// Collection with metadata values stored as strings before the version 2
+	#[derive(Serialize)]
+	struct StringMetadataCollection {
+		dimension: usize,
+		distance: Distance,
+		embeddings: Vec<StringMetadataEmbedding>,
+	}
+
+	#[derive(Serialize)]
+	struct StringMetadataEmbedding {
+		id: String,
+		vector: Vec<f32>,
+		metadata: Option<HashMap<String, String>>,
+	}
+
+	fn string_metadata_collection() -> StringMetadataCollection {
+		StringMetadataCollection {
+			dimension: 2,
+			distance: Distance::Euclidean,
+			embeddings: vec![
+				StringMetadataEmbedding {
+					id: "a".to_string(),
+					vector: vec![1.0, 0.0],
+					metadata: Some(HashMap::from([("page".to_string(), "1".to_string())])),
+				},
+				StringMetadataEmbedding {
+					id: "b".to_string(),
+					vector: vec![0.0, 1.0],
+					metadata: None,
+				},
+			],
+		}
+	}
+
+	fn pending_versions() -> Vec<(u32, u32)> {
+		pending()
+			.unwrap()
+			.into_iter()
+			.map(|(version, migration)| (version, migration.to))
+			.collect()
+	}
+
+	async fn assert_migrated() {
+		assert_eq!(version().unwrap(), Some(VERSION));
+		assert!(pending_versions().is_empty());
+		assert!(!STORE_PATH.join("._metadata").exists());
+		let db = db::from_store(Durability::Off, None).await.unwrap();
+		assert_eq!(db.list(), ["docs"]);
+		let collection = db.read_collection("docs").await.unwrap();
+		assert_eq!(collection.dimension, 2);
+		let embedding = |id| serde_json::to_value(collection.get(id, false)).unwrap();
+		assert_eq!(
+			embedding("a"),
+			json!({ "id": "a", "vector": [1.0, 0.0], "metadata": { "page": "1" } })
+		);
+		assert_eq!(
+			embedding("b"),
+			json!({ "id": "b", "vector": [0.0, 1.0], "metadata": null })
+		);
+	}
+
+	#[tokio::test]
+	async fn splits_single_database_file() {
+		let _store = testing::store().await;
+		let collections = HashMap::from([("docs", string_metadata_collection())]);
+		fs::write(
+			STORE_PATH.join("db"),
+			bincode::serialize(&collections).unwrap(),
+		)
+		.unwrap();
+		assert_eq!(version().unwrap(), Some(0));
+		assert_eq!(pending_versions(), [(0, 1), (1, VERSION)]);
+
+		migrate().unwrap();
+		assert!(!STORE_PATH.join("db").exists());
+		assert_migrated().await;
+	}
+
+	#[tokio::test]
+	async fn converts_string_metadata() {
+		let _store = testing::store().await;
+		fs::write(STORE_PATH.join("._collections"), "").unwrap();
+		fs::write(
+			STORE_PATH.join("docs"),
+			bincode::serialize(&string_metadata_collection()).unwrap(),
+		)
+		.unwrap();
+		assert_eq!(version().unwrap(), Some(1));
+		assert_eq!(pending_versions(), [(1, VERSION)]);
+
+		migrate().unwrap();
+		assert_migrated().await;
+	}
+
+	#[tokio::test]
+	async fn rewrites_collection_files() {
+		let _store = testing::store().await;
+		fs::write(STORE_PATH.join("._collections"), "").unwrap();
+		fs::write(STORE_PATH.join("._metadata"), "").unwrap();
+		// the collection files were written by bincode without headers
+		let embeddings: Vec<Embedding> = string_metadata_collection()
+			.embeddings
+			.into_iter()
+			.map(|embedding| Embedding {
+				id: embedding.id,
+				vector: embedding.vector,
+				metadata: embedding.metadata.map(metadata::from_strings),
+			})
+			.collect();
+		fs::write(
+			STORE_PATH.join("docs"),
+			bincode::serialize(&(2_usize, Distance::Euclidean, embeddings)).unwrap(),
+		)
+		.unwrap();
+		assert_eq!(version().unwrap(), Some(2));
+		assert_eq!(pending_versions(), [(2, VERSION)]);
+
+		migrate().unwrap();
+		assert_migrated().await;
+
+		// stores upgraded only partially continue with the last migration
+		write_version(3).unwrap();
+		assert_eq!(pending_versions(), [(3, VERSION)]);
+	}
+}
@@ -0,0 +1,232 @@
+//! Chooses how a filtered similarity search is executed. Graph searches starve when
+//! the filter accepts only few embeddings, scoring all matching embeddings wastes time
+//! when the filter accepts most of them.
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::random;
+
+/// Matching embeddings are scored directly if there aren't more of them
+const BRUTE_FORCE_MATCHES: usize = 1000;
+/// Matching embeddings are scored directly if they're a smaller fraction of the collection
+const BRUTE_FORCE_SELECTIVITY: f32 = 0.05;
+/// The index is searched without the filter if it accepts a bigger fraction of the collection
+const POST_FILTER_SELECTIVITY: f32 = 0.5;
+/// How many more results to find than the filter is expected to leave
+const POST_FILTER_MARGIN: f32 = 1.5;
+/// Number of randomly chosen embeddings checked by the filter to estimate its selectivity
+const SAMPLE_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+	/// Scores the original vectors of the embeddings matching the filter
+	BruteForce,
+	/// Searches the index or the quantized vectors, skipping embeddings not matching the filter
+	Filtered,
+	/// Searches the index for more results and drops the ones not matching the filter
+	PostFilter,
+}
+
+/// Plan of a similarity search, reported in the response if it's asked for
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Plan {
+	/// How the search was executed
+	pub strategy: Strategy,
+	/// Number of embeddings in the collection
+	pub embedding_count: usize,
+	/// Estimated number of embeddings matching the filter
+	pub estimated_matches: usize,
+	/// Estimated fraction of the embeddings matching the filter
+	pub selectivity: f32,
+	/// If the candidates were narrowed by the metadata index
+	pub metadata_index: bool,
+	/// Number of results requested from the index before filtering them
+	pub candidates: Option<usize>,
+	/// If the post-filtered results weren't enough and the index was searched with the filter
+	pub fallback: bool,
+	/// If the index and the quantization have been trained, all embeddings are scored
+	/// until then
+	pub trained: bool,
+}
+
+/// Ways, which the collection can be searched by
+#[derive(Debug, Clone, Copy)]
+pub struct Access {
+	/// An approximate index can be searched
+	pub index: bool,
+	/// Quantized vectors can be searched
+	pub quantizer: bool,
+	/// Original vectors are available for scoring
+	pub vectors: bool,
+}
+
+impl Plan {
+	/// Estimates how many of the candidates match the filter by checking a sample of them
+	/// and chooses the cheapest strategy, which finds enough results
+	#[allow(
+		clippy::cast_possible_truncation,
+		clippy::cast_precision_loss,
+		clippy::cast_sign_loss
+	)]
+	pub fn new(
+		embedding_count: usize,
+		candidates: Option<&[usize]>,
+		filtered: bool,
+		accept: impl Fn(usize) -> bool,
+		k: usize,
+		access: Access,
+		trained: bool,
+	) -> Self {
+		let metadata_index = candidates.is_some();
+		let candidate_count = candidates.map_or(embedding_count, <[usize]>::len);
+		let position = |i: usize| candidates.map_or(i, |candidates| candidates[i]);
+		let estimated_matches = if !filtered {
+			candidate_count
+		} else if candidate_count <= SAMPLE_SIZE {
+			(0..candidate_count)
+				.filter(|&i| accept(position(i)))
+				.count()
+		} else {
+			let mut seed = random::SEED;
+			let matched = (0..SAMPLE_SIZE)
+				.filter(|_| accept(position(random::below(&mut seed, candidate_count))))
+				.count();
+			(candidate_count as f32 * matched as f32 / SAMPLE_SIZE as f32).round() as usize
+		};
+		let selectivity = if embedding_count > 0 {
+			estimated_matches as f32 / embedding_count as f32
+		} else {
+			1.0
+		};
+
+		let few_matches = estimated_matches <= BRUTE_FORCE_MATCHES.max(k)
+			|| selectivity < BRUTE_FORCE_SELECTIVITY;
+		let (strategy, requested) =
+			if !(access.index || access.quantizer) || (access.vectors && few_matches) {
+				(Strategy::BruteForce, None)
+			} else if access.index && filtered && selectivity >= POST_FILTER_SELECTIVITY {
+				let oversampling = POST_FILTER_MARGIN / selectivity;
+				(
+					Strategy::PostFilter,
+					Some((k as f32 * oversampling).ceil() as usize),
+				)
+			} else {
+				(Strategy::Filtered, None)
+			};
+
+		Self {
+			strategy,
+			embedding_count,
+			estimated_matches,
+			selectivity,
+			metadata_index,
+			candidates: requested,
+			fallback: false,
+			trained,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const INDEX: Access = Access {
+		index: true,
+		quantizer: false,
+		vectors: true,
+	};
+
+	/// Plans a search of 10000 embeddings, which the filter accepts every `nth` of
+	fn plan(nth: usize, candidates: Option<&[usize]>, access: Access) -> Plan {
+		Plan::new(
+			10_000,
+			candidates,
+			nth > 1,
+			|index| index % nth == 0,
+			10,
+			access,
+			true,
+		)
+	}
+
+	#[test]
+	fn chooses_strategy_by_selectivity() {
+		let unfiltered = plan(1, None, INDEX);
+		assert_eq!(unfiltered.strategy, Strategy::Filtered);
+		assert_eq!(unfiltered.estimated_matches, 10_000);
+
+		// most embeddings match, searching more of them without the filter is cheaper
+		let post_filter = Plan::new(10_000, None, true, |index| index % 5 != 0, 10, INDEX, true);
+		assert_eq!(post_filter.strategy, Strategy::PostFilter);
+		assert!((post_filter.selectivity - 0.8).abs() < 0.1);
+		let candidates = post_filter.candidates.unwrap();
+		assert!((15..=25).contains(&candidates));
+
+		let filtered = plan(3, None, INDEX);
+		assert_eq!(filtered.strategy, Strategy::Filtered);
+		assert!((filtered.selectivity - 0.33).abs() < 0.1);
+
+		// few matching embeddings are scored directly
+		let brute_force = plan(50, None, INDEX);
+		assert_eq!(brute_force.strategy, Strategy::BruteForce);
+		assert!(brute_force.estimated_matches <= 1000);
+	}
+
+	#[test]
+	fn depends_on_available_access() {
+		let no_index = Access {
+			index: false,
+			quantizer: false,
+			vectors: true,
+		};
+		assert_eq!(plan(1, None, no_index).strategy, Strategy::BruteForce);
+
+		// quantized vectors have to be searched, if the original ones were dropped
+		let quantized = Access {
+			index: false,
+			quantizer: true,
+			vectors: false,
+		};
+		assert_eq!(plan(50, None, quantized).strategy, Strategy::Filtered);
+		// only an index can be searched without the filter
+		let quantized_with_vectors = Access {
+			vectors: true,
+			..quantized
+		};
+		let plan = Plan::new(
+			10_000,
+			None,
+			true,
+			|index| index % 5 != 0,
+			10,
+			quantized_with_vectors,
+			false,
+		);
+		assert_eq!(plan.strategy, Strategy::Filtered);
+		assert!(!plan.trained);
+	}
+
+	#[test]
+	fn counts_candidates_of_metadata_index() {
+		let candidates: Vec<usize> = (0..10_000).step_by(2).collect();
+		let plan = plan(4, Some(&candidates), INDEX);
+		assert!(plan.metadata_index);
+		// all candidates are checked, if there are few of them
+		let few: Vec<usize> = (0..200).collect();
+		let exact = Plan::new(
+			10_000,
+			Some(&few),
+			true,
+			|index| index % 4 == 0,
+			10,
+			INDEX,
+			true,
+		);
+		assert_eq!(exact.estimated_matches, 50);
+		assert_eq!(exact.strategy, Strategy::BruteForce);
+		assert!((plan.selectivity - 0.25).abs() < 0.1);
+	}
+}
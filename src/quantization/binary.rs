@@ -0,0 +1,119 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	index::Vectors,
+	similarity::{binarize, hamming_distance, top_k, ScoreIndex},
+};
+
+/// Encodes each value of a vector by its sign bit, packed to 64-bit words. The codes need
+/// no training and the original vectors are always kept for re-ranking the candidates.
+/// Codes are stored by the positions of the embeddings in the collection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BinaryQuantizer {
+	/// Number of words per vector
+	words: usize,
+	/// Codes of all vectors, `words` words per vector
+	codes: Vec<u64>,
+}
+
+impl BinaryQuantizer {
+	pub const fn new(dimension: usize) -> Self {
+		Self {
+			words: dimension.div_ceil(u64::BITS as usize),
+			codes: Vec::new(),
+		}
+	}
+
+	/// Size of the codes in bytes
+	pub const fn size(&self) -> usize {
+		self.codes.len() * size_of::<u64>()
+	}
+
+	pub fn clear(&mut self) {
+		self.codes.clear();
+	}
+
+	/// Encodes all vectors again
+	pub fn train(&mut self, vectors: &impl Vectors) {
+		tracing::debug!("Binarizing {} vectors", vectors.len());
+		self.codes = (0..vectors.len())
+			.into_par_iter()
+			.flat_map_iter(|index| binarize(vectors.vector(index)))
+			.collect();
+	}
+
+	pub fn insert(&mut self, vector: &[f32]) {
+		self.codes.extend(binarize(vector));
+	}
+
+	/// Encodes the vector again, which replaced the one at `index`
+	pub fn update(&mut self, index: usize, vector: &[f32]) {
+		let range = index * self.words..(index + 1) * self.words;
+		self.codes[range].copy_from_slice(&binarize(vector));
+	}
+
+	/// Drops the codes of the deleted vectors, keeping the others in the order of their slots
+	pub fn compact(&mut self, remap: &[Option<usize>]) {
+		self.codes = self
+			.codes
+			.chunks_exact(self.words)
+			.zip(remap)
+			.filter(|(_, slot)| slot.is_some())
+			.flat_map(|(codes, _)| codes.iter().copied())
+			.collect();
+	}
+
+	/// Scores all vectors accepted by the filter by the negative number of sign bits, which
+	/// differ from the query, and returns at most `k` of them
+	#[allow(clippy::cast_precision_loss)]
+	pub fn search(
+		&self,
+		query: &[f32],
+		k: usize,
+		accept: impl Fn(usize) -> bool + Sync,
+	) -> Vec<ScoreIndex> {
+		let query = binarize(query);
+
+		let scores = self
+			.codes
+			.par_chunks_exact(self.words)
+			.enumerate()
+			.filter(|(index, _)| accept(*index))
+			.map(|(index, codes)| ScoreIndex {
+				score: -(hamming_distance(&query, codes) as f32),
+				index,
+			})
+			.collect::<Vec<_>>();
+		top_k(scores, k)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn encodes_signs_of_values() {
+		let vectors = testing::vectors(100, 70, 1);
+		let mut bq = BinaryQuantizer::new(70);
+		bq.train(&vectors);
+		assert_eq!(bq.codes.len(), 100 * 2);
+		for index in 0..vectors.len() {
+			let codes = &bq.codes[index * 2..(index + 1) * 2];
+			for (bit, &value) in vectors.vector(index).iter().enumerate() {
+				assert_eq!(codes[bit / 64] >> (bit % 64) & 1 == 1, value > 0.0);
+			}
+			let found = bq.search(vectors.vector(index), 1, |_| true);
+			assert_eq!(found[0].index, index);
+			assert!(found[0].score.abs() <= f32::EPSILON);
+		}
+
+		bq.insert(vectors.vector(1));
+		let remap: Vec<Option<usize>> = (0..=100_usize).map(|index| index.checked_sub(1)).collect();
+		bq.compact(&remap);
+		assert_eq!(bq.codes.len(), 100 * 2);
+		assert_eq!(bq.codes[99 * 2..], bq.codes[0..2]);
+	}
+}
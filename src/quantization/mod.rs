@@ -0,0 +1,257 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	index::Vectors,
+	similarity::{Distance, ScoreIndex},
+};
+
+mod binary;
+mod product;
+mod scalar;
+
+pub use binary::BinaryQuantizer;
+pub use product::ProductQuantizer;
+pub use scalar::{Calibration, ScalarQuantizer};
+
+/// How many more candidates than requested are found in the quantized vectors
+/// to be re-ranked with the original vectors by default
+const DEFAULT_OVERSAMPLING: f32 = 4.0;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum QuantizationOptions {
+	/// Vectors split to `m` sub-vectors, each one encoded by one byte
+	Product {
+		/// Number of sub-vectors, which has to divide the dimension
+		m: usize,
+		/// Keep the original vectors for re-ranking the candidates
+		#[serde(default)]
+		rerank: bool,
+	},
+	/// Each value of a vector encoded by one byte
+	Scalar {
+		/// How the interval of the values is computed
+		#[serde(default)]
+		calibration: Calibration,
+		/// Keep the original vectors for re-ranking the candidates
+		#[serde(default)]
+		rerank: bool,
+	},
+	/// Each value of a vector encoded by its sign bit, re-ranked with the original vectors
+	Binary,
+}
+
+impl QuantizationOptions {
+	/// Product and scalar quantization approximate only the euclidean distance
+	/// and the dot product, the signs of the values preserve only the angles
+	/// between the vectors and the bits of binary vectors
+	pub const fn is_valid(&self, dimension: usize, distance: Distance) -> bool {
+		let approximable = matches!(
+			distance,
+			Distance::Euclidean | Distance::Cosine | Distance::DotProduct
+		);
+		match self {
+			Self::Product { m, .. } => approximable && *m >= 1 && dimension.is_multiple_of(*m),
+			Self::Scalar { .. } => approximable,
+			Self::Binary => matches!(
+				distance,
+				Distance::Cosine | Distance::DotProduct | Distance::Hamming
+			),
+		}
+	}
+
+	pub const fn keeps_vectors(&self) -> bool {
+		match self {
+			Self::Product { rerank, .. } | Self::Scalar { rerank, .. } => *rerank,
+			Self::Binary => true,
+		}
+	}
+}
+
+/// Compressed copy of the vectors of a collection, which can replace the original vectors
+/// once trained, unless they are kept for re-ranking the candidates
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum Quantizer {
+	#[default]
+	None,
+	Product(ProductQuantizer),
+	Scalar(ScalarQuantizer),
+	Binary(BinaryQuantizer),
+}
+
+impl Quantizer {
+	pub const fn new(
+		options: Option<QuantizationOptions>,
+		distance: Distance,
+		dimension: usize,
+	) -> Self {
+		match options {
+			None => Self::None,
+			Some(QuantizationOptions::Product { m, rerank }) => {
+				Self::Product(ProductQuantizer::new(m, rerank, distance, dimension))
+			},
+			Some(QuantizationOptions::Scalar {
+				calibration,
+				rerank,
+			}) => Self::Scalar(ScalarQuantizer::new(
+				calibration,
+				rerank,
+				distance,
+				dimension,
+			)),
+			Some(QuantizationOptions::Binary) => Self::Binary(BinaryQuantizer::new(dimension)),
+		}
+	}
+
+	pub const fn options(&self) -> Option<QuantizationOptions> {
+		match self {
+			Self::None => None,
+			Self::Product(pq) => Some(pq.options()),
+			Self::Scalar(sq) => Some(sq.options()),
+			Self::Binary(_) => Some(QuantizationOptions::Binary),
+		}
+	}
+
+	pub const fn is_trained(&self) -> bool {
+		match self {
+			Self::None => false,
+			Self::Product(pq) => pq.is_trained(),
+			Self::Scalar(sq) => sq.is_trained(),
+			Self::Binary(_) => true,
+		}
+	}
+
+	/// If the original vectors stay in the embeddings after the quantizer has been trained
+	pub const fn keeps_vectors(&self) -> bool {
+		match self {
+			Self::None | Self::Binary(_) => true,
+			Self::Product(pq) => pq.keeps_vectors(),
+			Self::Scalar(sq) => sq.keeps_vectors(),
+		}
+	}
+
+	/// Size of the quantized vectors in bytes
+	pub fn size(&self) -> Option<usize> {
+		match self {
+			Self::None => None,
+			Self::Product(pq) => Some(pq.size()),
+			Self::Scalar(sq) => Some(sq.size()),
+			Self::Binary(bq) => Some(bq.size()),
+		}
+	}
+
+	pub fn train(&mut self, vectors: &impl Vectors) {
+		match self {
+			Self::None => {},
+			Self::Product(pq) => pq.train(vectors),
+			Self::Scalar(sq) => sq.train(vectors),
+			Self::Binary(bq) => bq.train(vectors),
+		}
+	}
+
+	pub fn insert(&mut self, vector: &[f32]) {
+		match self {
+			Self::None => {},
+			Self::Product(pq) => pq.insert(vector),
+			Self::Scalar(sq) => sq.insert(vector),
+			Self::Binary(bq) => bq.insert(vector),
+		}
+	}
+
+	/// Encodes the vector again, which replaced the one at `index`
+	pub fn update(&mut self, index: usize, vector: &[f32]) {
+		match self {
+			Self::None => {},
+			Self::Product(pq) => pq.update(index, vector),
+			Self::Scalar(sq) => sq.update(index, vector),
+			Self::Binary(bq) => bq.update(index, vector),
+		}
+	}
+
+	/// Drops the codes of the deleted vectors, `remap` has the new slot of each vector
+	pub fn compact(&mut self, remap: &[Option<usize>]) {
+		match self {
+			Self::None => {},
+			Self::Product(pq) => pq.compact(remap),
+			Self::Scalar(sq) => sq.compact(remap),
+			Self::Binary(bq) => bq.compact(remap),
+		}
+	}
+
+	pub fn clear(&mut self) {
+		match self {
+			Self::None => {},
+			Self::Product(pq) => pq.clear(),
+			Self::Scalar(sq) => sq.clear(),
+			Self::Binary(bq) => bq.clear(),
+		}
+	}
+
+	/// Number of codes of each vector, which can replace the original vectors
+	pub const fn code_len(&self) -> usize {
+		match self {
+			Self::Product(pq) if pq.is_trained() => pq.code_len(),
+			Self::Scalar(sq) if sq.is_trained() => sq.code_len(),
+			_ => 0,
+		}
+	}
+
+	/// Codes of the vector at `index`, which replace the original vector dropped
+	/// after quantizing, empty if the quantizer can't replace them
+	pub fn codes(&self, index: usize) -> &[u8] {
+		match self {
+			Self::Product(pq) if pq.is_trained() => pq.codes(index),
+			Self::Scalar(sq) if sq.is_trained() => sq.codes(index),
+			_ => &[],
+		}
+	}
+
+	/// Sets the codes of the vector at `index` read from a segment, appending them
+	/// if it follows the last one, the codes have to be [`Self::code_len`] long
+	pub fn set_codes(&mut self, index: usize, codes: &[u8]) {
+		match self {
+			Self::Product(pq) if pq.is_trained() => pq.set_codes(index, codes),
+			Self::Scalar(sq) if sq.is_trained() => sq.set_codes(index, codes),
+			_ => {},
+		}
+	}
+
+	/// Reconstructs the vector at `index` if the quantizer has been trained
+	pub fn decode(&self, index: usize) -> Option<Vec<f32>> {
+		match self {
+			Self::Product(pq) if pq.is_trained() => Some(pq.decode(index)),
+			Self::Scalar(sq) if sq.is_trained() => Some(sq.decode(index)),
+			_ => None,
+		}
+	}
+
+	/// Returns the best candidates sorted from the most similar one, `oversampling` times
+	/// more than `k` if they should be re-ranked, or `None` if the quantizer has not been
+	/// trained
+	#[allow(
+		clippy::cast_possible_truncation,
+		clippy::cast_precision_loss,
+		clippy::cast_sign_loss
+	)]
+	pub fn search(
+		&self,
+		query: &[f32],
+		k: usize,
+		oversampling: Option<f32>,
+		accept: impl Fn(usize) -> bool + Sync,
+	) -> Option<Vec<ScoreIndex>> {
+		let candidates = if self.keeps_vectors() {
+			let oversampling = oversampling.unwrap_or(DEFAULT_OVERSAMPLING).max(1.0);
+			(k as f32 * oversampling).ceil() as usize
+		} else {
+			k
+		};
+		match self {
+			Self::Product(pq) if pq.is_trained() => Some(pq.search(query, candidates, accept)),
+			Self::Scalar(sq) if sq.is_trained() => Some(sq.search(query, candidates, accept)),
+			Self::Binary(bq) => Some(bq.search(query, candidates, accept)),
+			_ => None,
+		}
+	}
+}
@@ -0,0 +1,272 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::QuantizationOptions;
+use crate::{
+	index::Vectors,
+	kmeans, random, simd,
+	similarity::{top_k, Distance, ScoreIndex},
+};
+
+/// Number of centroids in the codebook of each sub-quantizer, addressable by one byte
+const CODEBOOK_SIZE: usize = 256;
+
+/// Maximum number of vectors per centroid sampled for the training
+const MAX_TRAINING_POINTS: usize = 32;
+
+/// Splits vectors to `m` sub-vectors and encodes each one by the position of the closest
+/// centroid in the codebook of its sub-space. Codes are stored by the positions of the
+/// embeddings in the collection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductQuantizer {
+	/// Number of sub-quantizers
+	m: usize,
+	/// If the original vectors are kept for re-ranking the candidates
+	rerank: bool,
+	/// Distance metric used for querying
+	distance: Distance,
+	/// Dimension of the sub-vectors
+	subdimension: usize,
+	/// Centroids of the sub-quantizers, flattened for each sub-space, empty until trained
+	codebooks: Vec<Vec<f32>>,
+	/// Codes of all vectors, `m` bytes per vector
+	codes: Vec<u8>,
+}
+
+impl ProductQuantizer {
+	pub const fn new(m: usize, rerank: bool, distance: Distance, dimension: usize) -> Self {
+		Self {
+			m,
+			rerank,
+			distance,
+			subdimension: dimension / m,
+			codebooks: Vec::new(),
+			codes: Vec::new(),
+		}
+	}
+
+	pub const fn options(&self) -> QuantizationOptions {
+		QuantizationOptions::Product {
+			m: self.m,
+			rerank: self.rerank,
+		}
+	}
+
+	pub const fn keeps_vectors(&self) -> bool {
+		self.rerank
+	}
+
+	pub const fn is_trained(&self) -> bool {
+		!self.codebooks.is_empty()
+	}
+
+	/// Size of the codes and codebooks in bytes
+	pub fn size(&self) -> usize {
+		let codebooks = self.codebooks.iter().map(Vec::len).sum::<usize>();
+		self.codes.len() + codebooks * size_of::<f32>()
+	}
+
+	pub fn clear(&mut self) {
+		self.codes.clear();
+	}
+
+	/// Trains the codebooks by k-means in each sub-space and encodes all vectors
+	pub fn train(&mut self, vectors: &impl Vectors) {
+		let len = vectors.len();
+		let mut seed = random::SEED;
+		let sample = random::sample(&mut seed, len, CODEBOOK_SIZE * MAX_TRAINING_POINTS);
+		tracing::debug!(
+			"Training {} codebooks from {} vectors",
+			self.m,
+			sample.len()
+		);
+
+		self.codebooks = (0..self.m)
+			.map(|part| {
+				let points = sample
+					.iter()
+					.map(|&index| self.subvector(vectors.vector(index), part))
+					.collect::<Vec<_>>();
+				kmeans::train(&points, CODEBOOK_SIZE, &negative_squared_l2, false).concat()
+			})
+			.collect();
+		if self.codebooks.iter().any(Vec::is_empty) {
+			self.codebooks.clear();
+		}
+
+		self.codes = (0..len)
+			.into_par_iter()
+			.flat_map_iter(|index| self.encode(vectors.vector(index)))
+			.collect();
+	}
+
+	pub fn insert(&mut self, vector: &[f32]) {
+		if self.is_trained() {
+			let codes = self.encode(vector);
+			self.codes.extend(codes);
+		}
+	}
+
+	/// Encodes the vector again, which replaced the one at `index`
+	pub fn update(&mut self, index: usize, vector: &[f32]) {
+		if self.is_trained() {
+			let codes = self.encode(vector);
+			let range = index * self.m..(index + 1) * self.m;
+			for (code, new) in self.codes[range].iter_mut().zip(codes) {
+				*code = new;
+			}
+		}
+	}
+
+	/// Number of codes of each vector
+	pub const fn code_len(&self) -> usize {
+		self.m
+	}
+
+	/// Codes of the vector at `index`
+	pub fn codes(&self, index: usize) -> &[u8] {
+		&self.codes[index * self.m..(index + 1) * self.m]
+	}
+
+	/// Sets the codes of the vector at `index`, appending them if it follows the last one
+	pub fn set_codes(&mut self, index: usize, codes: &[u8]) {
+		let start = index * self.m;
+		if start == self.codes.len() {
+			self.codes.extend_from_slice(codes);
+		} else {
+			self.codes[start..start + self.m].copy_from_slice(codes);
+		}
+	}
+
+	/// Drops the codes of the deleted vectors, keeping the others in the order of their slots
+	pub fn compact(&mut self, remap: &[Option<usize>]) {
+		if self.is_trained() {
+			self.codes = self
+				.codes
+				.chunks_exact(self.m)
+				.zip(remap)
+				.filter(|(_, slot)| slot.is_some())
+				.flat_map(|(codes, _)| codes.iter().copied())
+				.collect();
+		}
+	}
+
+	/// Reconstructs the vector at `index` from the centroids of its codes
+	pub fn decode(&self, index: usize) -> Vec<f32> {
+		self.codes[index * self.m..(index + 1) * self.m]
+			.iter()
+			.zip(&self.codebooks)
+			.flat_map(|(&code, codebook)| self.centroid(codebook, code))
+			.copied()
+			.collect()
+	}
+
+	/// Scores all vectors accepted by the filter using a table of distances between
+	/// the sub-vectors of the query and all centroids, and returns at most `k` of them
+	pub fn search(
+		&self,
+		query: &[f32],
+		k: usize,
+		accept: impl Fn(usize) -> bool + Sync,
+	) -> Vec<ScoreIndex> {
+		let table = self
+			.codebooks
+			.iter()
+			.enumerate()
+			.map(|(part, codebook)| {
+				let subquery = self.subvector(query, part);
+				codebook
+					.chunks_exact(self.subdimension)
+					.map(|centroid| self.partial_score(subquery, centroid))
+					.collect::<Vec<_>>()
+			})
+			.collect::<Vec<_>>();
+
+		let scores = self
+			.codes
+			.par_chunks_exact(self.m)
+			.enumerate()
+			.filter(|(index, _)| accept(*index))
+			.map(|(index, codes)| {
+				let sum = codes
+					.iter()
+					.zip(&table)
+					.map(|(&code, partial_scores)| partial_scores[code as usize])
+					.sum();
+				ScoreIndex {
+					score: self.final_score(sum),
+					index,
+				}
+			})
+			.collect::<Vec<_>>();
+		top_k(scores, k)
+	}
+
+	fn encode(&self, vector: &[f32]) -> Vec<u8> {
+		self.codebooks
+			.iter()
+			.enumerate()
+			.map(|(part, codebook)| {
+				let centroids = codebook.chunks_exact(self.subdimension);
+				let subvector = self.subvector(vector, part);
+				let code = kmeans::nearest(centroids, subvector, &negative_squared_l2);
+				u8::try_from(code).expect("codebook larger than a byte")
+			})
+			.collect()
+	}
+
+	fn subvector<'a>(&self, vector: &'a [f32], part: usize) -> &'a [f32] {
+		&vector[part * self.subdimension..(part + 1) * self.subdimension]
+	}
+
+	fn centroid<'a>(&self, codebook: &'a [f32], code: u8) -> &'a [f32] {
+		let start = code as usize * self.subdimension;
+		&codebook[start..start + self.subdimension]
+	}
+
+	/// Contribution of one sub-space to the score, which can be summed up
+	fn partial_score(&self, a: &[f32], b: &[f32]) -> f32 {
+		match self.distance {
+			Distance::Euclidean => -negative_squared_l2(a, b),
+			_ => a.iter().zip(b).fold(0.0, |acc, (x, y)| x.mul_add(*y, acc)),
+		}
+	}
+
+	/// Ranking score from the summed contributions, where the highest score is the best one
+	fn final_score(&self, sum: f32) -> f32 {
+		match self.distance {
+			Distance::Euclidean => -sum.max(0.0).sqrt(),
+			_ => sum,
+		}
+	}
+}
+
+fn negative_squared_l2(a: &[f32], b: &[f32]) -> f32 {
+	-simd::squared_l2(a, b)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn decodes_encoded_vectors() {
+		let vectors = testing::vectors(500, 8, 1);
+		let mut pq = ProductQuantizer::new(4, false, Distance::Cosine, 8);
+		pq.insert(vectors.vector(0));
+		assert!(!pq.is_trained());
+		pq.train(&vectors);
+		assert_eq!(pq.codes.len(), 500 * 4);
+		let error = testing::squared_error(&vectors, |index| pq.decode(index));
+		assert!(error < 0.01);
+
+		pq.insert(vectors.vector(1));
+		assert_eq!(pq.decode(500), pq.decode(1));
+		let last = pq.decode(500);
+		let remap: Vec<Option<usize>> = (0..=500_usize).map(|index| index.checked_sub(1)).collect();
+		pq.compact(&remap);
+		assert_eq!(pq.codes.len(), 500 * 4);
+		assert_eq!(pq.decode(499), last);
+	}
+}
@@ -0,0 +1,258 @@
+use rayon::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::QuantizationOptions;
+use crate::{
+	index::Vectors,
+	similarity::{get_quantized_distance_fn, quantize_query, top_k, Distance, ScoreIndex},
+};
+
+/// Largest code of a quantized value
+const MAX_CODE: f32 = 255.0;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Calibration {
+	/// Minimum and maximum of each dimension
+	#[default]
+	Dimension,
+	/// Minimum and maximum of all values
+	Global,
+}
+
+/// Encodes each value of a vector by one byte, mapping the interval between the minimum
+/// and the maximum value to 256 steps. Codes are stored by the positions of the embeddings
+/// in the collection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScalarQuantizer {
+	/// How the interval of the values is computed
+	calibration: Calibration,
+	/// If the original vectors are kept for re-ranking the candidates
+	rerank: bool,
+	/// Distance metric used for querying
+	distance: Distance,
+	/// Dimension of the vectors
+	dimension: usize,
+	/// Minimum value of each dimension, empty until trained
+	offsets: Vec<f32>,
+	/// Size of the step between two codes in each dimension, empty until trained
+	scales: Vec<f32>,
+	/// Codes of all vectors, one byte per dimension
+	codes: Vec<u8>,
+}
+
+impl ScalarQuantizer {
+	pub const fn new(
+		calibration: Calibration,
+		rerank: bool,
+		distance: Distance,
+		dimension: usize,
+	) -> Self {
+		Self {
+			calibration,
+			rerank,
+			distance,
+			dimension,
+			offsets: Vec::new(),
+			scales: Vec::new(),
+			codes: Vec::new(),
+		}
+	}
+
+	pub const fn options(&self) -> QuantizationOptions {
+		QuantizationOptions::Scalar {
+			calibration: self.calibration,
+			rerank: self.rerank,
+		}
+	}
+
+	pub const fn keeps_vectors(&self) -> bool {
+		self.rerank
+	}
+
+	pub const fn is_trained(&self) -> bool {
+		!self.scales.is_empty()
+	}
+
+	/// Size of the codes and calibration in bytes
+	pub const fn size(&self) -> usize {
+		self.codes.len() + (self.offsets.len() + self.scales.len()) * size_of::<f32>()
+	}
+
+	pub fn clear(&mut self) {
+		self.codes.clear();
+	}
+
+	/// Finds the intervals of the values and encodes all vectors
+	pub fn train(&mut self, vectors: &impl Vectors) {
+		let len = vectors.len();
+		tracing::debug!(
+			"Calibrating {} dimensions from {len} vectors",
+			self.dimension
+		);
+		if len == 0 {
+			self.offsets.clear();
+			self.scales.clear();
+			self.codes.clear();
+			return;
+		}
+
+		let mut minimums = vec![f32::INFINITY; self.dimension];
+		let mut maximums = vec![f32::NEG_INFINITY; self.dimension];
+		for index in 0..len {
+			for ((minimum, maximum), &value) in minimums
+				.iter_mut()
+				.zip(maximums.iter_mut())
+				.zip(vectors.vector(index))
+			{
+				*minimum = minimum.min(value);
+				*maximum = maximum.max(value);
+			}
+		}
+
+		if self.calibration == Calibration::Global {
+			let minimum = minimums.iter().copied().fold(f32::INFINITY, f32::min);
+			let maximum = maximums.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+			minimums.fill(minimum);
+			maximums.fill(maximum);
+		}
+
+		self.scales = minimums
+			.iter()
+			.zip(&maximums)
+			.map(|(minimum, maximum)| (maximum - minimum) / MAX_CODE)
+			.collect();
+		self.offsets = minimums;
+
+		self.codes = (0..len)
+			.into_par_iter()
+			.flat_map_iter(|index| self.encode(vectors.vector(index)))
+			.collect();
+	}
+
+	pub fn insert(&mut self, vector: &[f32]) {
+		if self.is_trained() {
+			let codes = self.encode(vector);
+			self.codes.extend(codes);
+		}
+	}
+
+	/// Encodes the vector again, which replaced the one at `index`
+	pub fn update(&mut self, index: usize, vector: &[f32]) {
+		if self.is_trained() {
+			let codes = self.encode(vector);
+			let range = index * self.dimension..(index + 1) * self.dimension;
+			for (code, new) in self.codes[range].iter_mut().zip(codes) {
+				*code = new;
+			}
+		}
+	}
+
+	/// Number of codes of each vector
+	pub const fn code_len(&self) -> usize {
+		self.dimension
+	}
+
+	/// Codes of the vector at `index`
+	pub fn codes(&self, index: usize) -> &[u8] {
+		&self.codes[index * self.dimension..(index + 1) * self.dimension]
+	}
+
+	/// Sets the codes of the vector at `index`, appending them if it follows the last one
+	pub fn set_codes(&mut self, index: usize, codes: &[u8]) {
+		let start = index * self.dimension;
+		if start == self.codes.len() {
+			self.codes.extend_from_slice(codes);
+		} else {
+			self.codes[start..start + self.dimension].copy_from_slice(codes);
+		}
+	}
+
+	/// Drops the codes of the deleted vectors, keeping the others in the order of their slots
+	pub fn compact(&mut self, remap: &[Option<usize>]) {
+		if self.is_trained() {
+			self.codes = self
+				.codes
+				.chunks_exact(self.dimension)
+				.zip(remap)
+				.filter(|(_, slot)| slot.is_some())
+				.flat_map(|(codes, _)| codes.iter().copied())
+				.collect();
+		}
+	}
+
+	/// Reconstructs the vector at `index` from its codes
+	pub fn decode(&self, index: usize) -> Vec<f32> {
+		self.codes[index * self.dimension..(index + 1) * self.dimension]
+			.iter()
+			.zip(self.offsets.iter().zip(&self.scales))
+			.map(|(&code, (offset, scale))| scale.mul_add(f32::from(code), *offset))
+			.collect()
+	}
+
+	/// Scores all vectors accepted by the filter using their codes and returns
+	/// at most `k` of them
+	pub fn search(
+		&self,
+		query: &[f32],
+		k: usize,
+		accept: impl Fn(usize) -> bool + Sync,
+	) -> Vec<ScoreIndex> {
+		let query = quantize_query(self.distance, query, &self.offsets, &self.scales);
+		let distance_fn = get_quantized_distance_fn(self.distance);
+
+		let scores = self
+			.codes
+			.par_chunks_exact(self.dimension)
+			.enumerate()
+			.filter(|(index, _)| accept(*index))
+			.map(|(index, codes)| ScoreIndex {
+				score: distance_fn(&query, codes),
+				index,
+			})
+			.collect::<Vec<_>>();
+		top_k(scores, k)
+	}
+
+	#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+	fn encode(&self, vector: &[f32]) -> Vec<u8> {
+		vector
+			.iter()
+			.zip(self.offsets.iter().zip(&self.scales))
+			.map(|(value, (offset, scale))| {
+				if *scale > 0.0 {
+					((value - offset) / scale).round().clamp(0.0, MAX_CODE) as u8
+				} else {
+					0
+				}
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn decodes_values_within_half_a_step() {
+		let vectors = testing::vectors(500, 8, 1);
+		for calibration in [Calibration::Dimension, Calibration::Global] {
+			let mut sq = ScalarQuantizer::new(calibration, false, Distance::Cosine, 8);
+			sq.train(&vectors);
+			assert_eq!(sq.codes.len(), 500 * 8);
+			for index in 0..vectors.len() {
+				for ((value, decoded), scale) in vectors
+					.vector(index)
+					.iter()
+					.zip(sq.decode(index))
+					.zip(&sq.scales)
+				{
+					assert!((value - decoded).abs() <= scale / 2.0 + f32::EPSILON);
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,38 @@
+//! Deterministic pseudo-random numbers for building the indexes, which have to be
+//! reproducible and don't need to be cryptographically secure
+
+/// Initial state of the generators
+pub const SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+/// Advances the state and returns the next number using splitmix64
+pub const fn next(state: &mut u64) -> u64 {
+	*state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+	let mut z = *state;
+	z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+	z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+	z ^ (z >> 31)
+}
+
+/// Returns a number in the interval (0, 1]
+#[allow(clippy::cast_precision_loss)]
+pub fn uniform(state: &mut u64) -> f64 {
+	((next(state) >> 11) as f64 + 1.0) / (1u64 << 53) as f64
+}
+
+/// Returns a number in the interval [0, bound)
+#[allow(clippy::cast_possible_truncation)]
+pub const fn below(state: &mut u64, bound: usize) -> usize {
+	(next(state) % bound as u64) as usize
+}
+
+/// Returns `count` distinct positions in the interval [0, len) by a partial shuffle
+pub fn sample(state: &mut u64, len: usize, count: usize) -> Vec<usize> {
+	let count = count.min(len);
+	let mut positions = (0..len).collect::<Vec<_>>();
+	for i in 0..count {
+		let j = i + below(state, len - i);
+		positions.swap(i, j);
+	}
+	positions.truncate(count);
+	positions
+}
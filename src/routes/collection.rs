@@ -9,11 +9,16 @@ use axum::{
 };
 use axum_jsonschema::Json;
 use schemars::JsonSchema;
-use std::{collections::HashMap, time::Instant};
+use std::time::Instant;
 
 use crate::{
-	db::{self, DbExtension, Embedding, Error as DbError, SimilarityResult},
+	db::{self, DbExtension, Embedding, Error as DbError, SimilarityResult, Summary, Upserted},
 	errors::HTTPError,
+	filter::Filter,
+	index::{IndexOptions, SearchParams},
+	metadata::{self, Metadata},
+	planner::Plan,
+	quantization::QuantizationOptions,
 	similarity::Distance,
 };
 
@@ -27,8 +32,14 @@ pub fn handler() -> ApiRouter {
 			.api_route("/:collection_name", post(query_collection))
 			.api_route("/:collection_name", get(get_collection_info))
 			.api_route("/:collection_name", delete(delete_collection))
+			.api_route("/:collection_name/index", post(rebuild_index))
 			.api_route("/:collection_name/embeddings", get(get_embeddings))
+			.api_route(
+				"/:collection_name/embeddings",
+				put(insert_many_into_collection),
+			)
 			.api_route("/:collection_name/embeddings", post(query_embeddings))
+			.api_route("/:collection_name/embeddings", patch(update_embeddings))
 			.api_route("/:collection_name/embeddings", delete(delete_embeddings))
 			.api_route(
 				"/:collection_name/embeddings/:embedding_id",
@@ -51,10 +62,7 @@ pub fn handler() -> ApiRouter {
 
 /// Get collection names
 async fn get_collections(Extension(db): DbExtension) -> Json<Vec<String>> {
-	let db = db.read().await;
-
 	let results = db.list();
-	drop(db);
 
 	Json(results)
 }
@@ -66,6 +74,12 @@ pub struct CollectionData {
 	pub dimension: usize,
 	/// Distance metric used for querying
 	pub distance: Distance,
+	/// Index used for querying, exact search by default
+	pub index: Option<IndexOptions>,
+	/// Compression of the vectors, none by default
+	pub quantization: Option<QuantizationOptions>,
+	/// Metadata fields indexed for filtering, none by default
+	pub metadata_index: Option<Vec<String>>,
 }
 
 /// Create a new collection
@@ -74,16 +88,31 @@ async fn create_collection(
 	Extension(db): DbExtension,
 	Json(body): Json<CollectionData>,
 ) -> Result<StatusCode, HTTPError> {
-	let mut db = db.write().await;
-
-	let create_result = db.create_collection(collection_name, body.dimension, body.distance);
-	drop(db);
+	let create_result = db
+		.create_collection(
+			collection_name,
+			body.dimension,
+			body.distance,
+			body.index.unwrap_or_default(),
+			body.quantization,
+			body.metadata_index.unwrap_or_default(),
+		)
+		.await;
 
 	match create_result {
 		Ok(_) => Ok(StatusCode::CREATED),
 		Err(db::Error::UniqueViolation) => {
 			Err(HTTPError::new("Collection already exists").with_status(StatusCode::CONFLICT))
 		},
+		Err(db::Error::InvalidIndex) => {
+			Err(HTTPError::new("Invalid index options").with_status(StatusCode::BAD_REQUEST))
+		},
+		Err(db::Error::InvalidQuantization) => {
+			Err(HTTPError::new("Invalid quantization options").with_status(StatusCode::BAD_REQUEST))
+		},
+		Err(db::Error::InvalidDistance) => {
+			Err(HTTPError::new("Invalid distance options").with_status(StatusCode::BAD_REQUEST))
+		},
 		Err(_) => Err(HTTPError::new("Couldn't create collection")),
 	}
 }
@@ -101,10 +130,7 @@ async fn rename_collection(
 	Extension(db): DbExtension,
 	Json(body): Json<CollectionUpdate>,
 ) -> Result<StatusCode, HTTPError> {
-	let mut db = db.write().await;
-
-	let create_result = db.rename_collection(&collection_name, body.name);
-	drop(db);
+	let create_result = db.rename_collection(&collection_name, body.name).await;
 
 	match create_result {
 		Ok(()) => Ok(StatusCode::NO_CONTENT),
@@ -123,9 +149,31 @@ struct QueryCollectionQuery {
 	/// Vector to query with
 	query: Vec<f32>,
 	/// Metadata to filter with
-	filter: Option<Vec<HashMap<String, String>>>,
+	filter: Option<Filter>,
 	/// Number of results to return
 	k: Option<usize>,
+	/// Number of candidates to consider when searching a graph index
+	ef: Option<usize>,
+	/// Number of posting lists to scan when searching an inverted file index
+	nprobe: Option<usize>,
+	/// How many times more candidates to find in quantized vectors for re-ranking
+	oversampling: Option<f32>,
+	/// Score all embeddings instead of using the index
+	exact: Option<bool>,
+	/// Report how the search was executed
+	explain: Option<bool>,
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+#[serde(untagged)]
+enum QueryCollectionResponse {
+	/// Best matches sorted from the most similar one
+	Results(Vec<SimilarityResult>),
+	/// Best matches with the plan of the search
+	Explained {
+		results: Vec<SimilarityResult>,
+		explain: Plan,
+	},
 }
 
 /// Query a collection
@@ -133,10 +181,10 @@ async fn query_collection(
 	Path(collection_name): Path<String>,
 	Extension(db): DbExtension,
 	Json(body): Json<QueryCollectionQuery>,
-) -> Result<Json<Vec<SimilarityResult>>, HTTPError> {
-	let db = db.read().await;
+) -> Result<Json<QueryCollectionResponse>, HTTPError> {
 	let collection = db
-		.get_collection(&collection_name)
+		.read_collection(&collection_name)
+		.await
 		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
 
 	if body.query.len() != collection.dimension {
@@ -144,27 +192,37 @@ async fn query_collection(
 	}
 
 	let instant = Instant::now();
-	let results = collection.get_by_metadata_and_similarity(
+	let (results, plan) = collection.get_by_metadata_and_similarity(
 		&body.filter.unwrap_or_default(),
 		&body.query,
 		body.k.unwrap_or(1),
+		SearchParams {
+			ef: body.ef,
+			nprobe: body.nprobe,
+			oversampling: body.oversampling,
+			exact: body.exact.unwrap_or_default(),
+		},
 	);
-	drop(db);
+	drop(collection);
 
 	tracing::trace!("Querying {collection_name} took {:?}", instant.elapsed());
-	Ok(Json(results))
+	if body.explain.unwrap_or_default() {
+		return Ok(Json(QueryCollectionResponse::Explained {
+			results,
+			explain: plan,
+		}));
+	}
+	Ok(Json(QueryCollectionResponse::Results(results)))
 }
 
 #[derive(Debug, serde::Serialize, JsonSchema)]
 struct CollectionInfo {
 	/// Name of the collection
 	name: String,
-	/// Dimension of the embeddings in the collection
-	dimension: usize,
-	/// Distance function used for the collection
-	distance: Distance,
-	/// Number of embeddings in the collection
-	embedding_count: usize,
+	#[serde(flatten)]
+	summary: Summary,
+	/// If the collection is loaded in memory
+	resident: bool,
 }
 
 /// Get collection info
@@ -173,16 +231,17 @@ async fn get_collection_info(
 	Path(collection_name): Path<String>,
 	Extension(db): DbExtension,
 ) -> Result<Json<CollectionInfo>, HTTPError> {
-	let db = db.read().await;
-	let collection = db
-		.get_collection(&collection_name)
+	// unloaded collections are described without loading them again, unless they
+	// haven't been loaded since the start
+	let (summary, resident) = db
+		.summary(&collection_name)
+		.await
 		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
 
 	Ok(Json(CollectionInfo {
 		name: collection_name,
-		distance: collection.distance,
-		dimension: collection.dimension,
-		embedding_count: collection.embeddings.len(),
+		resident,
+		summary,
 	}))
 }
 
@@ -191,10 +250,7 @@ async fn delete_collection(
 	Path(collection_name): Path<String>,
 	Extension(db): DbExtension,
 ) -> Result<StatusCode, HTTPError> {
-	let mut db = db.write().await;
-
-	let delete_result = db.delete_collection(&collection_name);
-	drop(db);
+	let delete_result = db.delete_collection(&collection_name).await;
 
 	match delete_result {
 		Ok(()) => Ok(StatusCode::NO_CONTENT),
@@ -205,32 +261,64 @@ async fn delete_collection(
 	}
 }
 
+/// Rebuild the index and retrain the quantization of a collection
+async fn rebuild_index(
+	Path(collection_name): Path<String>,
+	Extension(db): DbExtension,
+) -> Result<StatusCode, HTTPError> {
+	let instant = Instant::now();
+	let rebuild_result = db.rebuild_index(&collection_name).await;
+
+	tracing::trace!(
+		"Rebuilding index of {collection_name} took {:?}",
+		instant.elapsed()
+	);
+	match rebuild_result {
+		Ok(()) => Ok(StatusCode::NO_CONTENT),
+		Err(DbError::NotFound) => {
+			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't rebuild index")),
+	}
+}
+
 #[derive(Debug, serde::Deserialize, JsonSchema)]
 struct EmbeddingData {
 	/// Vector computed from a text chunk
 	vector: Vec<f32>,
 	/// Metadata about the source text
-	metadata: Option<HashMap<String, String>>,
+	metadata: Option<Metadata>,
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct InsertParams {
+	/// Replaces the vector and the metadata of an existing embedding instead of failing
+	upsert: Option<bool>,
 }
 
-/// Insert a vector into a collection
+/// Insert a vector into a collection, or replace an existing one
 async fn insert_into_collection(
 	Path((collection_name, embedding_id)): Path<(String, String)>,
+	Query(params): Query<InsertParams>,
 	Extension(db): DbExtension,
 	Json(embedding_data): Json<EmbeddingData>,
 ) -> Result<StatusCode, HTTPError> {
-	let mut db = db.write().await;
-
 	let embedding = Embedding {
 		id: embedding_id,
 		vector: embedding_data.vector,
 		metadata: embedding_data.metadata,
 	};
-	let insert_result = db.insert_into_collection(&collection_name, embedding);
-	drop(db);
+	let insert_result = db
+		.insert_into_collection(
+			&collection_name,
+			embedding,
+			params.upsert.unwrap_or_default(),
+		)
+		.await;
 
 	match insert_result {
-		Ok(()) => Ok(StatusCode::CREATED),
+		Ok(Upserted::Created) => Ok(StatusCode::CREATED),
+		Ok(Upserted::Updated) => Ok(StatusCode::NO_CONTENT),
 		Err(DbError::NotFound) => {
 			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
 		},
@@ -241,6 +329,83 @@ async fn insert_into_collection(
 			"The provided vector has the wrong dimension",
 		)
 		.with_status(StatusCode::BAD_REQUEST)),
+		Err(_) => Err(HTTPError::new("Couldn't insert vector")),
+	}
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct EmbeddingsData {
+	/// Embeddings to insert
+	embeddings: Vec<Embedding>,
+	/// Replace the vectors and the metadata of existing embeddings instead of failing
+	upsert: Option<bool>,
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+struct InsertResult {
+	/// Identifier of the embedding
+	id: String,
+	/// Outcome of the insertion
+	status: InsertStatus,
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum InsertStatus {
+	/// The embedding was inserted
+	Created,
+	/// The existing embedding was replaced
+	Updated,
+	/// The embedding already exists
+	Conflict,
+	/// The vector has the wrong dimension
+	DimensionMismatch,
+}
+
+/// Insert or replace many vectors in a collection
+async fn insert_many_into_collection(
+	Path(collection_name): Path<String>,
+	Extension(db): DbExtension,
+	Json(body): Json<EmbeddingsData>,
+) -> Result<Json<Vec<InsertResult>>, HTTPError> {
+	let ids = body
+		.embeddings
+		.iter()
+		.map(|embedding| embedding.id.clone())
+		.collect::<Vec<_>>();
+	let instant = Instant::now();
+	let insert_result = db
+		.insert_many_into_collection(
+			&collection_name,
+			body.embeddings,
+			body.upsert.unwrap_or_default(),
+		)
+		.await;
+
+	tracing::trace!(
+		"Inserting {} vectors to {collection_name} took {:?}",
+		ids.len(),
+		instant.elapsed()
+	);
+	match insert_result {
+		Ok(results) => Ok(Json(
+			ids.into_iter()
+				.zip(results)
+				.map(|(id, result)| InsertResult {
+					id,
+					status: match result {
+						Ok(Upserted::Created) => InsertStatus::Created,
+						Ok(Upserted::Updated) => InsertStatus::Updated,
+						Err(DbError::DimensionMismatch) => InsertStatus::DimensionMismatch,
+						Err(_) => InsertStatus::Conflict,
+					},
+				})
+				.collect(),
+		)),
+		Err(DbError::NotFound) => {
+			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't insert vectors")),
 	}
 }
 
@@ -249,13 +414,13 @@ async fn get_embeddings(
 	Path(collection_name): Path<String>,
 	Extension(db): DbExtension,
 ) -> Result<Json<Vec<String>>, HTTPError> {
-	let db = db.read().await;
 	let collection = db
-		.get_collection(&collection_name)
+		.read_collection(&collection_name)
+		.await
 		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
 
 	let results = collection.list();
-	drop(db);
+	drop(collection);
 
 	Ok(Json(results))
 }
@@ -269,7 +434,7 @@ struct EmbeddingParams {
 #[derive(Debug, serde::Deserialize, JsonSchema)]
 struct EmbeddingsQuery {
 	/// Metadata to filter with
-	filter: Vec<HashMap<String, String>>,
+	filter: Filter,
 	/// Number of results to return
 	k: Option<usize>,
 }
@@ -281,9 +446,9 @@ async fn query_embeddings(
 	Extension(db): DbExtension,
 	Json(body): Json<EmbeddingsQuery>,
 ) -> Result<Json<Vec<Embedding>>, HTTPError> {
-	let db = db.read().await;
 	let collection = db
-		.get_collection(&collection_name)
+		.read_collection(&collection_name)
+		.await
 		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
 
 	let instant = Instant::now();
@@ -292,7 +457,7 @@ async fn query_embeddings(
 		body.k.unwrap_or(1),
 		params.novector.unwrap_or_default(),
 	);
-	drop(db);
+	drop(collection);
 
 	tracing::trace!(
 		"Filtering embeddings from {collection_name} took {:?}",
@@ -304,7 +469,9 @@ async fn query_embeddings(
 #[derive(Debug, serde::Deserialize, JsonSchema)]
 struct EmbeddingsDeleteQuery {
 	/// Metadata to filter with
-	filter: Vec<HashMap<String, String>>,
+	filter: Filter,
+	/// Confirms deleting all embeddings by a filter matching any embedding
+	all: Option<bool>,
 }
 
 /// Delete embeddings in a collection
@@ -313,42 +480,118 @@ async fn delete_embeddings(
 	Extension(db): DbExtension,
 	Json(body): Json<EmbeddingsDeleteQuery>,
 ) -> Result<StatusCode, HTTPError> {
-	let mut db = db.write().await;
-	let collection = db
-		.get_collection_mut(&collection_name)
-		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+	check_match_all(&body.filter, body.all)?;
+
+	let delete_result = db.delete_by_metadata(&collection_name, &body.filter).await;
 
-	if collection.delete_by_metadata(&body.filter) {
-		collection.set_dirty();
+	match delete_result {
+		Ok(()) => Ok(StatusCode::NO_CONTENT),
+		Err(DbError::NotFound) => {
+			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't delete vectors")),
 	}
-	drop(db);
+}
 
-	Ok(StatusCode::NO_CONTENT)
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct EmbeddingsPatch {
+	/// Metadata to filter with
+	filter: Filter,
+	/// Merge patch applied to the metadata, null removes a field
+	metadata: Metadata,
+	/// Confirms updating all embeddings by a filter matching any embedding
+	all: Option<bool>,
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+struct PatchResult {
+	/// Number of updated embeddings
+	updated: usize,
+}
+
+/// Merge metadata into the embeddings matching a filter
+#[allow(clippy::significant_drop_tightening)]
+async fn update_embeddings(
+	Path(collection_name): Path<String>,
+	Extension(db): DbExtension,
+	Json(body): Json<EmbeddingsPatch>,
+) -> Result<Json<PatchResult>, HTTPError> {
+	check_match_all(&body.filter, body.all)?;
+
+	let update_result = db
+		.merge_metadata_by_filter(&collection_name, &body.filter, body.metadata)
+		.await;
+
+	match update_result {
+		Ok(updated) => Ok(Json(PatchResult { updated })),
+		Err(DbError::NotFound) => {
+			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't update metadata")),
+	}
+}
+
+/// Rejects a filter matching any embedding, unless modifying all embeddings was
+/// confirmed, so that an empty filter doesn't modify the whole collection by mistake
+fn check_match_all(filter: &Filter, all: Option<bool>) -> Result<(), HTTPError> {
+	if filter.matches_all() && !all.unwrap_or_default() {
+		return Err(HTTPError::new(
+			"The filter matches all embeddings, set all to true to confirm it",
+		)
+		.with_status(StatusCode::BAD_REQUEST));
+	}
+	Ok(())
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct UpdateParams {
+	/// Merges the metadata into the current one instead of replacing it
+	merge: Option<bool>,
 }
 
 #[derive(Debug, serde::Deserialize, JsonSchema)]
 struct EmbeddingsUpdate {
-	/// Metadata to update
-	metadata: Option<HashMap<String, String>>,
+	/// Vector to replace the current one, normalised like when inserting
+	vector: Option<Vec<f32>>,
+	/// Metadata to replace the current one, null removes it, missing keeps it
+	#[serde(default, deserialize_with = "metadata::deserialize_nullable")]
+	#[schemars(with = "Option<Metadata>")]
+	#[allow(clippy::option_option)]
+	metadata: Option<Option<Metadata>>,
 }
 
-/// Update metadata in an embedding
+/// Update the vector or metadata in an embedding
 #[allow(clippy::significant_drop_tightening)]
 async fn update_embedding(
 	Path((collection_name, embedding_id)): Path<(String, String)>,
+	Query(params): Query<UpdateParams>,
 	Extension(db): DbExtension,
 	Json(body): Json<EmbeddingsUpdate>,
 ) -> Result<StatusCode, HTTPError> {
-	let mut db = db.write().await;
-	let collection = db
-		.get_collection_mut(&collection_name)
-		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+	if !db.contains(&collection_name) {
+		return Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND));
+	}
 
-	if collection.update_metadata(&embedding_id, body.metadata) {
-		collection.set_dirty();
-		Ok(StatusCode::NO_CONTENT)
-	} else {
-		Err(HTTPError::new("Embedding not found").with_status(StatusCode::NOT_FOUND))
+	let update_result = db
+		.update_embedding(
+			&collection_name,
+			&embedding_id,
+			body.vector,
+			body.metadata,
+			params.merge.unwrap_or_default(),
+		)
+		.await;
+
+	match update_result {
+		Ok(()) => Ok(StatusCode::NO_CONTENT),
+		Err(DbError::NotFound) => {
+			Err(HTTPError::new("Embedding not found").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(DbError::DimensionMismatch) => Err(HTTPError::new(
+			"The provided vector has the wrong dimension",
+		)
+		.with_status(StatusCode::BAD_REQUEST)),
+		Err(_) => Err(HTTPError::new("Couldn't update vector")),
 	}
 }
 
@@ -359,22 +602,16 @@ async fn get_embedding(
 	Query(params): Query<EmbeddingParams>,
 	Extension(db): DbExtension,
 ) -> Result<Json<Embedding>, HTTPError> {
-	let db = db.read().await;
 	let collection = db
-		.get_collection(&collection_name)
+		.read_collection(&collection_name)
+		.await
 		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
 
 	let embedding = collection
-		.get(&embedding_id)
+		.get(&embedding_id, params.novector.unwrap_or_default())
 		.ok_or_else(|| HTTPError::new("Embedding not found").with_status(StatusCode::NOT_FOUND))?;
 
-	if params.novector.unwrap_or_default() {
-		let mut clone = embedding.clone();
-		clone.vector.clear();
-		Ok(Json(clone))
-	} else {
-		Ok(Json(embedding.to_owned()))
-	}
+	Ok(Json(embedding))
 }
 
 /// Delete an embedding from a collection
@@ -383,17 +620,15 @@ async fn delete_embedding(
 	Path((collection_name, embedding_id)): Path<(String, String)>,
 	Extension(db): DbExtension,
 ) -> Result<StatusCode, HTTPError> {
-	let mut db = db.write().await;
-	let collection = db
-		.get_collection_mut(&collection_name)
-		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+	if !db.contains(&collection_name) {
+		return Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND));
+	}
 
-	let delete_result = collection.delete(&embedding_id);
+	let delete_result = db.delete_embedding(&collection_name, &embedding_id).await;
 
-	if delete_result {
-		collection.set_dirty();
-		Ok(StatusCode::NO_CONTENT)
-	} else {
-		Err(HTTPError::new("Embedding not found").with_status(StatusCode::NOT_FOUND))
+	match delete_result {
+		Ok(true) => Ok(StatusCode::NO_CONTENT),
+		Ok(false) => Err(HTTPError::new("Embedding not found").with_status(StatusCode::NOT_FOUND)),
+		Err(_) => Err(HTTPError::new("Couldn't delete vector")),
 	}
 }
@@ -15,6 +15,7 @@ async fn openapi_schema(Extension(openapi): Extension<OpenApi>) -> Json<OpenApi>
 	Json(openapi)
 }
 
+#[allow(clippy::literal_string_with_formatting_args)]
 async fn swagger() -> Html<String> {
 	Html(SWAGGER_UI_TEMPLATE.replace("{:spec_url}", "/openapi.json"))
 }
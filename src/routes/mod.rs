@@ -2,6 +2,7 @@ use aide::axum::ApiRouter;
 
 mod collection;
 mod docs;
+mod snapshot;
 mod system;
 
 pub fn handler() -> ApiRouter {
@@ -9,4 +10,5 @@ pub fn handler() -> ApiRouter {
 		.merge(docs::handler())
 		.merge(system::handler())
 		.merge(collection::handler())
+		.merge(snapshot::handler())
 }
@@ -0,0 +1,91 @@
+use aide::axum::{
+	routing::{delete, get, post},
+	ApiRouter,
+};
+use axum::{extract::Path, http::StatusCode, Extension};
+use axum_jsonschema::Json;
+use schemars::JsonSchema;
+use std::time::Instant;
+
+use crate::{
+	db::{self, DbExtension, STORE_PATH},
+	errors::HTTPError,
+	snapshot::{self, Snapshot},
+};
+
+pub fn handler() -> ApiRouter {
+	ApiRouter::new().nest(
+		"/snapshots",
+		ApiRouter::new()
+			.api_route("/", get(get_snapshots))
+			.api_route("/", post(create_snapshot))
+			.api_route("/:snapshot_id", delete(delete_snapshot))
+			.api_route("/:snapshot_id/restore", post(restore_snapshot)),
+	)
+}
+
+/// Get snapshots
+async fn get_snapshots() -> Result<Json<Vec<Snapshot>>, HTTPError> {
+	snapshot::list(&STORE_PATH)
+		.map(Json)
+		.map_err(|_| HTTPError::new("Couldn't list snapshots"))
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct SnapshotOptions {
+	/// Names of the collections to include, all collections by default
+	collections: Option<Vec<String>>,
+}
+
+/// Save the collections and take a snapshot of them
+async fn create_snapshot(
+	Extension(db): DbExtension,
+	Json(options): Json<SnapshotOptions>,
+) -> Result<Json<Snapshot>, HTTPError> {
+	if let Some(names) = &options.collections {
+		if names.iter().any(|name| !db.contains(name)) {
+			return Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND));
+		}
+	}
+
+	let instant = Instant::now();
+	let snapshot_result = db.create_snapshot(options.collections).await;
+
+	tracing::trace!("Taking snapshot took {:?}", instant.elapsed());
+	snapshot_result
+		.map(Json)
+		.map_err(|_| HTTPError::new("Couldn't create snapshot"))
+}
+
+/// Replace the collections by the ones in a snapshot, other collections are kept
+async fn restore_snapshot(
+	Path(snapshot_id): Path<String>,
+	Extension(db): DbExtension,
+) -> Result<StatusCode, HTTPError> {
+	let snapshot = match snapshot::get(&STORE_PATH, &snapshot_id) {
+		Ok(Some(snapshot)) => snapshot,
+		Ok(None) => {
+			return Err(HTTPError::new("Snapshot not found").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(_) => return Err(HTTPError::new("Couldn't read snapshot")),
+	};
+	// loading the collections doesn't need to block the database
+	let collections =
+		db::load_snapshot(&snapshot).map_err(|_| HTTPError::new("Couldn't load snapshot"))?;
+
+	let restore_result = db.restore_snapshot(collections).await;
+
+	match restore_result {
+		Ok(()) => Ok(StatusCode::NO_CONTENT),
+		Err(_) => Err(HTTPError::new("Couldn't restore snapshot")),
+	}
+}
+
+/// Delete a snapshot
+async fn delete_snapshot(Path(snapshot_id): Path<String>) -> Result<StatusCode, HTTPError> {
+	match snapshot::delete(&STORE_PATH, &snapshot_id) {
+		Ok(true) => Ok(StatusCode::NO_CONTENT),
+		Ok(false) => Err(HTTPError::new("Snapshot not found").with_status(StatusCode::NOT_FOUND)),
+		Err(_) => Err(HTTPError::new("Couldn't delete snapshot")),
+	}
+}
@@ -2,11 +2,11 @@ use aide::axum::{
 	routing::{get, post},
 	ApiRouter,
 };
-use axum::{http::StatusCode, Extension};
+use axum::http::StatusCode;
 use axum_jsonschema::Json;
 use schemars::JsonSchema;
 
-use crate::{db::DbExtension, shutdown};
+use crate::shutdown;
 
 pub fn handler() -> ApiRouter {
 	ApiRouter::new()
@@ -48,10 +48,7 @@ pub async fn trigger_ping() -> StatusCode {
 	StatusCode::NO_CONTENT
 }
 
-pub async fn trigger_shutdown(Extension(db): DbExtension) -> StatusCode {
-	let db = db.read().await;
-	drop(db);
-
+pub async fn trigger_shutdown() -> StatusCode {
 	shutdown::trigger();
 
 	StatusCode::NO_CONTENT
@@ -0,0 +1,236 @@
+//! Segments of the collections in the store. The file of a collection lists its
+//! segments and the deleted slots, the segments are files in `._segments` with the
+//! records and the vectors of the slots written by one saving, which are never
+//! modified. Saving writes only the slots modified since the last saving as a new
+//! segment, so that it takes time by the size of the changes and not by the size
+//! of the collection. The index and the quantized vectors are written to a
+//! checkpoint only when the segments are merged and the segments following the
+//! checkpoint are replayed to them when the collection is loaded, only the last
+//! version of each slot and only if its vector was modified.
+
+use serde::{Deserialize, Serialize};
+use std::{
+	fs, io,
+	ops::Range,
+	path::{Path, PathBuf},
+};
+use url_escape::encode_component;
+
+use crate::store;
+
+/// Directory with the segments of the collections, one subdirectory for each
+const SEGMENTS_DIR: &str = "._segments";
+/// Segments are merged when a collection has more of them
+const MAX_SEGMENTS: usize = 8;
+/// The checkpoint is written again when the segments following it have a bigger
+/// fraction of all slots
+const CHECKPOINT_RATIO: f32 = 0.25;
+
+/// Segment file of a collection
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Segment {
+	/// Identifier of the segment, newer segments have bigger ones
+	pub id: u64,
+	/// Number of slots written in the segment
+	pub len: usize,
+	/// Size of the content of the segment file in bytes
+	pub size: usize,
+}
+
+/// Slots written in a segment file, which precede their records
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Slots {
+	pub slots: Vec<usize>,
+	/// If the vector of each slot was modified, the slots with only their metadata
+	/// modified aren't replayed to the index and the quantized vectors
+	pub vectors: Vec<bool>,
+}
+
+impl Slots {
+	pub fn iter(&self) -> impl Iterator<Item = (usize, bool)> + '_ {
+		self.slots.iter().copied().zip(self.vectors.iter().copied())
+	}
+}
+
+impl FromIterator<(usize, bool)> for Slots {
+	fn from_iter<I: IntoIterator<Item = (usize, bool)>>(iter: I) -> Self {
+		let (slots, vectors) = iter.into_iter().unzip();
+		Self { slots, vectors }
+	}
+}
+
+/// Checkpoint file of a collection with the index and the quantized vectors of the
+/// slots written in the segments up to the one with the same identifier
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+	pub id: u64,
+	/// Size of the content of the checkpoint file in bytes
+	pub size: usize,
+}
+
+/// Returns the directory with the segments of the collection
+pub fn dir(store_path: &Path, name: &str) -> PathBuf {
+	store_path
+		.join(SEGMENTS_DIR)
+		.join(encode_component(name).to_string())
+}
+
+pub fn segment_path(dir: &Path, id: u64) -> PathBuf {
+	dir.join(format!("{id}.seg"))
+}
+
+pub fn checkpoint_path(dir: &Path, id: u64) -> PathBuf {
+	dir.join(format!("{id}.idx"))
+}
+
+/// Returns the identifier following the ones of the files in the directory, so that
+/// a new file doesn't replace a file of a collection deleted with the same name
+pub fn next_id(dir: &Path) -> io::Result<u64> {
+	let mut next = 1;
+	for entry in fs::read_dir(dir)? {
+		let entry_name = entry?.file_name();
+		let id = entry_name
+			.to_str()
+			.and_then(|file_name| file_name.split_once('.'))
+			.and_then(|(id, _)| id.parse::<u64>().ok());
+		if let Some(id) = id {
+			next = next.max(id + 1);
+		}
+	}
+	Ok(next)
+}
+
+/// Removes the files, which the collection file doesn't refer to, left behind by
+/// merged segments, by a previous collection with the same name or by an interrupted
+/// saving
+pub fn remove_unused(dir: &Path, segments: &[Segment], checkpoint: Checkpoint) -> io::Result<()> {
+	let mut used: Vec<PathBuf> = segments
+		.iter()
+		.map(|segment| segment_path(dir, segment.id))
+		.collect();
+	used.push(checkpoint_path(dir, checkpoint.id));
+	for entry in fs::read_dir(dir)? {
+		let entry = entry?;
+		let path = entry.path();
+		if used.contains(&path) {
+			continue;
+		}
+		tracing::debug!("Removing unused file {}", path.display());
+		if entry.file_type()?.is_dir() {
+			fs::remove_dir_all(path)?;
+		} else {
+			fs::remove_file(path)?;
+		}
+	}
+	Ok(())
+}
+
+/// Removes the temporary files, which were left behind in the directories of the
+/// segments by an interrupted saving
+pub fn clean(store_path: &Path) -> io::Result<()> {
+	let segments_path = store_path.join(SEGMENTS_DIR);
+	if !segments_path.exists() {
+		return Ok(());
+	}
+	for entry in fs::read_dir(segments_path)? {
+		let entry = entry?;
+		if entry.file_type()?.is_dir() {
+			store::clean(&entry.path())?;
+		}
+	}
+	Ok(())
+}
+
+/// Removes the segments of a deleted collection
+pub fn remove(dir: &Path) -> io::Result<()> {
+	match fs::remove_dir_all(dir) {
+		Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+		_ => Ok(()),
+	}
+}
+
+/// Moves the segments of a collection, which couldn't be loaded, aside
+pub fn quarantine(dir: &Path) -> io::Result<()> {
+	if !dir.exists() {
+		return Ok(());
+	}
+	let quarantine_path = store::quarantine_path(dir)?;
+	remove(&quarantine_path)?;
+	fs::rename(dir, quarantine_path)
+}
+
+/// Links the segments of a collection to another directory, the files can be
+/// linked, because they are never modified
+pub fn link(source: &Path, target: &Path) -> io::Result<()> {
+	if !source.exists() {
+		return Ok(());
+	}
+	fs::create_dir_all(target)?;
+	for entry in fs::read_dir(source)? {
+		let entry = entry?;
+		if entry.file_type()?.is_dir() {
+			continue;
+		}
+		let target = target.join(entry.file_name());
+		// copying is needed only by file systems without hard links
+		if fs::hard_link(entry.path(), &target).is_err() {
+			fs::copy(entry.path(), &target)?;
+		}
+	}
+	Ok(())
+}
+
+/// Returns the newest segments, which should be merged to one. Segments are merged
+/// when there are too many of them, starting with the newest one and adding older
+/// ones as long as they aren't bigger than the merged ones together, so that a big
+/// segment is written again only when the small ones have grown to its size.
+pub fn merge_range(segments: &[Segment]) -> Option<Range<usize>> {
+	if segments.len() <= MAX_SEGMENTS {
+		return None;
+	}
+	let end = segments.len();
+	let mut start = end - 1;
+	let mut len = segments[start].len;
+	while start > 0 && (end - start < 2 || segments[start - 1].len <= len) {
+		start -= 1;
+		len += segments[start].len;
+	}
+	Some(start..end)
+}
+
+/// If the segments following the checkpoint have too many slots for replaying them
+/// to the index and the quantized vectors when the collection is loaded
+#[allow(clippy::cast_precision_loss)]
+pub fn needs_checkpoint(segments: &[Segment], checkpoint: Checkpoint, len: usize) -> bool {
+	let replayed: usize = segments
+		.iter()
+		.filter(|segment| segment.id > checkpoint.id)
+		.map(|segment| segment.len)
+		.sum();
+	replayed as f32 > len as f32 * CHECKPOINT_RATIO
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn segments(lens: &[usize]) -> Vec<Segment> {
+		lens.iter()
+			.zip(1..)
+			.map(|(&len, id)| Segment { id, len, size: 0 })
+			.collect()
+	}
+
+	#[test]
+	fn merges_small_segments() {
+		assert_eq!(merge_range(&segments(&[100, 10, 10])), None);
+		assert_eq!(
+			merge_range(&segments(&[1000, 10, 10, 10, 10, 10, 10, 10, 10])),
+			Some(1..9)
+		);
+		assert_eq!(
+			merge_range(&segments(&[256, 128, 64, 32, 16, 8, 4, 2, 1])),
+			Some(7..9)
+		);
+	}
+}
@@ -1,6 +1,7 @@
 use aide::openapi::{self, OpenApi};
 use anyhow::Result;
 use axum::{
+	extract::DefaultBodyLimit,
 	http::{header::CONTENT_TYPE, Method},
 	Extension,
 };
@@ -17,12 +18,20 @@ use tower_http::{
 	CompressionLevel,
 };
 
-use crate::{db, routes, shutdown};
+use crate::{db, routes, shutdown, wal::Durability};
 
 pub async fn start() -> Result<()> {
-	let db = db::from_store()?;
+	let durability = env::var("LITEVEC_DURABILITY").map_or(Ok(Durability::Batch), |v| v.parse())?;
+	let memory_budget = env::var("LITEVEC_MEMORY_BUDGET")
+		.ok()
+		.map(|v| v.parse())
+		.transpose()?;
+	let db = db::from_store(durability, memory_budget).await?;
 	let duration = env::var("LITEVEC_AUTOSAVE_INTERVAL").map_or(Ok(10), |v| v.parse())?;
 	db::autosave(Arc::clone(&db), duration);
+	if durability == Durability::Batch {
+		db::sync_log(Arc::clone(&db));
+	}
 
 	let mut openapi = OpenApi {
 		info: openapi::Info {
@@ -42,7 +51,13 @@ pub async fn start() -> Result<()> {
 
 	let maxage = env::var("LITEVEC_CORS_MAXAGE").map_or(Ok(86_400), |v| v.parse())?;
 	let cors = CorsLayer::new()
-		.allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+		.allow_methods([
+			Method::GET,
+			Method::POST,
+			Method::PUT,
+			Method::PATCH,
+			Method::DELETE,
+		])
 		.allow_headers([CONTENT_TYPE])
 		.max_age(Duration::from_secs(maxage))
 		.allow_origin(Any);
@@ -55,6 +70,8 @@ pub async fn start() -> Result<()> {
 		.layer(Extension(openapi))
 		.layer(Extension(db))
 		.layer(TimeoutLayer::new(Duration::from_secs(timeout)))
+		// the payload limit replaces the default limit of the JSON extractor
+		.layer(DefaultBodyLimit::disable())
 		.layer(RequestBodyLimitLayer::new(payload_limit))
 		.layer(ValidateRequestHeaderLayer::accept("application/json"))
 		.layer(RequestDecompressionLayer::new())
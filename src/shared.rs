@@ -0,0 +1,74 @@
+//! Parts of a collection shared by its versions. A writer copies only the parts,
+//! which it modifies, if a query is still using the previous version of the
+//! collection, instead of copying the whole collection.
+
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+	ops::{Deref, DerefMut},
+	sync::Arc,
+};
+
+#[derive(Debug, Default)]
+pub struct Shared<T>(Arc<T>);
+
+impl<T> Shared<T> {
+	/// If both versions of the collection use the same part
+	#[cfg(test)]
+	pub fn ptr_eq(&self, other: &Self) -> bool {
+		Arc::ptr_eq(&self.0, &other.0)
+	}
+}
+
+impl<T> Clone for Shared<T> {
+	fn clone(&self) -> Self {
+		Self(Arc::clone(&self.0))
+	}
+}
+
+impl<T> From<T> for Shared<T> {
+	fn from(value: T) -> Self {
+		Self(Arc::new(value))
+	}
+}
+
+impl<T> Deref for Shared<T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		&self.0
+	}
+}
+
+impl<T: Clone> DerefMut for Shared<T> {
+	/// Copies the part first, if another version of the collection uses it
+	fn deref_mut(&mut self) -> &mut T {
+		Arc::make_mut(&mut self.0)
+	}
+}
+
+impl<T: Serialize> Serialize for Shared<T> {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		self.0.serialize(serializer)
+	}
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Shared<T> {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		T::deserialize(deserializer).map(Self::from)
+	}
+}
+
+impl<T: JsonSchema> JsonSchema for Shared<T> {
+	fn is_referenceable() -> bool {
+		T::is_referenceable()
+	}
+
+	fn schema_name() -> String {
+		T::schema_name()
+	}
+
+	fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+		T::json_schema(gen)
+	}
+}
@@ -1,11 +1,8 @@
 use axum_server::Handle;
-use lazy_static::lazy_static;
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::LazyLock};
 use tokio::{signal, time::Duration};
 
-lazy_static! {
-	static ref HANDLE: Handle = Handle::new();
-}
+static HANDLE: LazyLock<Handle> = LazyLock::new(Handle::new);
 
 pub fn handle() -> Handle {
 	HANDLE.clone()
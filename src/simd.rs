@@ -0,0 +1,414 @@
+//! Vectorised kernels for the distance metrics, chosen once by the features of the CPU,
+//! with a scalar fallback for the other platforms
+
+use std::sync::LazyLock;
+
+/// Kernel computing a sum over the pairs of values of two vectors. Only the values
+/// of the shorter vector and the matching values of the longer one are used.
+type Kernel = unsafe fn(&[f32], &[f32]) -> f32;
+
+struct Kernels {
+	/// Name of the instruction set for logging
+	name: &'static str,
+	dot: Kernel,
+	squared_l2: Kernel,
+}
+
+static KERNELS: LazyLock<Kernels> = LazyLock::new(|| {
+	let kernels = available().swap_remove(0);
+	tracing::debug!("Computing distances by {} instructions", kernels.name);
+	kernels
+});
+
+/// Sum of the products of the values
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+	// SAFETY: the kernels were chosen by the features of the CPU
+	unsafe { (KERNELS.dot)(a, b) }
+}
+
+/// Sum of the squared differences of the values
+pub fn squared_l2(a: &[f32], b: &[f32]) -> f32 {
+	// SAFETY: the kernels were chosen by the features of the CPU
+	unsafe { (KERNELS.squared_l2)(a, b) }
+}
+
+/// Kernels supported by the CPU, sorted from the fastest one, ending with the scalar ones
+fn available() -> Vec<Kernels> {
+	let mut kernels = Vec::new();
+	#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+	{
+		if is_x86_feature_detected!("avx512f") {
+			kernels.push(Kernels {
+				name: "AVX-512",
+				dot: x86::dot_avx512,
+				squared_l2: x86::squared_l2_avx512,
+			});
+		}
+		if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+			kernels.push(Kernels {
+				name: "AVX2",
+				dot: x86::dot_avx2,
+				squared_l2: x86::squared_l2_avx2,
+			});
+		}
+		if is_x86_feature_detected!("sse") {
+			kernels.push(Kernels {
+				name: "SSE",
+				dot: x86::dot_sse,
+				squared_l2: x86::squared_l2_sse,
+			});
+		}
+	}
+	#[cfg(target_arch = "aarch64")]
+	{
+		if std::arch::is_aarch64_feature_detected!("neon") {
+			kernels.push(Kernels {
+				name: "NEON",
+				dot: aarch64::dot_neon,
+				squared_l2: aarch64::squared_l2_neon,
+			});
+		}
+	}
+	kernels.push(Kernels {
+		name: "scalar",
+		dot: scalar::dot,
+		squared_l2: scalar::squared_l2,
+	});
+	kernels
+}
+
+mod scalar {
+	pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+		a.iter().zip(b).fold(0.0, |acc, (x, y)| x.mul_add(*y, acc))
+	}
+
+	pub fn squared_l2(a: &[f32], b: &[f32]) -> f32 {
+		a.iter()
+			.zip(b)
+			.fold(0.0, |acc, (x, y)| (x - y).mul_add(x - y, acc))
+	}
+}
+
+/// Kernels processing two registers per iteration to hide the latency of the additions
+/// and the tail of the vectors by the scalar kernels
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+	#[cfg(target_arch = "x86")]
+	#[allow(clippy::wildcard_imports)]
+	use std::arch::x86::*;
+	#[cfg(target_arch = "x86_64")]
+	#[allow(clippy::wildcard_imports)]
+	use std::arch::x86_64::*;
+
+	use super::scalar;
+
+	#[target_feature(enable = "avx512f")]
+	pub unsafe fn dot_avx512(a: &[f32], b: &[f32]) -> f32 {
+		let len = a.len().min(b.len());
+		let (pa, pb) = (a.as_ptr(), b.as_ptr());
+		let mut sum0 = _mm512_setzero_ps();
+		let mut sum1 = _mm512_setzero_ps();
+		let mut i = 0;
+		while i + 32 <= len {
+			sum0 = _mm512_fmadd_ps(_mm512_loadu_ps(pa.add(i)), _mm512_loadu_ps(pb.add(i)), sum0);
+			sum1 = _mm512_fmadd_ps(
+				_mm512_loadu_ps(pa.add(i + 16)),
+				_mm512_loadu_ps(pb.add(i + 16)),
+				sum1,
+			);
+			i += 32;
+		}
+		if i < len {
+			// the remaining values are loaded by a mask, the others are zeros
+			let (low, high) = tail_masks(len - i);
+			let a0 = _mm512_maskz_loadu_ps(low, pa.add(i));
+			let b0 = _mm512_maskz_loadu_ps(low, pb.add(i));
+			sum0 = _mm512_fmadd_ps(a0, b0, sum0);
+			if high != 0 {
+				let a1 = _mm512_maskz_loadu_ps(high, pa.add(i + 16));
+				let b1 = _mm512_maskz_loadu_ps(high, pb.add(i + 16));
+				sum1 = _mm512_fmadd_ps(a1, b1, sum1);
+			}
+		}
+		_mm512_reduce_add_ps(_mm512_add_ps(sum0, sum1))
+	}
+
+	#[target_feature(enable = "avx512f")]
+	pub unsafe fn squared_l2_avx512(a: &[f32], b: &[f32]) -> f32 {
+		let len = a.len().min(b.len());
+		let (pa, pb) = (a.as_ptr(), b.as_ptr());
+		let mut sum0 = _mm512_setzero_ps();
+		let mut sum1 = _mm512_setzero_ps();
+		let mut i = 0;
+		while i + 32 <= len {
+			let d0 = _mm512_sub_ps(_mm512_loadu_ps(pa.add(i)), _mm512_loadu_ps(pb.add(i)));
+			let d1 = _mm512_sub_ps(
+				_mm512_loadu_ps(pa.add(i + 16)),
+				_mm512_loadu_ps(pb.add(i + 16)),
+			);
+			sum0 = _mm512_fmadd_ps(d0, d0, sum0);
+			sum1 = _mm512_fmadd_ps(d1, d1, sum1);
+			i += 32;
+		}
+		if i < len {
+			// the remaining values are loaded by a mask, the others are zeros
+			let (low, high) = tail_masks(len - i);
+			let d0 = _mm512_sub_ps(
+				_mm512_maskz_loadu_ps(low, pa.add(i)),
+				_mm512_maskz_loadu_ps(low, pb.add(i)),
+			);
+			sum0 = _mm512_fmadd_ps(d0, d0, sum0);
+			if high != 0 {
+				let d1 = _mm512_sub_ps(
+					_mm512_maskz_loadu_ps(high, pa.add(i + 16)),
+					_mm512_maskz_loadu_ps(high, pb.add(i + 16)),
+				);
+				sum1 = _mm512_fmadd_ps(d1, d1, sum1);
+			}
+		}
+		_mm512_reduce_add_ps(_mm512_add_ps(sum0, sum1))
+	}
+
+	#[target_feature(enable = "avx2,fma")]
+	pub unsafe fn dot_avx2(a: &[f32], b: &[f32]) -> f32 {
+		let len = a.len().min(b.len());
+		let (pa, pb) = (a.as_ptr(), b.as_ptr());
+		let mut sum0 = _mm256_setzero_ps();
+		let mut sum1 = _mm256_setzero_ps();
+		let mut i = 0;
+		while i + 16 <= len {
+			sum0 = _mm256_fmadd_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)), sum0);
+			sum1 = _mm256_fmadd_ps(
+				_mm256_loadu_ps(pa.add(i + 8)),
+				_mm256_loadu_ps(pb.add(i + 8)),
+				sum1,
+			);
+			i += 16;
+		}
+		if i + 8 <= len {
+			sum0 = _mm256_fmadd_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)), sum0);
+			i += 8;
+		}
+		sum_avx(_mm256_add_ps(sum0, sum1)) + scalar::dot(&a[i..len], &b[i..len])
+	}
+
+	#[target_feature(enable = "avx2,fma")]
+	pub unsafe fn squared_l2_avx2(a: &[f32], b: &[f32]) -> f32 {
+		let len = a.len().min(b.len());
+		let (pa, pb) = (a.as_ptr(), b.as_ptr());
+		let mut sum0 = _mm256_setzero_ps();
+		let mut sum1 = _mm256_setzero_ps();
+		let mut i = 0;
+		while i + 16 <= len {
+			let d0 = _mm256_sub_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)));
+			let d1 = _mm256_sub_ps(
+				_mm256_loadu_ps(pa.add(i + 8)),
+				_mm256_loadu_ps(pb.add(i + 8)),
+			);
+			sum0 = _mm256_fmadd_ps(d0, d0, sum0);
+			sum1 = _mm256_fmadd_ps(d1, d1, sum1);
+			i += 16;
+		}
+		if i + 8 <= len {
+			let d0 = _mm256_sub_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)));
+			sum0 = _mm256_fmadd_ps(d0, d0, sum0);
+			i += 8;
+		}
+		sum_avx(_mm256_add_ps(sum0, sum1)) + scalar::squared_l2(&a[i..len], &b[i..len])
+	}
+
+	#[target_feature(enable = "sse")]
+	pub unsafe fn dot_sse(a: &[f32], b: &[f32]) -> f32 {
+		let len = a.len().min(b.len());
+		let (pa, pb) = (a.as_ptr(), b.as_ptr());
+		let mut sum0 = _mm_setzero_ps();
+		let mut sum1 = _mm_setzero_ps();
+		let mut i = 0;
+		while i + 8 <= len {
+			sum0 = _mm_add_ps(
+				sum0,
+				_mm_mul_ps(_mm_loadu_ps(pa.add(i)), _mm_loadu_ps(pb.add(i))),
+			);
+			sum1 = _mm_add_ps(
+				sum1,
+				_mm_mul_ps(_mm_loadu_ps(pa.add(i + 4)), _mm_loadu_ps(pb.add(i + 4))),
+			);
+			i += 8;
+		}
+		if i + 4 <= len {
+			sum0 = _mm_add_ps(
+				sum0,
+				_mm_mul_ps(_mm_loadu_ps(pa.add(i)), _mm_loadu_ps(pb.add(i))),
+			);
+			i += 4;
+		}
+		sum_sse(_mm_add_ps(sum0, sum1)) + scalar::dot(&a[i..len], &b[i..len])
+	}
+
+	#[target_feature(enable = "sse")]
+	pub unsafe fn squared_l2_sse(a: &[f32], b: &[f32]) -> f32 {
+		let len = a.len().min(b.len());
+		let (pa, pb) = (a.as_ptr(), b.as_ptr());
+		let mut sum0 = _mm_setzero_ps();
+		let mut sum1 = _mm_setzero_ps();
+		let mut i = 0;
+		while i + 8 <= len {
+			let d0 = _mm_sub_ps(_mm_loadu_ps(pa.add(i)), _mm_loadu_ps(pb.add(i)));
+			let d1 = _mm_sub_ps(_mm_loadu_ps(pa.add(i + 4)), _mm_loadu_ps(pb.add(i + 4)));
+			sum0 = _mm_add_ps(sum0, _mm_mul_ps(d0, d0));
+			sum1 = _mm_add_ps(sum1, _mm_mul_ps(d1, d1));
+			i += 8;
+		}
+		if i + 4 <= len {
+			let d0 = _mm_sub_ps(_mm_loadu_ps(pa.add(i)), _mm_loadu_ps(pb.add(i)));
+			sum0 = _mm_add_ps(sum0, _mm_mul_ps(d0, d0));
+			i += 4;
+		}
+		sum_sse(_mm_add_ps(sum0, sum1)) + scalar::squared_l2(&a[i..len], &b[i..len])
+	}
+
+	/// Masks for loading the last `rest` values, less than 32, to two registers
+	const fn tail_masks(rest: usize) -> (__mmask16, __mmask16) {
+		let low = if rest >= 16 {
+			u16::MAX
+		} else {
+			!(u16::MAX << rest)
+		};
+		let high = if rest > 16 {
+			!(u16::MAX << (rest - 16))
+		} else {
+			0
+		};
+		(low, high)
+	}
+
+	#[target_feature(enable = "avx")]
+	unsafe fn sum_avx(sum: __m256) -> f32 {
+		sum_sse(_mm_add_ps(
+			_mm256_castps256_ps128(sum),
+			_mm256_extractf128_ps(sum, 1),
+		))
+	}
+
+	#[target_feature(enable = "sse")]
+	unsafe fn sum_sse(sum: __m128) -> f32 {
+		let pairs = _mm_add_ps(sum, _mm_movehl_ps(sum, sum));
+		_mm_cvtss_f32(_mm_add_ss(pairs, _mm_shuffle_ps(pairs, pairs, 1)))
+	}
+}
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64 {
+	#[allow(clippy::wildcard_imports)]
+	use std::arch::aarch64::*;
+
+	use super::scalar;
+
+	#[target_feature(enable = "neon")]
+	pub unsafe fn dot_neon(a: &[f32], b: &[f32]) -> f32 {
+		let len = a.len().min(b.len());
+		let (pa, pb) = (a.as_ptr(), b.as_ptr());
+		let mut sum0 = vdupq_n_f32(0.0);
+		let mut sum1 = vdupq_n_f32(0.0);
+		let mut i = 0;
+		while i + 8 <= len {
+			sum0 = vfmaq_f32(sum0, vld1q_f32(pa.add(i)), vld1q_f32(pb.add(i)));
+			sum1 = vfmaq_f32(sum1, vld1q_f32(pa.add(i + 4)), vld1q_f32(pb.add(i + 4)));
+			i += 8;
+		}
+		if i + 4 <= len {
+			sum0 = vfmaq_f32(sum0, vld1q_f32(pa.add(i)), vld1q_f32(pb.add(i)));
+			i += 4;
+		}
+		vaddvq_f32(vaddq_f32(sum0, sum1)) + scalar::dot(&a[i..len], &b[i..len])
+	}
+
+	#[target_feature(enable = "neon")]
+	pub unsafe fn squared_l2_neon(a: &[f32], b: &[f32]) -> f32 {
+		let len = a.len().min(b.len());
+		let (pa, pb) = (a.as_ptr(), b.as_ptr());
+		let mut sum0 = vdupq_n_f32(0.0);
+		let mut sum1 = vdupq_n_f32(0.0);
+		let mut i = 0;
+		while i + 8 <= len {
+			let d0 = vsubq_f32(vld1q_f32(pa.add(i)), vld1q_f32(pb.add(i)));
+			let d1 = vsubq_f32(vld1q_f32(pa.add(i + 4)), vld1q_f32(pb.add(i + 4)));
+			sum0 = vfmaq_f32(sum0, d0, d0);
+			sum1 = vfmaq_f32(sum1, d1, d1);
+			i += 8;
+		}
+		if i + 4 <= len {
+			let d0 = vsubq_f32(vld1q_f32(pa.add(i)), vld1q_f32(pb.add(i)));
+			sum0 = vfmaq_f32(sum0, d0, d0);
+			i += 4;
+		}
+		vaddvq_f32(vaddq_f32(sum0, sum1)) + scalar::squared_l2(&a[i..len], &b[i..len])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::random;
+
+	#[allow(clippy::cast_possible_truncation)]
+	fn vector(state: &mut u64, len: usize) -> Vec<f32> {
+		(0..len)
+			.map(|_| random::uniform(state).mul_add(2.0, -1.0) as f32)
+			.collect()
+	}
+
+	/// Compares all kernels supported by the CPU with the scalar ones for vectors
+	/// of all lengths covering the tails
+	fn assert_agreement(kernel: impl Fn(&Kernels) -> Kernel, magnitude: impl Fn(f32, f32) -> f32) {
+		let mut state = random::SEED;
+		for len in 0..=100 {
+			let a = vector(&mut state, len);
+			let b = vector(&mut state, len);
+			let tolerance = 1e-5
+				* a.iter()
+					.zip(&b)
+					.map(|(x, y)| magnitude(*x, *y))
+					.sum::<f32>();
+			// SAFETY: the scalar kernels run on every CPU
+			let expected = unsafe { kernel(available().last().unwrap())(&a, &b) };
+			for kernels in available() {
+				// SAFETY: only the kernels supported by the CPU are available
+				let actual = unsafe { kernel(&kernels)(&a, &b) };
+				assert!(
+					(actual - expected).abs() <= tolerance + f32::EPSILON,
+					"{} differs for length {len}: {actual} != {expected}",
+					kernels.name
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn dot_agrees_with_scalar() {
+		assert_agreement(|kernels| kernels.dot, |x, y| (x * y).abs());
+	}
+
+	#[test]
+	fn squared_l2_agrees_with_scalar() {
+		assert_agreement(|kernels| kernels.squared_l2, |x, y| (x - y) * (x - y));
+	}
+
+	#[test]
+	fn uses_shorter_vector() {
+		let a = [1.0; 40];
+		let b = [2.0; 37];
+		for kernels in available() {
+			// SAFETY: only the kernels supported by the CPU are available
+			unsafe {
+				assert!(((kernels.dot)(&a, &b) - 74.0).abs() < f32::EPSILON);
+				assert!(((kernels.squared_l2)(&b, &a) - 37.0).abs() < f32::EPSILON);
+			}
+		}
+	}
+
+	#[test]
+	fn chooses_fastest_kernels() {
+		assert_eq!(KERNELS.name, available()[0].name);
+	}
+}
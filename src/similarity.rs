@@ -1,8 +1,10 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::cmp::Ordering;
+use std::{cmp::Ordering, collections::BinaryHeap};
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+use crate::simd;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq)]
 pub enum Distance {
 	#[serde(rename = "euclidean")]
 	Euclidean,
@@ -10,54 +12,252 @@ pub enum Distance {
 	Cosine,
 	#[serde(rename = "dot")]
 	DotProduct,
+	/// Sum of the absolute differences (L1)
+	#[serde(rename = "manhattan")]
+	Manhattan,
+	/// Maximum of the absolute differences (L-infinity)
+	#[serde(rename = "chebyshev")]
+	Chebyshev,
+	/// Root of the sum of the absolute differences raised to `p`
+	#[serde(rename = "minkowski")]
+	Minkowski {
+		/// Order of the distance, at least 1
+		p: f32,
+	},
+	/// Number of differing bits, a bit is set for a positive value
+	#[serde(rename = "hamming")]
+	Hamming,
+	/// Tanimoto coefficient, which is the Jaccard index for binary vectors
+	#[serde(rename = "jaccard")]
+	Jaccard,
+}
+
+/// Meaning of the score returned for a metric
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScoreType {
+	/// Lower score means closer vectors
+	Distance,
+	/// Higher score means closer vectors
+	Similarity,
+}
+
+impl Distance {
+	pub fn is_valid(self) -> bool {
+		match self {
+			Self::Minkowski { p } => p >= 1.0,
+			_ => true,
+		}
+	}
+
+	/// Distances are negated when ranking, so that the highest score is always the best one
+	pub const fn score_type(self) -> ScoreType {
+		match self {
+			Self::Euclidean
+			| Self::Manhattan
+			| Self::Chebyshev
+			| Self::Minkowski { .. }
+			| Self::Hamming => ScoreType::Distance,
+			Self::Cosine | Self::DotProduct | Self::Jaccard => ScoreType::Similarity,
+		}
+	}
+
+	/// Converts a ranking score to the value of the metric
+	pub fn to_metric(self, score: f32) -> f32 {
+		match self.score_type() {
+			ScoreType::Distance => -score,
+			ScoreType::Similarity => score,
+		}
+	}
 }
 
 pub fn get_cache_attr(metric: Distance, vec: &[f32]) -> f32 {
 	match metric {
-		// Dot product doesn't allow any caching
-		Distance::DotProduct | Distance::Euclidean => 0.0,
 		// Precompute the magnitude of the vector
 		Distance::Cosine => vec.iter().map(|&x| x.powi(2)).sum::<f32>().sqrt(),
+		// Other metrics don't allow any caching
+		_ => 0.0,
 	}
 }
 
+/// Returns a function computing the ranking score of two vectors, where the highest score
+/// is the best one
 pub fn get_distance_fn(metric: Distance) -> impl Fn(&[f32], &[f32], f32) -> f32 {
-	match metric {
-		Distance::Euclidean => euclidian_distance,
+	move |a, b, memo_attr| match metric {
+		Distance::Euclidean => -euclidian_distance(a, b),
 		// We use dot product for cosine because we've normalized the vectors on insertion
-		Distance::Cosine | Distance::DotProduct => dot_product,
+		Distance::Cosine | Distance::DotProduct => dot_product(a, b, memo_attr),
+		Distance::Manhattan => -manhattan_distance(a, b),
+		Distance::Chebyshev => -chebyshev_distance(a, b),
+		Distance::Minkowski { p } => -minkowski_distance(a, b, p),
+		Distance::Hamming => -hamming_bits_distance(a, b),
+		Distance::Jaccard => tanimoto_coefficient(a, b),
 	}
 }
 
-fn euclidian_distance(a: &[f32], b: &[f32], a_sum_squares: f32) -> f32 {
+fn euclidian_distance(a: &[f32], b: &[f32]) -> f32 {
+	simd::squared_l2(a, b).sqrt()
+}
+
+fn dot_product(a: &[f32], b: &[f32], _: f32) -> f32 {
+	simd::dot(a, b)
+}
+
+fn manhattan_distance(a: &[f32], b: &[f32]) -> f32 {
+	a.iter().zip(b).map(|(x, y)| (x - y).abs()).sum()
+}
+
+fn chebyshev_distance(a: &[f32], b: &[f32]) -> f32 {
+	a.iter()
+		.zip(b)
+		.map(|(x, y)| (x - y).abs())
+		.fold(0.0, f32::max)
+}
+
+fn minkowski_distance(a: &[f32], b: &[f32], p: f32) -> f32 {
+	a.iter()
+		.zip(b)
+		.map(|(x, y)| (x - y).abs().powf(p))
+		.sum::<f32>()
+		.powf(p.recip())
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn hamming_bits_distance(a: &[f32], b: &[f32]) -> f32 {
+	a.iter()
+		.zip(b)
+		.filter(|(x, y)| (**x > 0.0) != (**y > 0.0))
+		.count() as f32
+}
+
+fn tanimoto_coefficient(a: &[f32], b: &[f32]) -> f32 {
 	let mut cross_terms = 0.0;
+	let mut a_sum_squares = 0.0;
 	let mut b_sum_squares = 0.0;
 
 	for (i, j) in a.iter().zip(b) {
 		cross_terms += i * j;
-		b_sum_squares += j.powi(2);
+		a_sum_squares += i * i;
+		b_sum_squares += j * j;
+	}
+
+	let union = a_sum_squares + b_sum_squares - cross_terms;
+	if union > f32::EPSILON {
+		cross_terms / union
+	} else {
+		0.0
 	}
+}
+
+/// Query prepared for scoring vectors quantized to bytes, which are reconstructed
+/// as `offset + code * scale` in each dimension
+pub struct QuantizedQuery {
+	/// Query multiplied by the scales for dot product, or moved to the space
+	/// of the codes for euclidean distance
+	values: Vec<f32>,
+	/// Squared scales weighting the dimensions for euclidean distance
+	weights: Vec<f32>,
+	/// Part of the dot product not depending on the codes
+	offset: f32,
+}
+
+/// Supports only metrics computed from the euclidean distance or the dot product,
+/// see [`QuantizationOptions::is_valid`](crate::quantization::QuantizationOptions::is_valid)
+pub fn quantize_query(
+	metric: Distance,
+	query: &[f32],
+	offsets: &[f32],
+	scales: &[f32],
+) -> QuantizedQuery {
+	match metric {
+		Distance::Euclidean => QuantizedQuery {
+			values: query
+				.iter()
+				.zip(offsets.iter().zip(scales))
+				.map(|(value, (offset, scale))| {
+					if *scale > 0.0 {
+						(value - offset) / scale
+					} else {
+						0.0
+					}
+				})
+				.collect(),
+			weights: scales.iter().map(|scale| scale * scale).collect(),
+			offset: 0.0,
+		},
+		_ => QuantizedQuery {
+			values: query
+				.iter()
+				.zip(scales)
+				.map(|(value, scale)| value * scale)
+				.collect(),
+			weights: Vec::new(),
+			offset: dot_product(query, offsets, 0.0),
+		},
+	}
+}
 
-	2.0f32
-		.mul_add(-cross_terms, a_sum_squares + b_sum_squares)
-		.max(0.0)
+/// Returns a function computing the ranking score of a query and quantized vector,
+/// where the highest score is the best one
+pub fn get_quantized_distance_fn(metric: Distance) -> impl Fn(&QuantizedQuery, &[u8]) -> f32 {
+	move |query, codes| match metric {
+		Distance::Euclidean => -quantized_euclidian_distance(query, codes),
+		_ => quantized_dot_product(query, codes),
+	}
+}
+
+fn quantized_euclidian_distance(query: &QuantizedQuery, codes: &[u8]) -> f32 {
+	query
+		.values
+		.iter()
+		.zip(&query.weights)
+		.zip(codes)
+		.fold(0.0, |acc, ((value, weight), &code)| {
+			let difference = value - f32::from(code);
+			(weight * difference).mul_add(difference, acc)
+		})
 		.sqrt()
 }
 
-fn dot_product(a: &[f32], b: &[f32], _: f32) -> f32 {
-	a.iter().zip(b).fold(0.0, |acc, (x, y)| acc + x * y)
+fn quantized_dot_product(query: &QuantizedQuery, codes: &[u8]) -> f32 {
+	query
+		.values
+		.iter()
+		.zip(codes)
+		.fold(query.offset, |acc, (value, &code)| {
+			value.mul_add(f32::from(code), acc)
+		})
+}
+
+/// Packs the sign bits of a vector to 64-bit words, setting a bit for a positive value
+pub fn binarize(vec: &[f32]) -> Vec<u64> {
+	vec.chunks(64)
+		.map(|chunk| {
+			chunk
+				.iter()
+				.enumerate()
+				.filter(|(_, &val)| val > 0.0)
+				.fold(0, |word, (bit, _)| word | 1 << bit)
+		})
+		.collect()
+}
+
+/// Counts the bits, which differ in two binarized vectors
+pub fn hamming_distance(a: &[u64], b: &[u64]) -> u32 {
+	a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum()
 }
 
 pub fn normalize(vec: &[f32]) -> Vec<f32> {
 	let magnitude = (vec.iter().fold(0.0, |acc, &val| val.mul_add(val, acc))).sqrt();
 
-	if magnitude > std::f32::EPSILON {
+	if magnitude > f32::EPSILON {
 		vec.iter().map(|&val| val / magnitude).collect()
 	} else {
 		vec.to_vec()
 	}
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct ScoreIndex {
 	pub score: f32,
 	pub index: usize,
@@ -72,15 +272,88 @@ impl PartialEq for ScoreIndex {
 impl Eq for ScoreIndex {}
 
 impl PartialOrd for ScoreIndex {
-	#[allow(clippy::non_canonical_partial_ord_impl)]
 	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-		// The comparison is intentionally reversed here to make the heap a min-heap
-		other.score.partial_cmp(&self.score)
+		Some(self.cmp(other))
 	}
 }
 
 impl Ord for ScoreIndex {
 	fn cmp(&self, other: &Self) -> Ordering {
-		self.partial_cmp(other).unwrap_or(Ordering::Equal)
+		// The comparison is intentionally reversed here to make the heap a min-heap
+		other
+			.score
+			.partial_cmp(&self.score)
+			.unwrap_or(Ordering::Equal)
+	}
+}
+
+/// Returns at most `k` scores sorted from the highest one
+pub fn top_k(scores: impl IntoIterator<Item = ScoreIndex>, k: usize) -> Vec<ScoreIndex> {
+	let mut heap = BinaryHeap::new();
+	for score_index in scores {
+		if heap.len() < k || score_index < *heap.peek().unwrap() {
+			heap.push(score_index);
+
+			if heap.len() > k {
+				heap.pop();
+			}
+		}
+	}
+	heap.into_sorted_vec()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn metric(distance: Distance, a: &[f32], b: &[f32]) -> f32 {
+		let score = get_distance_fn(distance)(a, b, get_cache_attr(distance, a));
+		distance.to_metric(score)
+	}
+
+	#[test]
+	fn computes_metrics() {
+		let a = [1.0, -2.0, 3.0];
+		let b = [2.0, 1.0, -1.0];
+		assert!((metric(Distance::Euclidean, &a, &b) - 26.0_f32.sqrt()).abs() < 1e-5);
+		assert!((metric(Distance::Manhattan, &a, &b) - 8.0).abs() < 1e-6);
+		assert!((metric(Distance::Chebyshev, &a, &b) - 4.0).abs() < 1e-6);
+		assert!((metric(Distance::Minkowski { p: 1.0 }, &a, &b) - 8.0).abs() < 1e-5);
+		assert!((metric(Distance::Minkowski { p: 2.0 }, &a, &b) - 26.0_f32.sqrt()).abs() < 1e-5);
+		assert!((metric(Distance::Hamming, &a, &b) - 2.0).abs() < 1e-6);
+		// the dot product is -3, the sums of squares are 14 and 6
+		assert!((metric(Distance::Jaccard, &a, &b) + 3.0 / 23.0).abs() < 1e-6);
+		assert!((metric(Distance::Jaccard, &a, &a) - 1.0).abs() < 1e-6);
+		assert!(metric(Distance::Jaccard, &[0.0; 3], &[0.0; 3]).abs() < 1e-6);
+	}
+
+	#[test]
+	fn ranks_closer_vectors_higher() {
+		let query = [0.0, 0.0];
+		let vectors = [[3.0, 0.0], [1.0, 1.0], [0.0, 2.5]];
+		for distance in [
+			Distance::Euclidean,
+			Distance::Manhattan,
+			Distance::Chebyshev,
+			Distance::Minkowski { p: 3.0 },
+		] {
+			assert_eq!(distance.score_type(), ScoreType::Distance);
+			let distance_fn = get_distance_fn(distance);
+			let ranked = top_k(
+				vectors
+					.iter()
+					.enumerate()
+					.map(|(index, vector)| ScoreIndex {
+						score: distance_fn(&query, vector, 0.0),
+						index,
+					}),
+				3,
+			);
+			let indexes: Vec<usize> = ranked.iter().map(|result| result.index).collect();
+			assert_eq!(indexes, [1, 2, 0]);
+		}
+		assert_eq!(Distance::Jaccard.score_type(), ScoreType::Similarity);
+		assert!(Distance::Minkowski { p: 1.0 }.is_valid());
+		assert!(!Distance::Minkowski { p: 0.5 }.is_valid());
 	}
 }
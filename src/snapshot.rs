@@ -0,0 +1,174 @@
+//! Point-in-time copies of the stored collections. A snapshot is a directory in
+//! `._snapshots` in the store with links to the collection files and to their
+//! segments, which are never modified, because saving a collection replaces its
+//! file with a new one and writes new segments. A snapshot is written to a temporary
+//! directory, which is moved in place only when it's complete.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+	fs::{self, File},
+	io::{self, Write},
+	path::{Path, PathBuf},
+	time::{SystemTime, UNIX_EPOCH},
+};
+use url_escape::encode_component;
+
+use crate::{segment, store};
+
+/// Directory with the snapshots in the store
+const SNAPSHOTS_DIR: &str = "._snapshots";
+/// File describing the snapshot, directories without it aren't snapshots
+const INFO_FILE: &str = "._snapshot";
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Snapshot {
+	/// Identifier of the snapshot
+	pub id: String,
+	/// When the snapshot was taken, in milliseconds since the Unix epoch
+	pub created_at: u64,
+	/// Names of the collections in the snapshot
+	pub collections: Vec<String>,
+}
+
+/// Takes a snapshot of the files of the collections, which have to be saved
+pub fn create(store_path: &Path, collections: Vec<String>) -> io::Result<Snapshot> {
+	let snapshots_path = store_path.join(SNAPSHOTS_DIR);
+	fs::create_dir_all(&snapshots_path)?;
+	let mut created_at = u64::try_from(
+		SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_millis(),
+	)
+	.unwrap_or_default();
+	// snapshots taken in the same millisecond get the next free identifier
+	let (id, path) = loop {
+		let id = created_at.to_string();
+		let path = snapshots_path.join(&id);
+		if !path.exists() {
+			break (id, path);
+		}
+		created_at += 1;
+	};
+
+	let snapshot = Snapshot {
+		id,
+		created_at,
+		collections,
+	};
+	let temp_path = store::temp_path(&path)?;
+	if let Err(error) =
+		write(store_path, &temp_path, &snapshot).and_then(|()| fs::rename(&temp_path, &path))
+	{
+		fs::remove_dir_all(&temp_path).ok();
+		return Err(error);
+	}
+	store::sync_dir(&path)?;
+	Ok(snapshot)
+}
+
+/// Links the files of the collections in the snapshot to the directory and writes
+/// the description of the snapshot to it
+fn write(store_path: &Path, path: &Path, snapshot: &Snapshot) -> io::Result<()> {
+	fs::create_dir(path)?;
+	for name in &snapshot.collections {
+		let file_name = encode_component(name).to_string();
+		let source = store_path.join(&file_name);
+		let target = path.join(&file_name);
+		// copying is needed only by file systems without hard links
+		if fs::hard_link(&source, &target).is_err() {
+			fs::copy(&source, &target)?;
+		}
+		segment::link(&segment::dir(store_path, name), &segment::dir(path, name))?;
+	}
+	let mut file = File::create(path.join(INFO_FILE))?;
+	file.write_all(&serde_json::to_vec(snapshot)?)?;
+	file.sync_all()
+}
+
+/// Removes the temporary directories left behind by an interrupted snapshot
+pub fn clean(store_path: &Path) -> io::Result<()> {
+	store::clean(&store_path.join(SNAPSHOTS_DIR))
+}
+
+/// Returns the complete snapshots, the oldest first
+pub fn list(store_path: &Path) -> io::Result<Vec<Snapshot>> {
+	let snapshots_path = store_path.join(SNAPSHOTS_DIR);
+	if !snapshots_path.exists() {
+		return Ok(Vec::new());
+	}
+	let mut snapshots = Vec::new();
+	for entry in fs::read_dir(snapshots_path)? {
+		if let Some(snapshot) = read_info(&entry?.path())? {
+			snapshots.push(snapshot);
+		}
+	}
+	snapshots.sort_by_key(|snapshot| snapshot.created_at);
+	Ok(snapshots)
+}
+
+/// Returns the snapshot with the identifier, if it exists and is complete
+pub fn get(store_path: &Path, id: &str) -> io::Result<Option<Snapshot>> {
+	path(store_path, id).map_or(Ok(None), |path| read_info(&path))
+}
+
+/// Returns the directory of the snapshot, which has the collection files laid out
+/// like the store
+pub fn dir(store_path: &Path, snapshot: &Snapshot) -> PathBuf {
+	store_path.join(SNAPSHOTS_DIR).join(&snapshot.id)
+}
+
+/// Deletes the snapshot, returns false if it didn't exist
+pub fn delete(store_path: &Path, id: &str) -> io::Result<bool> {
+	let Some(path) = path(store_path, id).filter(|path| path.exists()) else {
+		return Ok(false);
+	};
+	fs::remove_dir_all(path)?;
+	Ok(true)
+}
+
+/// Returns the directory of the snapshot, if the identifier is valid
+fn path(store_path: &Path, id: &str) -> Option<PathBuf> {
+	// identifiers are numbers, which prevents escaping from the directory
+	if id.is_empty() || !id.bytes().all(|byte| byte.is_ascii_digit()) {
+		return None;
+	}
+	Some(store_path.join(SNAPSHOTS_DIR).join(id))
+}
+
+fn read_info(path: &Path) -> io::Result<Option<Snapshot>> {
+	let info_path = path.join(INFO_FILE);
+	if !info_path.exists() {
+		return Ok(None);
+	}
+	Ok(Some(serde_json::from_slice(&fs::read(info_path)?)?))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{db::STORE_PATH, testing};
+
+	#[tokio::test]
+	async fn leaves_no_snapshot_if_taking_it_fails() {
+		let _store = testing::store().await;
+		fs::write(STORE_PATH.join("stored"), b"content").unwrap();
+		let snapshot = create(&STORE_PATH, vec!["stored".into()]).unwrap();
+		assert!(dir(&STORE_PATH, &snapshot).join("stored").exists());
+
+		assert!(create(&STORE_PATH, vec!["stored".into(), "missing".into()]).is_err());
+		let temp_dir = store::temp_path(&dir(&STORE_PATH, &snapshot)).unwrap();
+		let temp_dir = temp_dir.parent().unwrap();
+		let snapshots = list(&STORE_PATH).unwrap();
+		assert_eq!(snapshots.len(), 1);
+		assert_eq!(snapshots[0].id, snapshot.id);
+		// only the empty temporary directory is left, which is removed on start
+		assert_eq!(fs::read_dir(temp_dir).unwrap().count(), 0);
+		clean(&STORE_PATH).unwrap();
+		let entries = fs::read_dir(STORE_PATH.join(SNAPSHOTS_DIR))
+			.unwrap()
+			.count();
+		assert_eq!(entries, 1);
+	}
+}
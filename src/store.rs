@@ -0,0 +1,352 @@
+//! Files of the collections in the store. Each file starts with a header carrying
+//! a magic number, the format version, a checksum and the length of the content,
+//! and is written to a temporary file first, which replaces the previous one only
+//! when it has been flushed to the disk completely. The vectors follow the content,
+//! aligned to be mapped to memory and read in place. Their checksum in the header
+//! is checked when they are copied, because they can't be mapped, otherwise after
+//! the collection has been loaded, so that they don't have to be read by loading.
+
+use anyhow::bail;
+use memmap2::Mmap;
+use std::{
+	fs::{self, File},
+	io::{self, BufWriter, Seek, SeekFrom, Write},
+	ops::Range,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+/// Identifies a collection file
+const MAGIC: [u8; 4] = *b"LVEC";
+/// Version of the format of collection files, the first one had no vectors section,
+/// the second one had the whole collection in the content instead of segments and
+/// the third one had no checksum of the vectors
+pub const VERSION: u32 = 4;
+/// Length of the magic number, version, checksum and length of the content and
+/// checksum of the vectors
+const HEADER_LEN: usize = 24;
+/// Length of the header of the first version, which had no length of the content
+const FIRST_HEADER_LEN: usize = 12;
+/// Length of the header of the versions, which had no checksum of the vectors
+const UNCHECKED_HEADER_LEN: usize = 20;
+/// Alignment of the vectors section in the file
+const ALIGNMENT: usize = 64;
+
+/// Directory with files, which are being written
+const TEMP_DIR: &str = "._tmp";
+/// Directory with files, which couldn't be loaded
+const QUARANTINE_DIR: &str = "._quarantine";
+
+/// Collection file with a verified content
+pub struct Stored {
+	/// Version of the format of the file, zero if it has no header
+	pub version: u32,
+	bytes: Arc<Mmap>,
+	content: Range<usize>,
+	vectors: Range<usize>,
+	/// Checksum of the vectors, if the version of the file has it
+	vectors_checksum: Option<u32>,
+}
+
+impl Stored {
+	pub fn content(&self) -> &[u8] {
+		&self.bytes[self.content.clone()]
+	}
+
+	/// Values of the vectors following the content, which are checked, if they
+	/// can't be mapped and are copied from the file
+	pub fn vectors(&self) -> anyhow::Result<Values> {
+		let values = Values::new(&self.bytes, self.vectors.clone());
+		if matches!(values, Values::Owned(_)) {
+			self.check_vectors()?;
+		}
+		Ok(values)
+	}
+
+	/// Checks the vectors, which are mapped from the file unchecked
+	pub fn check_vectors(&self) -> anyhow::Result<()> {
+		if let Some(checksum) = self.vectors_checksum {
+			if crc32fast::hash(&self.bytes[self.vectors.clone()]) != checksum {
+				bail!("the checksum doesn't match the vectors");
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Values of vectors read from a collection file, mapped from the file if they
+/// can be read in place
+#[derive(Debug, Clone)]
+pub enum Values {
+	Mapped(Arc<Mmap>, Range<usize>),
+	Owned(Vec<f32>),
+}
+
+impl Default for Values {
+	fn default() -> Self {
+		Self::Owned(Vec::new())
+	}
+}
+
+impl Values {
+	fn new(bytes: &Arc<Mmap>, range: Range<usize>) -> Self {
+		let slice = &bytes[range.clone()];
+		// SAFETY: every bit pattern is a valid f32
+		let (prefix, _, suffix) = unsafe { slice.align_to::<f32>() };
+		if cfg!(target_endian = "little") && prefix.is_empty() && suffix.is_empty() {
+			return Self::Mapped(Arc::clone(bytes), range);
+		}
+		Self::Owned(
+			slice
+				.chunks_exact(size_of::<f32>())
+				.map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+				.collect(),
+		)
+	}
+
+	pub fn as_slice(&self) -> &[f32] {
+		match self {
+			// SAFETY: every bit pattern is a valid f32 and the alignment was checked
+			Self::Mapped(bytes, range) => unsafe { bytes[range.clone()].align_to::<f32>().1 },
+			Self::Owned(values) => values,
+		}
+	}
+}
+
+/// Writes a collection file with the content and the vectors, replacing the previous
+/// one at once, and returns the vectors mapped from the new file
+pub fn write(
+	path: &Path,
+	content: &[u8],
+	vectors: impl Iterator<Item = impl AsRef<[f32]>>,
+) -> io::Result<Values> {
+	let temp_path = temp_path(path)?;
+	let mut file = BufWriter::new(File::create(&temp_path)?);
+	file.write_all(&MAGIC)?;
+	file.write_all(&VERSION.to_le_bytes())?;
+	file.write_all(&crc32fast::hash(content).to_le_bytes())?;
+	file.write_all(&(content.len() as u64).to_le_bytes())?;
+	// the checksum of the vectors is written once they have been written
+	file.write_all(&[0; 4])?;
+	file.write_all(content)?;
+	let start = align(HEADER_LEN + content.len());
+	file.write_all(&[0; ALIGNMENT][..start - HEADER_LEN - content.len()])?;
+	let mut hasher = crc32fast::Hasher::new();
+	for vector in vectors {
+		let bytes: Vec<u8> = vector
+			.as_ref()
+			.iter()
+			.flat_map(|value| value.to_le_bytes())
+			.collect();
+		hasher.update(&bytes);
+		file.write_all(&bytes)?;
+	}
+	let mut file = file.into_inner().map_err(io::IntoInnerError::into_error)?;
+	file.seek(SeekFrom::Start(UNCHECKED_HEADER_LEN as u64))?;
+	file.write_all(&hasher.finalize().to_le_bytes())?;
+	commit(file, &temp_path, path)?;
+
+	let bytes = Arc::new(map(path)?);
+	let end = bytes.len();
+	Ok(Values::new(&bytes, start..end))
+}
+
+/// Maps a file to memory read-only, which lets the operating system read the pages
+/// on demand and drop them under memory pressure
+fn map(path: &Path) -> io::Result<Mmap> {
+	let file = File::open(path)?;
+	// SAFETY: the mapped files aren't modified, the store replaces them instead
+	unsafe { Mmap::map(&file) }
+}
+
+/// Writes a file with other content than a collection, replacing the previous one
+/// at once too
+pub fn replace(path: &Path, content: &[u8]) -> io::Result<()> {
+	let temp_path = temp_path(path)?;
+	let mut file = File::create(&temp_path)?;
+	file.write_all(content)?;
+	commit(file, &temp_path, path)
+}
+
+/// Flushes a written temporary file to the disk and moves it in place of the file
+fn commit(file: File, temp_path: &Path, path: &Path) -> io::Result<()> {
+	file.sync_all()?;
+	drop(file);
+	fs::rename(temp_path, path)?;
+	sync_dir(path)
+}
+
+/// Reads a collection file, checking the integrity of its content. Files stored
+/// before the header was introduced are returned as-is.
+pub fn read(path: &Path) -> anyhow::Result<Stored> {
+	let bytes = Arc::new(map(path)?);
+	let len = bytes.len();
+	if !bytes.starts_with(&MAGIC) {
+		return Ok(Stored {
+			version: 0,
+			bytes,
+			content: 0..len,
+			vectors: len..len,
+			vectors_checksum: None,
+		});
+	}
+	if len < FIRST_HEADER_LEN {
+		bail!("the header is incomplete");
+	}
+	let version = u32::from_le_bytes(bytes[4..8].try_into()?);
+	if version > VERSION {
+		bail!("the format version {version} is newer than the supported {VERSION}");
+	}
+	let checksum = u32::from_le_bytes(bytes[8..12].try_into()?);
+	let mut vectors_checksum = None;
+	let (content, vectors) = if version == 1 {
+		(FIRST_HEADER_LEN..len, len..len)
+	} else {
+		let header_len = if version < 4 {
+			UNCHECKED_HEADER_LEN
+		} else {
+			HEADER_LEN
+		};
+		if len < header_len {
+			bail!("the header is incomplete");
+		}
+		let content_len = usize::try_from(u64::from_le_bytes(bytes[12..20].try_into()?))?;
+		if version >= 4 {
+			vectors_checksum = Some(u32::from_le_bytes(bytes[20..24].try_into()?));
+		}
+		let Some(end) = header_len
+			.checked_add(content_len)
+			.filter(|&end| end <= len)
+		else {
+			bail!("the content is incomplete");
+		};
+		(header_len..end, align(end).min(len)..len)
+	};
+	if crc32fast::hash(&bytes[content.clone()]) != checksum {
+		bail!("the checksum doesn't match the content");
+	}
+	Ok(Stored {
+		version,
+		bytes,
+		content,
+		vectors,
+		vectors_checksum,
+	})
+}
+
+/// Checks the vectors mapped from the files on another thread, so that they don't
+/// have to be read before the collection is used, and reports the damaged ones
+pub fn check_vectors(files: Vec<(PathBuf, Stored)>) {
+	rayon::spawn(move || {
+		for (path, stored) in files {
+			if let Err(error) = stored.check_vectors() {
+				tracing::error!("File {} is corrupted ({error})", path.display());
+			}
+		}
+	});
+}
+
+/// Moves a file, which couldn't be loaded, aside and returns its new path
+pub fn quarantine(path: &Path) -> io::Result<PathBuf> {
+	let quarantine_path = quarantine_path(path)?;
+	fs::rename(path, &quarantine_path)?;
+	Ok(quarantine_path)
+}
+
+/// Returns the path, which a file or a directory, which couldn't be loaded, is moved to
+pub fn quarantine_path(path: &Path) -> io::Result<PathBuf> {
+	sibling(path, QUARANTINE_DIR)
+}
+
+/// Removes files, which were left behind in the directory by an interrupted writing
+pub fn clean(dir: &Path) -> io::Result<()> {
+	let temp_dir = dir.join(TEMP_DIR);
+	if temp_dir.exists() {
+		fs::remove_dir_all(temp_dir)?;
+	}
+	Ok(())
+}
+
+const fn align(offset: usize) -> usize {
+	offset.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+/// Returns the path of a temporary file, which replaces the file when it's written
+pub fn temp_path(path: &Path) -> io::Result<PathBuf> {
+	sibling(path, TEMP_DIR)
+}
+
+/// Returns the path of a file with the same name in a subdirectory of the store
+fn sibling(path: &Path, dir: &str) -> io::Result<PathBuf> {
+	let parent = path.parent().unwrap_or_else(|| Path::new("."));
+	let dir = parent.join(dir);
+	fs::create_dir_all(&dir)?;
+	Ok(dir.join(path.file_name().unwrap_or_default()))
+}
+
+/// Flushes the renaming of a file to the disk
+#[cfg(unix)]
+pub fn sync_dir(path: &Path) -> io::Result<()> {
+	let parent = path.parent().unwrap_or_else(|| Path::new("."));
+	File::open(parent)?.sync_all()
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+pub const fn sync_dir(_path: &Path) -> io::Result<()> {
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{db::STORE_PATH, testing};
+	use std::iter;
+
+	#[tokio::test]
+	async fn checks_content_of_files() {
+		let _store = testing::store().await;
+		let path = STORE_PATH.join("test");
+		let vectors = [1.0, 2.0, 3.0];
+		let mapped = write(&path, b"content", iter::once(&vectors[..])).unwrap();
+		assert_eq!(mapped.as_slice(), vectors);
+		let stored = read(&path).unwrap();
+		assert_eq!(stored.version, VERSION);
+		assert_eq!(stored.content(), b"content");
+		assert_eq!(stored.vectors().unwrap().as_slice(), vectors);
+		assert!(!STORE_PATH.join(TEMP_DIR).join("test").exists());
+
+		// files stored before the header was introduced
+		fs::write(&path, b"old content").unwrap();
+		let stored = read(&path).unwrap();
+		assert_eq!(stored.version, 0);
+		assert_eq!(stored.content(), b"old content");
+		assert!(stored.vectors().unwrap().as_slice().is_empty());
+
+		write(&path, b"content", iter::empty::<&[f32]>()).unwrap();
+		let mut binary = fs::read(&path).unwrap();
+		binary[HEADER_LEN] ^= 1;
+		fs::write(&path, &binary).unwrap();
+		assert!(read(&path).is_err());
+		fs::write(&path, &binary[..HEADER_LEN - 1]).unwrap();
+		assert!(read(&path).is_err());
+
+		// the vectors are checked only on demand, when they are mapped
+		write(&path, b"content", iter::once(&vectors[..])).unwrap();
+		read(&path).unwrap().check_vectors().unwrap();
+		let mut binary = fs::read(&path).unwrap();
+		let last = binary.len() - 1;
+		binary[last] ^= 1;
+		fs::write(&path, &binary).unwrap();
+		let stored = read(&path).unwrap();
+		assert!(stored.check_vectors().is_err());
+
+		let quarantined = quarantine(&path).unwrap();
+		assert!(!path.exists());
+		assert_eq!(quarantined, STORE_PATH.join(QUARANTINE_DIR).join("test"));
+
+		fs::create_dir_all(STORE_PATH.join(TEMP_DIR)).unwrap();
+		clean(&STORE_PATH).unwrap();
+		assert!(!STORE_PATH.join(TEMP_DIR).exists());
+	}
+}
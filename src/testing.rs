@@ -0,0 +1,99 @@
+//! Data and the store shared by the tests
+
+use std::{env, fs, path::PathBuf, process, sync::LazyLock};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{
+	db::STORE_PATH,
+	index::Vectors,
+	random,
+	similarity::{get_cache_attr, get_distance_fn, normalize, top_k, Distance, ScoreIndex},
+	vectors::VectorStore,
+};
+
+/// Returns `len` normalised random vectors, which are the same for the same seed
+#[allow(clippy::cast_possible_truncation)]
+pub fn vectors(len: usize, dimension: usize, mut seed: u64) -> VectorStore {
+	let mut vectors = VectorStore::new(dimension);
+	for _ in 0..len {
+		let vector: Vec<f32> = (0..dimension)
+			.map(|_| random::uniform(&mut seed).mul_add(2.0, -1.0) as f32)
+			.collect();
+		vectors.push(&normalize(&vector));
+	}
+	vectors
+}
+
+/// Returns the positions of the `k` vectors most similar to the query
+pub fn nearest(vectors: &impl Vectors, query: &[f32], k: usize, distance: Distance) -> Vec<usize> {
+	let memo_attr = get_cache_attr(distance, query);
+	let distance_fn = get_distance_fn(distance);
+	top_k(
+		(0..vectors.len()).map(|index| ScoreIndex {
+			score: distance_fn(vectors.vector(index), query, memo_attr),
+			index,
+		}),
+		k,
+	)
+	.into_iter()
+	.map(|result| result.index)
+	.collect()
+}
+
+/// Fraction of the exact results, which were found
+#[allow(clippy::cast_precision_loss)]
+pub fn recall(found: &[ScoreIndex], exact: &[usize]) -> f32 {
+	let hits = found
+		.iter()
+		.filter(|result| exact.contains(&result.index))
+		.count();
+	hits as f32 / exact.len() as f32
+}
+
+/// Mean squared difference between the values of the vectors and the decoded ones
+#[allow(clippy::cast_precision_loss)]
+pub fn squared_error(vectors: &impl Vectors, decode: impl Fn(usize) -> Vec<f32>) -> f32 {
+	let (sum, count) = (0..vectors.len()).fold((0.0, 0), |(sum, count), index| {
+		let vector = vectors.vector(index);
+		let error: f32 = vector
+			.iter()
+			.zip(decode(index))
+			.map(|(value, decoded)| (value - decoded).powi(2))
+			.sum();
+		(sum + error, count + vector.len())
+	});
+	sum / count as f32
+}
+
+/// Temporary directory of the store, which replaces the configured one for the tests
+static STORE_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+	let path = env::temp_dir().join(format!("litevec-test-{}", process::id()));
+	env::set_var("LITEVEC_STORAGE", &path);
+	path
+});
+
+/// Serialises the tests, which use the store
+static STORE: Mutex<()> = Mutex::const_new(());
+
+/// Store locked for a single test, which is removed when the test ends
+pub struct Store {
+	_guard: MutexGuard<'static, ()>,
+}
+
+/// Locks the store for a test and empties it. Databases with unsaved changes write
+/// to the store, when they are dropped.
+pub async fn store() -> Store {
+	let guard = STORE.lock().await;
+	// the configured store has to be replaced before it is used for the first time
+	let path = LazyLock::force(&STORE_DIR);
+	assert_eq!(*STORE_PATH, *path);
+	fs::remove_dir_all(STORE_PATH.as_path()).ok();
+	fs::create_dir_all(STORE_PATH.as_path()).unwrap();
+	Store { _guard: guard }
+}
+
+impl Drop for Store {
+	fn drop(&mut self) {
+		fs::remove_dir_all(STORE_PATH.as_path()).ok();
+	}
+}
@@ -0,0 +1,95 @@
+//! Deleted slots of a collection. Embeddings keep their slots until the collection
+//! is compacted, so that deleting one does not move the others, and the slots
+//! of deleted embeddings are reused by the next insertions.
+
+use serde::{Deserialize, Serialize};
+
+/// Bitmap of the deleted slots together with a list of them for reusing
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Tombstones {
+	/// One bit for each slot, set if the slot is deleted
+	bits: Vec<u64>,
+	/// Deleted slots, the last one is reused first
+	free: Vec<usize>,
+}
+
+impl Tombstones {
+	/// Number of deleted slots
+	pub const fn len(&self) -> usize {
+		self.free.len()
+	}
+
+	pub const fn is_empty(&self) -> bool {
+		self.free.is_empty()
+	}
+
+	pub fn clear(&mut self) {
+		self.bits.clear();
+		self.free.clear();
+	}
+
+	pub fn is_deleted(&self, slot: usize) -> bool {
+		self.bits
+			.get(slot / 64)
+			.is_some_and(|word| word & (1 << (slot % 64)) != 0)
+	}
+
+	pub fn delete(&mut self, slot: usize) {
+		if self.is_deleted(slot) {
+			return;
+		}
+		if self.bits.len() <= slot / 64 {
+			self.bits.resize(slot / 64 + 1, 0);
+		}
+		self.bits[slot / 64] |= 1 << (slot % 64);
+		self.free.push(slot);
+	}
+
+	/// Takes a deleted slot for a new embedding
+	pub fn reuse(&mut self) -> Option<usize> {
+		let slot = self.free.pop()?;
+		self.bits[slot / 64] &= !(1 << (slot % 64));
+		Some(slot)
+	}
+
+	/// Returns the new slot of each of the `slots`, which are left after dropping
+	/// the deleted ones and moving the following ones down
+	pub fn remap(&self, slots: usize) -> Vec<Option<usize>> {
+		let mut next = 0;
+		(0..slots)
+			.map(|slot| {
+				if self.is_deleted(slot) {
+					None
+				} else {
+					next += 1;
+					Some(next - 1)
+				}
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reuses_deleted_slots() {
+		let mut tombstones = Tombstones::default();
+		tombstones.delete(3);
+		tombstones.delete(70);
+		tombstones.delete(3);
+		assert_eq!(tombstones.len(), 2);
+		assert!(tombstones.is_deleted(70));
+		assert!(!tombstones.is_deleted(4));
+		assert_eq!(
+			tombstones.remap(5),
+			[Some(0), Some(1), Some(2), None, Some(3)]
+		);
+		assert_eq!(tombstones.reuse(), Some(70));
+		assert!(!tombstones.is_deleted(70));
+		assert_eq!(tombstones.reuse(), Some(3));
+		assert_eq!(tombstones.reuse(), None);
+		assert!(tombstones.is_empty());
+	}
+}
@@ -0,0 +1,223 @@
+//! Vectors of a collection by their slots. The vectors loaded from the segments of
+//! a collection stay mapped from their files, the vectors inserted or replaced later
+//! are kept in memory until the collection is saved and the new segment is mapped
+//! instead.
+
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::HashMap};
+
+use crate::{index::Vectors, store::Values};
+
+#[derive(Debug, Clone, Default)]
+pub struct VectorStore {
+	/// Number of values of each vector, zero if the vectors were dropped after quantizing
+	stride: usize,
+	/// Number of slots
+	len: usize,
+	/// Vectors mapped from the segment files with the number of slots using them
+	segments: Vec<(Values, usize)>,
+	/// Segment and position of the vector of each of the first slots, which were read
+	/// from the segments or saved to them
+	stored: Vec<(u32, u32)>,
+	/// Vectors replacing the stored ones by their slots
+	replaced: HashMap<usize, Vec<f32>>,
+	/// Vectors of the slots following the stored ones
+	appended: Vec<f32>,
+}
+
+/// Size of the vectors in a collection file, stored in its content before the
+/// collections were split to segments
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Layout {
+	stride: usize,
+	len: usize,
+}
+
+impl VectorStore {
+	pub fn new(stride: usize) -> Self {
+		Self {
+			stride,
+			len: 0,
+			segments: Vec::new(),
+			stored: Vec::new(),
+			replaced: HashMap::new(),
+			appended: Vec::new(),
+		}
+	}
+
+	/// Creates the store from the vectors read from a collection file stored before
+	/// the collections were split to segments, or returns `None` if there are not
+	/// as many values as the layout needs
+	pub fn from_stored(layout: Layout, stored: Values) -> Option<Self> {
+		let mut store = Self::new(layout.stride);
+		let slots: Vec<usize> = (0..layout.len).collect();
+		if !store.set_stored(stored, &slots) {
+			return None;
+		}
+		store.len = layout.len;
+		Some(store)
+	}
+
+	/// Creates the store from the vectors read from the segments, which are `stride`
+	/// long and belong to the slots of their segments, or returns `None` if they
+	/// don't match the slots
+	pub fn from_segments<'a>(
+		stride: usize,
+		len: usize,
+		segments: impl Iterator<Item = (Values, &'a [usize])>,
+	) -> Option<Self> {
+		let mut store = Self::new(stride);
+		if stride > 0 {
+			for (values, slots) in segments {
+				if !store.set_stored(values, slots) {
+					return None;
+				}
+			}
+			if store.len != len {
+				return None;
+			}
+		}
+		store.len = len;
+		Some(store)
+	}
+
+	/// Creates the store from vectors following one after another, which are
+	/// `stride` long, or empty if they were dropped
+	pub fn from_vectors<'a>(
+		stride: usize,
+		len: usize,
+		vectors: impl Iterator<Item = &'a [f32]>,
+	) -> Self {
+		let mut store = Self::new(stride);
+		store.appended = vectors.flatten().copied().collect();
+		store.len = len;
+		store
+	}
+
+	/// Number of values of each vector, zero if the vectors were dropped after quantizing
+	pub const fn stride(&self) -> usize {
+		self.stride
+	}
+
+	/// Size of the values of all vectors in bytes
+	pub const fn size(&self) -> usize {
+		self.stride * self.len * size_of::<f32>()
+	}
+
+	/// Replaces the vectors of the slots in memory with the same ones mapped from
+	/// a saved segment, which has them in the order of the slots. The slots, which
+	/// haven't been stored yet, have to follow the stored ones. Returns `false` if
+	/// the values don't match the slots.
+	#[allow(clippy::cast_possible_truncation)]
+	pub fn set_stored(&mut self, values: Values, slots: &[usize]) -> bool {
+		if self.stride == 0 {
+			return true;
+		}
+		if values.as_slice().len() != self.stride * slots.len() {
+			return false;
+		}
+		let segment = self.segments.len() as u32;
+		let mut moved = 0;
+		for (position, &slot) in slots.iter().enumerate() {
+			let location = (segment, position as u32);
+			match slot.cmp(&self.stored.len()) {
+				Ordering::Less => {
+					let (previous, _) = self.stored[slot];
+					self.release(previous);
+					self.stored[slot] = location;
+					self.replaced.remove(&slot);
+				},
+				Ordering::Equal => {
+					self.stored.push(location);
+					moved += 1;
+				},
+				Ordering::Greater => return false,
+			}
+		}
+		self.segments.push((values, slots.len()));
+		let moved = (moved * self.stride).min(self.appended.len());
+		self.appended.drain(..moved);
+		self.len = self.len.max(self.stored.len());
+		true
+	}
+
+	/// Drops the mapping of a segment, which no slot uses any more
+	fn release(&mut self, segment: u32) {
+		let (values, used) = &mut self.segments[segment as usize];
+		*used -= 1;
+		if *used == 0 {
+			*values = Values::default();
+		}
+	}
+
+	pub fn push(&mut self, vector: &[f32]) {
+		self.len += 1;
+		if self.stride > 0 {
+			self.appended.extend_from_slice(vector);
+		}
+	}
+
+	/// Replaces the vector at `index` keeping its slot
+	pub fn set(&mut self, index: usize, vector: Vec<f32>) {
+		if self.stride == 0 {
+			return;
+		}
+		if index < self.stored.len() {
+			self.replaced.insert(index, vector);
+		} else {
+			let start = (index - self.stored.len()) * self.stride;
+			self.appended[start..start + self.stride].copy_from_slice(&vector);
+		}
+	}
+
+	/// Drops the vectors, which aren't needed after they were quantized
+	pub fn drop_vectors(&mut self) {
+		*self = Self {
+			len: self.len,
+			..Self::new(0)
+		};
+	}
+
+	/// Drops the deleted vectors and moves the others to the new slots in `remap`
+	pub fn compact(&mut self, remap: &[Option<usize>]) {
+		let kept = remap.iter().flatten().count();
+		let values = if self.stride > 0 {
+			remap
+				.iter()
+				.enumerate()
+				.filter(|(_, slot)| slot.is_some())
+				.flat_map(|(index, _)| self.vector(index).iter().copied())
+				.collect()
+		} else {
+			Vec::new()
+		};
+		*self = Self {
+			len: kept,
+			appended: values,
+			..Self::new(self.stride)
+		};
+	}
+}
+
+impl Vectors for VectorStore {
+	fn len(&self) -> usize {
+		self.len
+	}
+
+	fn vector(&self, index: usize) -> &[f32] {
+		if self.stride == 0 {
+			return &[];
+		}
+		if let Some(&(segment, position)) = self.stored.get(index) {
+			if !self.replaced.is_empty() {
+				if let Some(vector) = self.replaced.get(&index) {
+					return vector;
+				}
+			}
+			let start = position as usize * self.stride;
+			return &self.segments[segment as usize].0.as_slice()[start..start + self.stride];
+		}
+		let start = (index - self.stored.len()) * self.stride;
+		&self.appended[start..start + self.stride]
+	}
+}
@@ -0,0 +1,270 @@
+//! Write-ahead log of the mutations made since the collections were saved, which
+//! is replayed when the database is loaded, so that a crash doesn't lose them.
+//! Each mutation is appended as a line of JSON before the request is answered.
+//! Collections are saved while other ones are being modified, so only the part of
+//! the log written before the saving started is dropped after it.
+
+use serde::{Deserialize, Serialize};
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{self, Read, Seek, SeekFrom, Write},
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Mutex, MutexGuard, PoisonError,
+	},
+};
+
+use crate::{
+	db::Embedding,
+	index::IndexOptions,
+	metadata::{self, Metadata},
+	quantization::QuantizationOptions,
+	similarity::Distance,
+	store,
+};
+
+/// When the log is flushed from the operating system to the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+	/// After every mutation, before the request is answered
+	Always,
+	/// Every second, a crash of the system can lose the last second of mutations
+	Batch,
+	/// When the operating system decides, only a crash of the process is survived
+	Off,
+}
+
+impl FromStr for Durability {
+	type Err = anyhow::Error;
+
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		match value {
+			"always" => Ok(Self::Always),
+			"batch" => Ok(Self::Batch),
+			"off" => Ok(Self::Off),
+			_ => Err(anyhow::anyhow!("durability has to be always, batch or off")),
+		}
+	}
+}
+
+/// Mutation of the database, mutations by filters are recorded by the identifiers
+/// of the embeddings, which they changed
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+	CreateCollection {
+		name: String,
+		dimension: usize,
+		distance: Distance,
+		index: IndexOptions,
+		quantization: Option<QuantizationOptions>,
+		metadata_index: Vec<String>,
+	},
+	RenameCollection {
+		name: String,
+		new_name: String,
+	},
+	DeleteCollection {
+		name: String,
+	},
+	RebuildIndex {
+		collection: String,
+	},
+	Upsert {
+		collection: String,
+		embeddings: Vec<Embedding>,
+		upsert: bool,
+	},
+	Update {
+		collection: String,
+		id: String,
+		vector: Option<Vec<f32>>,
+		#[serde(
+			default,
+			deserialize_with = "metadata::deserialize_nullable",
+			skip_serializing_if = "Option::is_none"
+		)]
+		#[allow(clippy::option_option)]
+		metadata: Option<Option<Metadata>>,
+		/// If the metadata are merged into the current ones
+		#[serde(default)]
+		merge: bool,
+	},
+	MergeMetadata {
+		collection: String,
+		ids: Vec<String>,
+		patch: Metadata,
+	},
+	Delete {
+		collection: String,
+		ids: Vec<String>,
+	},
+}
+
+#[derive(Debug)]
+pub struct Wal {
+	path: PathBuf,
+	/// Log file, locked while a mutation is appended
+	file: Mutex<File>,
+	durability: Durability,
+	/// If mutations were appended since the last flush to the disk
+	unsynced: AtomicBool,
+}
+
+impl Wal {
+	pub fn open(path: &Path, durability: Durability) -> io::Result<Self> {
+		Ok(Self {
+			path: path.to_path_buf(),
+			file: Mutex::new(Self::open_file(path)?),
+			durability,
+			unsynced: AtomicBool::new(false),
+		})
+	}
+
+	/// Reads the logged mutations, skipping the last line, if it wasn't written
+	/// completely, a damaged line followed by other mutations is an error
+	pub fn read(path: &Path) -> io::Result<Vec<Operation>> {
+		if !path.exists() {
+			return Ok(Vec::new());
+		}
+		let content = fs::read(path)?;
+		let mut lines = content
+			.split(|&byte| byte == b'\n')
+			.enumerate()
+			.filter(|(_, line)| !line.trim_ascii().is_empty())
+			.peekable();
+		let mut operations = Vec::new();
+		while let Some((number, line)) = lines.next() {
+			match serde_json::from_slice(line) {
+				Ok(operation) => operations.push(operation),
+				Err(error) if lines.peek().is_none() => {
+					tracing::warn!("Skipping the last line of the log: {error}");
+				},
+				Err(error) => {
+					return Err(io::Error::new(
+						io::ErrorKind::InvalidData,
+						format!("the log is damaged at line {}: {error}", number + 1),
+					));
+				},
+			}
+		}
+		Ok(operations)
+	}
+
+	pub async fn append(&self, operation: &Operation) -> io::Result<()> {
+		let mut line = serde_json::to_vec(operation)?;
+		line.push(b'\n');
+		let file = {
+			let mut file = self.lock();
+			file.write_all(&line)?;
+			if self.durability != Durability::Always {
+				self.unsynced.store(true, Ordering::Release);
+				return Ok(());
+			}
+			file.try_clone()?
+		};
+		sync_data(file).await
+	}
+
+	/// Flushes the mutations appended since the last flush to the disk
+	pub async fn sync(&self) -> io::Result<()> {
+		if self.unsynced.swap(false, Ordering::AcqRel) {
+			let file = self.lock().try_clone()?;
+			sync_data(file).await?;
+		}
+		Ok(())
+	}
+
+	/// Size of the log in bytes, which marks the mutations appended so far
+	pub fn len(&self) -> io::Result<u64> {
+		Ok(self.lock().metadata()?.len())
+	}
+
+	/// Drops the mutations appended before the mark returned by `len`, once they
+	/// have been saved in the collections, keeping the ones appended after it
+	pub fn truncate(&self, mark: u64) -> io::Result<()> {
+		let mut file = self.lock();
+		let mut rest = Vec::new();
+		let mut reader = File::open(&self.path)?;
+		reader.seek(SeekFrom::Start(mark))?;
+		reader.read_to_end(&mut rest)?;
+		if rest.is_empty() {
+			file.set_len(0)?;
+			file.sync_all()?;
+		} else {
+			// the rest replaces the log at once, so that a crash keeps one of them whole
+			let temp_path = store::temp_path(&self.path)?;
+			let mut temp = File::create(&temp_path)?;
+			temp.write_all(&rest)?;
+			temp.sync_all()?;
+			fs::rename(&temp_path, &self.path)?;
+			store::sync_dir(&self.path)?;
+			*file = Self::open_file(&self.path)?;
+		}
+		self.unsynced.store(false, Ordering::Release);
+		drop(file);
+		Ok(())
+	}
+
+	fn open_file(path: &Path) -> io::Result<File> {
+		OpenOptions::new().create(true).append(true).open(path)
+	}
+
+	fn lock(&self) -> MutexGuard<'_, File> {
+		self.file.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+}
+
+/// Flushes the log to the disk on a blocking thread, with the log unlocked, so
+/// that other mutations can be appended meanwhile
+async fn sync_data(file: File) -> io::Result<()> {
+	tokio::task::spawn_blocking(move || file.sync_data()).await?
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn delete(name: &str) -> Operation {
+		Operation::DeleteCollection {
+			name: name.to_string(),
+		}
+	}
+
+	fn names(operations: &[Operation]) -> Vec<&str> {
+		operations
+			.iter()
+			.map(|operation| match operation {
+				Operation::DeleteCollection { name } => name.as_str(),
+				_ => unreachable!(),
+			})
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn replays_appended_mutations() {
+		let path = std::env::temp_dir().join(format!("litevec-wal-{}", std::process::id()));
+		let wal = Wal::open(&path, Durability::Always).unwrap();
+		wal.append(&delete("a")).await.unwrap();
+		let mark = wal.len().unwrap();
+		wal.append(&delete("b")).await.unwrap();
+		assert_eq!(names(&Wal::read(&path).unwrap()), ["a", "b"]);
+
+		// a line written incompletely at the end is skipped
+		wal.lock().write_all(b"{\"op\":\"delete_coll").unwrap();
+		assert_eq!(names(&Wal::read(&path).unwrap()), ["a", "b"]);
+
+		// a damaged line followed by other mutations is not
+		wal.lock().write_all(b"\n").unwrap();
+		wal.append(&delete("c")).await.unwrap();
+		assert!(Wal::read(&path).is_err());
+
+		wal.truncate(mark).unwrap();
+		assert!(Wal::read(&path).is_err());
+		wal.truncate(wal.len().unwrap()).unwrap();
+		assert!(Wal::read(&path).unwrap().is_empty());
+		fs::remove_file(&path).unwrap();
+	}
+}